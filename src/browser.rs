@@ -0,0 +1,466 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, Process};
+use tracing::{debug, warn};
+
+use crate::protocol::BrowserInnerPid;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Browser {
+    #[default]
+    Chromium,
+    Firefox,
+}
+
+impl Browser {
+    pub fn backend(&self) -> &'static dyn BrowserBackend {
+        match self {
+            Browser::Chromium => &ChromiumBackend,
+            Browser::Firefox => &FirefoxBackend,
+        }
+    }
+}
+
+/// What backends tell processes apart by
+pub struct ProcessCmdline<'a> {
+    pub pid: Pid,
+    pub name: &'a OsStr,
+    pub cmd: &'a [OsString],
+}
+
+impl<'a> From<&'a Process> for ProcessCmdline<'a> {
+    fn from(process: &'a Process) -> Self {
+        ProcessCmdline {
+            pid: process.pid(),
+            name: process.name(),
+            cmd: process.cmd(),
+        }
+    }
+}
+
+/// How OS processes of a browser are recognized and paired with tab data from its extension
+pub trait BrowserBackend: Sync {
+    /// Whether the process is part of the browser with executable name `browser_name`
+    fn is_browser_process(&self, process: &ProcessCmdline, browser_name: &str) -> bool;
+
+    /// The id extension reported in `browserInnerPid` for tabs hosted by this process,
+    /// `None` if the process doesn't host tabs
+    fn browser_inner_pid(&self, process: &ProcessCmdline) -> Option<BrowserInnerPid>;
+
+    /// What the browser process does, only called on processes of the browser
+    fn process_class(&self, process: &ProcessCmdline) -> ProcessClass;
+}
+
+/// Kind of a browser process, by `--type=` on Chromium
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessClass {
+    /// The main process
+    Browser,
+    Renderer,
+    /// Renderer of extension pages and background scripts
+    Extension,
+    GpuProcess,
+    /// e.g. network service, audio service
+    Utility {
+        sub_type: Option<String>,
+    },
+    Zygote,
+    Other {
+        process_type: String,
+    },
+}
+
+impl fmt::Display for ProcessClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessClass::Browser => write!(f, "browser"),
+            ProcessClass::Renderer => write!(f, "renderer"),
+            ProcessClass::Extension => write!(f, "extension"),
+            ProcessClass::GpuProcess => write!(f, "gpu-process"),
+            ProcessClass::Utility { sub_type: None } => write!(f, "utility"),
+            ProcessClass::Utility {
+                sub_type: Some(sub_type),
+            } => write!(f, "utility ({sub_type})"),
+            ProcessClass::Zygote => write!(f, "zygote"),
+            ProcessClass::Other { process_type } => write!(f, "{process_type}"),
+        }
+    }
+}
+
+/// Arguments of the process, split again because browsers may rewrite the whole cmdline into the first argument
+fn split_args<'a>(process: &ProcessCmdline<'a>) -> Vec<&'a str> {
+    process
+        .cmd
+        .iter()
+        .filter_map(|arg| arg.to_str())
+        .flat_map(|arg| arg.split_whitespace())
+        .collect()
+}
+
+/// Value of `--name=value`
+fn arg_value<'a>(args: &[&'a str], name: &str) -> Option<&'a str> {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Renderers are recognized by `--renderer-client-id=`, which is the id `chrome.processes` reports
+pub struct ChromiumBackend;
+
+impl BrowserBackend for ChromiumBackend {
+    fn is_browser_process(&self, process: &ProcessCmdline, browser_name: &str) -> bool {
+        process.name == browser_name
+    }
+
+    fn browser_inner_pid(&self, process: &ProcessCmdline) -> Option<BrowserInnerPid> {
+        // Chromium rewrites its whole cmdline into the first argument
+        let cmdline = match process.cmd.first() {
+            Some(cmdline) => cmdline,
+            None => {
+                debug!(target: "browser", "Process {} cmdline is empty!", process.pid);
+                return None;
+            }
+        };
+        let cmdline = match cmdline.to_str() {
+            Some(cmdline) => cmdline,
+            None => {
+                warn!(
+                    target: "browser",
+                    "Process {} cmdline have invalid UTF-8 data: {:?}",
+                    process.pid,
+                    cmdline
+                );
+                return None;
+            }
+        };
+        // No target flag in this cmdline, skipped
+        let target_arg = cmdline
+            .split_whitespace()
+            .find(|arg| arg.starts_with("--renderer-client-id="))?;
+        let browser_inner_pid = match target_arg.split('=').nth(1) {
+            Some(browser_inner_pid) => browser_inner_pid.parse::<BrowserInnerPid>(),
+            None => {
+                warn!(
+                    target: "browser",
+                    "Process {}, no number after arg \"renderer-client-id=\", cmdline: {}",
+                    process.pid,
+                    cmdline
+                );
+                return None;
+            }
+        };
+        match browser_inner_pid {
+            Ok(browser_inner_pid) => Some(browser_inner_pid),
+            Err(e) => {
+                warn!(target: "browser", "Cannot find pid from cmdline arg: {}", e);
+                None
+            }
+        }
+    }
+
+    fn process_class(&self, process: &ProcessCmdline) -> ProcessClass {
+        let args = split_args(process);
+        match arg_value(&args, "--type") {
+            None => ProcessClass::Browser,
+            Some("renderer") if args.contains(&"--extension-process") => ProcessClass::Extension,
+            Some("renderer") => ProcessClass::Renderer,
+            Some("gpu-process") => ProcessClass::GpuProcess,
+            Some("utility") => ProcessClass::Utility {
+                sub_type: arg_value(&args, "--utility-sub-type").map(str::to_string),
+            },
+            Some("zygote") => ProcessClass::Zygote,
+            Some(process_type) => ProcessClass::Other {
+                process_type: process_type.to_string(),
+            },
+        }
+    }
+}
+
+/// Content processes are recognized by `-contentproc ... -childID <id> ... tab`,
+/// the extension reports the same childID through its `processes` experiment API
+pub struct FirefoxBackend;
+
+impl BrowserBackend for FirefoxBackend {
+    fn is_browser_process(&self, process: &ProcessCmdline, browser_name: &str) -> bool {
+        // Content processes rename themselves (e.g. "Isolated Web Co"), check the executable instead
+        let exe_name = process
+            .cmd
+            .first()
+            .and_then(|arg0| Path::new(arg0).file_name());
+        process.name == browser_name || exe_name.is_some_and(|exe_name| exe_name == browser_name)
+    }
+
+    fn browser_inner_pid(&self, process: &ProcessCmdline) -> Option<BrowserInnerPid> {
+        let args = split_args(process);
+        if !args.contains(&"-contentproc") || args.last() != Some(&"tab") {
+            return None;
+        }
+
+        let child_id_index = args.iter().position(|&arg| arg == "-childID")?;
+        match args.get(child_id_index + 1) {
+            Some(child_id) => match child_id.parse::<BrowserInnerPid>() {
+                Ok(child_id) => Some(child_id),
+                Err(e) => {
+                    warn!(
+                        target: "browser",
+                        "Process {}, invalid number after arg \"-childID\": {}",
+                        process.pid,
+                        e
+                    );
+                    None
+                }
+            },
+            None => {
+                warn!(
+                    target: "browser",
+                    "Process {}, no number after arg \"-childID\"",
+                    process.pid
+                );
+                None
+            }
+        }
+    }
+
+    fn process_class(&self, process: &ProcessCmdline) -> ProcessClass {
+        let args = split_args(process);
+        if !args.contains(&"-contentproc") {
+            return ProcessClass::Browser;
+        }
+        // The process type is the last argument of a child process
+        match args.last().copied() {
+            Some("tab") => ProcessClass::Renderer,
+            Some("gpu") => ProcessClass::GpuProcess,
+            Some("forkserver") => ProcessClass::Zygote,
+            Some(process_type @ ("rdd" | "socket" | "utility")) => ProcessClass::Utility {
+                sub_type: Some(process_type.to_string()),
+            },
+            process_type => ProcessClass::Other {
+                process_type: process_type.unwrap_or_default().to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Process name, arguments, then what the backend tells of it: whether it's a process of
+    /// the browser, its class and browser inner pid. Classes of other processes are never asked
+    type Sample<'a> = (
+        &'a str,
+        &'a [&'a str],
+        bool,
+        ProcessClass,
+        Option<BrowserInnerPid>,
+    );
+
+    fn check_samples(browser: Browser, browser_name: &str, samples: &[Sample]) {
+        let backend = browser.backend();
+        for (name, args, is_browser_process, class, browser_inner_pid) in samples {
+            let cmd: Vec<OsString> = args.iter().map(OsString::from).collect();
+            let process = ProcessCmdline {
+                pid: Pid::from_u32(100),
+                name: OsStr::new(name),
+                cmd: &cmd,
+            };
+            assert_eq!(
+                backend.is_browser_process(&process, browser_name),
+                *is_browser_process,
+                "{args:?}"
+            );
+            assert_eq!(&backend.process_class(&process), class, "{args:?}");
+            assert_eq!(
+                backend.browser_inner_pid(&process),
+                *browser_inner_pid,
+                "{args:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn chromium_processes_are_told_apart_by_type() {
+        let utility = |sub_type: &str| ProcessClass::Utility {
+            sub_type: Some(sub_type.to_string()),
+        };
+        // Child processes rewrite their whole cmdline into the first argument
+        check_samples(
+            Browser::Chromium,
+            "chromium",
+            &[
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium", "--ozone-platform-hint=auto"],
+                    true,
+                    ProcessClass::Browser,
+                    None,
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=renderer --crashpad-handler-pid=18058 --enable-crash-reporter=,Arch Linux --change-stack-guard-on-fork=enable --lang=en-US --num-raster-threads=4 --enable-main-frame-before-activation --renderer-client-id=7 --time-ticks-at-unix-epoch=-1700000000000000 --launch-time-ticks=92374123 --shared-files=v8_context_snapshot_data:100 --field-trial-handle=3,i,1530928406785618101,5384128452417306282,262144"],
+                    true,
+                    ProcessClass::Renderer,
+                    Some(7),
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=renderer --crashpad-handler-pid=18058 --enable-crash-reporter=,Arch Linux --extension-process --change-stack-guard-on-fork=enable --lang=en-US --num-raster-threads=4 --renderer-client-id=5 --time-ticks-at-unix-epoch=-1700000000000000 --launch-time-ticks=92345678 --shared-files=v8_context_snapshot_data:100"],
+                    true,
+                    ProcessClass::Extension,
+                    Some(5),
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=gpu-process --crashpad-handler-pid=18058 --enable-crash-reporter=,Arch Linux --change-stack-guard-on-fork=enable --gpu-preferences=WAAAAAAAAAAgAAAEAAAAAAAAAAAAAAAAAABgAAEAAAA4AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA== --shared-files --field-trial-handle=3,i,1530928406785618101,5384128452417306282,262144"],
+                    true,
+                    ProcessClass::GpuProcess,
+                    None,
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=utility --utility-sub-type=network.mojom.NetworkService --lang=en-US --service-sandbox-type=none --crashpad-handler-pid=18058 --enable-crash-reporter=,Arch Linux --change-stack-guard-on-fork=enable --shared-files=v8_context_snapshot_data:100"],
+                    true,
+                    utility("network.mojom.NetworkService"),
+                    None,
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=utility --utility-sub-type=audio.mojom.AudioService --lang=en-US --service-sandbox-type=audio --crashpad-handler-pid=18058"],
+                    true,
+                    utility("audio.mojom.AudioService"),
+                    None,
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=zygote --no-zygote-sandbox --crashpad-handler-pid=18058 --enable-crash-reporter=,Arch Linux --change-stack-guard-on-fork=enable"],
+                    true,
+                    ProcessClass::Zygote,
+                    None,
+                ),
+                (
+                    "chromium",
+                    &["/usr/lib/chromium/chromium --type=broker"],
+                    true,
+                    ProcessClass::Other {
+                        process_type: "broker".to_string(),
+                    },
+                    None,
+                ),
+                (
+                    "chrome_crashpad",
+                    &["/usr/lib/chromium/chrome_crashpad_handler --monitor-self --monitor-self-annotation=ptype=crashpad-handler --database=/home/user/.config/chromium/Crash Reports --annotation=channel=Arch Linux --initial-client-fd=5 --shared-client-connection"],
+                    false,
+                    ProcessClass::Browser,
+                    None,
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn firefox_processes_are_told_apart_by_the_last_argument() {
+        let utility = |sub_type: &str| ProcessClass::Utility {
+            sub_type: Some(sub_type.to_string()),
+        };
+        let child = |child_id: &'static str, process_type: &'static str| -> Vec<&'static str> {
+            vec![
+                "/usr/lib/firefox/firefox",
+                "-contentproc",
+                "-childID",
+                child_id,
+                "-isForBrowser",
+                "-prefsLen",
+                "31280",
+                "-prefMapSize",
+                "244976",
+                "-jsInitLen",
+                "231800",
+                "-parentBuildID",
+                "20240108143603",
+                "-greomni",
+                "/usr/lib/firefox/omni.ja",
+                "-appomni",
+                "/usr/lib/firefox/browser/omni.ja",
+                "-appDir",
+                "/usr/lib/firefox/browser",
+                "{8f5c3a6e-8a4d-4f3e-9a71-2c0b1e7d9f10}",
+                "17930",
+                "true",
+                process_type,
+            ]
+        };
+        let tab = child("3", "tab");
+        let gpu = child("1", "gpu");
+        let rdd = child("2", "rdd");
+        let socket = child("4", "socket");
+        let utility_process = child("5", "utility");
+        check_samples(
+            Browser::Firefox,
+            "firefox",
+            &[
+                (
+                    "firefox",
+                    &["/usr/lib/firefox/firefox"],
+                    true,
+                    ProcessClass::Browser,
+                    None,
+                ),
+                // Content processes rename themselves
+                (
+                    "Isolated Web Co",
+                    &tab,
+                    true,
+                    ProcessClass::Renderer,
+                    Some(3),
+                ),
+                (
+                    "Web Content",
+                    &child("6", "tab"),
+                    true,
+                    ProcessClass::Renderer,
+                    Some(6),
+                ),
+                ("firefox", &gpu, true, ProcessClass::GpuProcess, None),
+                ("RDD Process", &rdd, true, utility("rdd"), None),
+                ("Socket Process", &socket, true, utility("socket"), None),
+                (
+                    "Utility Process",
+                    &utility_process,
+                    true,
+                    utility("utility"),
+                    None,
+                ),
+                (
+                    "forkserver",
+                    &["/usr/lib/firefox/firefox", "-contentproc", "forkserver"],
+                    true,
+                    ProcessClass::Zygote,
+                    None,
+                ),
+                // "-childID" without "tab" is not a tab process
+                (
+                    "firefox",
+                    &child("7", "ipdlunittest"),
+                    true,
+                    ProcessClass::Other {
+                        process_type: "ipdlunittest".to_string(),
+                    },
+                    None,
+                ),
+                (
+                    "bash",
+                    &["/usr/bin/bash"],
+                    false,
+                    ProcessClass::Browser,
+                    None,
+                ),
+            ],
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, status::Status};
+
+/// The child cgroups tab processes are moved into
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CgroupTier {
+    Foreground,
+    Background,
+    Whitelisted,
+}
+
+impl CgroupTier {
+    pub const ALL: [CgroupTier; 3] = [
+        CgroupTier::Foreground,
+        CgroupTier::Background,
+        CgroupTier::Whitelisted,
+    ];
+
+    fn dir_name(&self) -> &'static str {
+        match self {
+            CgroupTier::Foreground => "foreground",
+            CgroupTier::Background => "background",
+            CgroupTier::Whitelisted => "whitelisted",
+        }
+    }
+}
+
+/// The counters of "memory.events"
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MemoryEvents {
+    pub low: u64,
+    pub high: u64,
+    pub max: u64,
+    pub oom: u64,
+    pub oom_kill: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CgroupTierStatus {
+    pub tier: CgroupTier,
+    pub memory_current: u64,
+    pub memory_events: MemoryEvents,
+}
+
+/// Where cgroup v2 is mounted
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+const PROC_MOUNT: &str = "/proc";
+
+/// Owns a delegated cgroup v2 subtree, tab processes are moved between its tiers
+#[derive(Debug)]
+pub struct CgroupManager {
+    root: PathBuf,
+    // Original cgroups of processes are read from "<proc_mount>/<pid>/cgroup", relative to it
+    proc_mount: PathBuf,
+    cgroup_mount: PathBuf,
+    pid_tiers: HashMap<Pid, CgroupTier>,
+    // The cgroup each process was in before moved, restored on shutdown
+    original_cgroups: HashMap<Pid, PathBuf>,
+    memory_events: HashMap<CgroupTier, MemoryEvents>,
+}
+
+impl CgroupManager {
+    /// Create the tier cgroups under root and apply memory limits on the background tier
+    pub fn new(config: &Config) -> io::Result<Self> {
+        Self::with_mounts(config, Path::new(PROC_MOUNT), Path::new(CGROUP_MOUNT))
+    }
+
+    /// `new` with proc and cgroup v2 mounted elsewhere, e.g. in a temp dir by tests
+    fn with_mounts(config: &Config, proc_mount: &Path, cgroup_mount: &Path) -> io::Result<Self> {
+        let root = resolve_root(&config.cgroup.root);
+        fs::create_dir_all(&root)?;
+        // Let children use memory controller, the parent of root must already delegate it
+        fs::write(root.join("cgroup.subtree_control"), "+memory")?;
+        for tier in CgroupTier::ALL {
+            fs::create_dir_all(root.join(tier.dir_name()))?;
+        }
+
+        let background = root.join(CgroupTier::Background.dir_name());
+        write_memory_limit(&background.join("memory.high"), config.cgroup.memory_high)?;
+        write_memory_limit(&background.join("memory.max"), config.cgroup.memory_max)?;
+
+        Ok(CgroupManager {
+            root,
+            proc_mount: proc_mount.to_path_buf(),
+            cgroup_mount: cgroup_mount.to_path_buf(),
+            pid_tiers: HashMap::new(),
+            original_cgroups: HashMap::new(),
+            memory_events: HashMap::new(),
+        })
+    }
+
+    /// Move every tab process into the tier it belongs to, only write when the tier changed
+    pub fn assign_tiers(&mut self, status: &Status, config: &Config) {
+        self.pid_tiers
+            .retain(|pid, _| status.tab_infos.contains_key(pid));
+        self.original_cgroups
+            .retain(|pid, _| status.tab_infos.contains_key(pid));
+
+        for (&pid, tab_info) in &status.tab_infos {
+            let in_whitelist = config
+                .whitelist
+                .iter()
+                .any(|regex| regex.is_match(&tab_info.url));
+            let tier = if in_whitelist || (config.whitelist_audible_tab && tab_info.audible) {
+                CgroupTier::Whitelisted
+            } else if tab_info.active {
+                CgroupTier::Foreground
+            } else {
+                CgroupTier::Background
+            };
+
+            if self.pid_tiers.get(&pid) == Some(&tier) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = self.original_cgroups.entry(pid) {
+                match read_cgroup(&self.proc_mount, &self.cgroup_mount, pid) {
+                    Ok(original_cgroup) => {
+                        entry.insert(original_cgroup);
+                    }
+                    Err(e) => {
+                        warn!(target: "cgroup", "Failed to read cgroup of process {}: {}", pid, e)
+                    }
+                }
+            }
+            let procs_path = self.root.join(tier.dir_name()).join("cgroup.procs");
+            match fs::write(&procs_path, pid.to_string()) {
+                Ok(_) => {
+                    self.pid_tiers.insert(pid, tier);
+                }
+                Err(e) => {
+                    warn!(
+                        target: "cgroup",
+                        "Failed to move process {} into {:?}: {}",
+                        pid, procs_path, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Read memory usage and events of each tier, report newly happened throttling and OOM
+    pub fn read_tier_statuses(&mut self) -> Vec<CgroupTierStatus> {
+        CgroupTier::ALL
+            .into_iter()
+            .filter_map(|tier| {
+                let tier_path = self.root.join(tier.dir_name());
+                let memory_events = match read_memory_events(&tier_path.join("memory.events")) {
+                    Ok(memory_events) => memory_events,
+                    Err(e) => {
+                        warn!(
+                            target: "cgroup",
+                            "Failed to read memory.events of {:?}: {}",
+                            tier_path,
+                            e
+                        );
+                        return None;
+                    }
+                };
+                let memory_current = fs::read_to_string(tier_path.join("memory.current"))
+                    .ok()
+                    .and_then(|s| s.trim().parse::<u64>().ok())
+                    .unwrap_or(0);
+
+                let last_memory_events = self.memory_events.insert(tier, memory_events);
+                let last_memory_events = last_memory_events.unwrap_or_default();
+                if memory_events.high > last_memory_events.high {
+                    info!(
+                        target: "cgroup",
+                        "Cgroup {:?} throttled {} times by memory.high",
+                        tier,
+                        memory_events.high - last_memory_events.high
+                    );
+                }
+                if memory_events.oom_kill > last_memory_events.oom_kill {
+                    warn!(
+                        target: "cgroup",
+                        "Cgroup {:?} had {} processes killed by OOM",
+                        tier,
+                        memory_events.oom_kill - last_memory_events.oom_kill
+                    );
+                }
+
+                Some(CgroupTierStatus {
+                    tier,
+                    memory_current,
+                    memory_events,
+                })
+            })
+            .collect()
+    }
+
+    /// Move processes back to their original cgroups and lift the background limits,
+    /// so tabs are not throttled after tab-memory-manager quits
+    pub fn release(&mut self) {
+        for (pid, original_cgroup) in self.original_cgroups.drain() {
+            let procs_path = original_cgroup.join("cgroup.procs");
+            if let Err(e) = fs::write(&procs_path, pid.to_string()) {
+                // The process may already exit
+                debug!(
+                    target: "cgroup",
+                    "Failed to move process {} back into {:?}: {}",
+                    pid, procs_path, e
+                );
+            }
+        }
+        self.pid_tiers.clear();
+
+        let background = self.root.join(CgroupTier::Background.dir_name());
+        for file_name in ["memory.high", "memory.max"] {
+            if let Err(e) = write_memory_limit(&background.join(file_name), None) {
+                warn!(target: "cgroup", "Failed to lift {} of {:?}: {}", file_name, background, e);
+            }
+        }
+    }
+}
+
+/// The cgroup v2 directory of the process, from "/proc/<pid>/cgroup"
+fn read_cgroup(proc_mount: &Path, cgroup_mount: &Path, pid: Pid) -> io::Result<PathBuf> {
+    let content = fs::read_to_string(proc_mount.join(pid.to_string()).join("cgroup"))?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|cgroup| cgroup_mount.join(cgroup.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not in a cgroup v2 hierarchy"))
+}
+
+/// Replace "{uid}" in the configured root
+fn resolve_root(root: &Path) -> PathBuf {
+    let root = root.to_string_lossy();
+    if !root.contains("{uid}") {
+        return PathBuf::from(root.as_ref());
+    }
+    let uid = fs::metadata("/proc/self")
+        .map(|metadata| metadata.uid())
+        .unwrap_or(0);
+    PathBuf::from(root.replace("{uid}", &uid.to_string()))
+}
+
+/// `None` is unlimited, "max" of cgroup
+fn write_memory_limit(path: &Path, limit: Option<u64>) -> io::Result<()> {
+    let value = match limit {
+        Some(limit) => limit.to_string(),
+        None => "max".to_string(),
+    };
+    fs::write(path, value)
+}
+
+fn read_memory_events(path: &Path) -> io::Result<MemoryEvents> {
+    let content = fs::read_to_string(path)?;
+    let mut memory_events = MemoryEvents::default();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let Ok(value) = value.parse::<u64>() else {
+            continue;
+        };
+        match key {
+            "low" => memory_events.low = value,
+            "high" => memory_events.high = value,
+            "max" => memory_events.max = value,
+            "oom" => memory_events.oom = value,
+            "oom_kill" => memory_events.oom_kill = value,
+            _ => (),
+        }
+    }
+    Ok(memory_events)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::TabInfo;
+
+    use super::*;
+
+    /// A fake proc and cgroup v2 mount in a temp dir, removed when dropped
+    struct Mounts {
+        dir: PathBuf,
+    }
+
+    impl Mounts {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("cgroup-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("proc")).unwrap();
+            fs::create_dir_all(dir.join("cgroup")).unwrap();
+            Mounts { dir }
+        }
+
+        fn config(&self) -> Config {
+            let mut config: Config = toml::from_str(include_str!("config.toml")).unwrap();
+            config.cgroup.root = self.dir.join("cgroup/tab-memory-manager.slice");
+            config
+        }
+
+        fn manager(&self, config: &Config) -> CgroupManager {
+            CgroupManager::with_mounts(config, &self.dir.join("proc"), &self.dir.join("cgroup"))
+                .unwrap()
+        }
+
+        /// A process in the cgroup, e.g. "app.slice/browser.scope"
+        fn spawn(&self, pid: u32, cgroup: &str) {
+            fs::create_dir_all(self.dir.join("proc").join(pid.to_string())).unwrap();
+            fs::write(
+                self.dir.join(format!("proc/{pid}/cgroup")),
+                format!("0::/{cgroup}\n"),
+            )
+            .unwrap();
+            fs::create_dir_all(self.dir.join("cgroup").join(cgroup)).unwrap();
+        }
+
+        fn read(&self, path: &str) -> String {
+            fs::read_to_string(self.dir.join("cgroup").join(path)).unwrap()
+        }
+    }
+
+    impl Drop for Mounts {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn status(tabs: &[(u32, bool)]) -> Status {
+        Status {
+            tab_infos: tabs
+                .iter()
+                .map(|&(pid, active)| {
+                    (
+                        Pid::from_u32(pid),
+                        TabInfo {
+                            active,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tiers_are_created_with_background_limits() {
+        let mounts = Mounts::new("setup");
+        let mut config = mounts.config();
+        config.cgroup.memory_max = None;
+        mounts.manager(&config);
+
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/cgroup.subtree_control"),
+            "+memory"
+        );
+        for tier in CgroupTier::ALL {
+            assert!(mounts
+                .dir
+                .join("cgroup/tab-memory-manager.slice")
+                .join(tier.dir_name())
+                .is_dir());
+        }
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/background/memory.high"),
+            "1500000000"
+        );
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/background/memory.max"),
+            "max"
+        );
+    }
+
+    #[test]
+    fn tabs_move_between_tiers_and_back_on_release() {
+        let mounts = Mounts::new("tiers");
+        let config = mounts.config();
+        let mut manager = mounts.manager(&config);
+        mounts.spawn(100, "app.slice/browser.scope");
+        mounts.spawn(200, "app.slice/browser.scope");
+
+        manager.assign_tiers(&status(&[(100, false), (200, true)]), &config);
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/background/cgroup.procs"),
+            "100"
+        );
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/foreground/cgroup.procs"),
+            "200"
+        );
+
+        // Brought to foreground, the original cgroup is still the one before the first move
+        manager.assign_tiers(&status(&[(100, true)]), &config);
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/foreground/cgroup.procs"),
+            "100"
+        );
+        assert_eq!(manager.pid_tiers.len(), 1);
+
+        manager.release();
+        assert_eq!(mounts.read("app.slice/browser.scope/cgroup.procs"), "100");
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/background/memory.high"),
+            "max"
+        );
+        assert_eq!(
+            mounts.read("tab-memory-manager.slice/background/memory.max"),
+            "max"
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{info, warn};
+
+use crate::protocol::Timestamp;
+
+/// Difference of the wall clock from the clock tolerated before it's taken as a jump, also the
+/// shortest suspend noticed
+const SKEW_TOLERANCE_SECS: f64 = 1.0;
+/// Jumps kept to convert browser timestamps, older browser timestamps use the oldest of them
+const MAX_SKEWS: usize = 64;
+
+/// Time of the daemon in milliseconds since unix epoch, like `Date.now()` in extension but
+/// steady: it starts at the wall clock and goes on with the monotonic clock, so timers of tabs
+/// don't jump when the wall clock is set, or by the time the system was suspended.
+/// Cloned by threads which need the time, they all share the same clock
+#[derive(Clone, Debug)]
+pub struct Clock {
+    inner: Arc<Mutex<ClockInner>>,
+}
+
+#[derive(Debug)]
+struct ClockInner {
+    source: Source,
+    // Wall clock minus this clock, since the wall timestamp it changed at, the latest last
+    skews: Vec<Skew>,
+    // Total time suspended as of the last call
+    suspended_secs: f64,
+    // When the system last resumed from suspend, by this clock
+    resume_timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug)]
+enum Source {
+    // The monotonic clock stops while suspended, the boottime clock doesn't
+    System {
+        start_timestamp: Timestamp,
+        start_instant: Instant,
+        start_boottime: Duration,
+    },
+    // Only moves when set, e.g. by tests or replay
+    Simulated {
+        timestamp: Timestamp,
+        wall_timestamp: Timestamp,
+        suspended_secs: f64,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Skew {
+    since_wall_timestamp: Timestamp,
+    // In milliseconds
+    skew: f64,
+}
+
+impl Clock {
+    /// Starts at the wall clock of this system
+    pub fn system() -> Self {
+        let start_timestamp = wall_timestamp();
+        Clock::new(
+            Source::System {
+                start_timestamp,
+                start_instant: Instant::now(),
+                start_boottime: boottime(),
+            },
+            start_timestamp,
+        )
+    }
+
+    /// Starts at `timestamp`, with the wall clock at the same time, see `set`
+    pub fn simulated(timestamp: Timestamp) -> Self {
+        Clock::new(
+            Source::Simulated {
+                timestamp,
+                wall_timestamp: timestamp,
+                suspended_secs: 0.0,
+            },
+            timestamp,
+        )
+    }
+
+    fn new(source: Source, start_timestamp: Timestamp) -> Self {
+        Clock {
+            inner: Arc::new(Mutex::new(ClockInner {
+                source,
+                skews: vec![Skew {
+                    since_wall_timestamp: start_timestamp,
+                    skew: 0.0,
+                }],
+                suspended_secs: 0.0,
+                resume_timestamp: None,
+            })),
+        }
+    }
+
+    /// Move a simulated clock, a wall timestamp apart from `timestamp` is a jump of the wall
+    /// clock. The system clock is not changed
+    pub fn set(&self, timestamp: Timestamp, wall_timestamp: Timestamp) {
+        if let Source::Simulated {
+            timestamp: simulated_timestamp,
+            wall_timestamp: simulated_wall_timestamp,
+            ..
+        } = &mut self.inner.lock().unwrap().source
+        {
+            *simulated_timestamp = timestamp;
+            *simulated_wall_timestamp = wall_timestamp;
+        }
+    }
+
+    /// Move a simulated clock and its wall clock forward together
+    pub fn advance(&self, duration: Duration) {
+        if let Source::Simulated {
+            timestamp,
+            wall_timestamp,
+            ..
+        } = &mut self.inner.lock().unwrap().source
+        {
+            *timestamp = add_secs(*timestamp, duration.as_secs_f64());
+            *wall_timestamp = add_secs(*wall_timestamp, duration.as_secs_f64());
+        }
+    }
+
+    /// Suspend a simulated system, only its wall clock goes on
+    pub fn suspend(&self, duration: Duration) {
+        if let Source::Simulated {
+            wall_timestamp,
+            suspended_secs,
+            ..
+        } = &mut self.inner.lock().unwrap().source
+        {
+            *wall_timestamp = add_secs(*wall_timestamp, duration.as_secs_f64());
+            *suspended_secs += duration.as_secs_f64();
+        }
+    }
+
+    /// Current time, also checks whether the wall clock jumped since the last call
+    pub fn now(&self) -> Timestamp {
+        self.inner.lock().unwrap().now()
+    }
+
+    /// When the system last resumed from suspend, by this clock, `None` if it didn't since the
+    /// clock started
+    pub fn resume_timestamp(&self) -> Option<Timestamp> {
+        let inner = &mut self.inner.lock().unwrap();
+        inner.now();
+        inner.resume_timestamp
+    }
+
+    /// Total time the system was suspended since the clock started
+    pub fn suspended_secs(&self) -> f64 {
+        self.inner.lock().unwrap().source.read().2
+    }
+
+    /// Convert a timestamp of extension, which is taken from the wall clock, with the skew of
+    /// the wall clock at that time. Timestamps after now are now, e.g. those from before the
+    /// wall clock was set back
+    pub fn from_browser(&self, browser_timestamp: Timestamp) -> Timestamp {
+        let inner = &mut self.inner.lock().unwrap();
+        let now = inner.now();
+        let skew = inner
+            .skews
+            .iter()
+            .rev()
+            .find(|skew| skew.since_wall_timestamp <= browser_timestamp)
+            .unwrap_or(&inner.skews[0])
+            .skew;
+        (browser_timestamp - skew).min(now)
+    }
+
+    /// Convert a timestamp of this clock to the wall clock as of now, e.g. to be read by the next
+    /// run of the daemon
+    pub fn to_wall(&self, timestamp: Timestamp) -> Timestamp {
+        let inner = &mut self.inner.lock().unwrap();
+        inner.now();
+        timestamp + inner.skews[inner.skews.len() - 1].skew
+    }
+}
+
+impl ClockInner {
+    fn now(&mut self) -> Timestamp {
+        let (timestamp, wall_timestamp, suspended_secs) = self.source.read();
+
+        let newly_suspended_secs = suspended_secs - self.suspended_secs;
+        self.suspended_secs = suspended_secs;
+        if newly_suspended_secs > SKEW_TOLERANCE_SECS {
+            info!(
+                target: "clock",
+                "System resumed from suspend after {:.1}s, timers of tabs didn't go on meanwhile",
+                newly_suspended_secs
+            );
+            self.resume_timestamp = Some(timestamp);
+        }
+
+        let skew = wall_timestamp - timestamp;
+        let last_skew = self.skews[self.skews.len() - 1].skew;
+        if (skew - last_skew).abs() > SKEW_TOLERANCE_SECS * 1000.0 {
+            // The wall clock goes on while suspended
+            let jump_secs = secs_between(last_skew, skew) - newly_suspended_secs;
+            if jump_secs.abs() > SKEW_TOLERANCE_SECS {
+                warn!(
+                    target: "clock",
+                    "Wall clock jumped {:+.1}s, timers of tabs go on with the monotonic clock",
+                    jump_secs
+                );
+            }
+            self.skews.push(Skew {
+                since_wall_timestamp: wall_timestamp,
+                skew,
+            });
+            if self.skews.len() > MAX_SKEWS {
+                self.skews.remove(0);
+            }
+        }
+        timestamp
+    }
+}
+
+impl Source {
+    /// Timestamp of the clock, wall timestamp and total secs suspended
+    fn read(&self) -> (Timestamp, Timestamp, f64) {
+        match self {
+            Source::System {
+                start_timestamp,
+                start_instant,
+                start_boottime,
+            } => {
+                let monotonic_elapsed = start_instant.elapsed();
+                let boottime_elapsed = boottime().saturating_sub(*start_boottime);
+                (
+                    add_secs(*start_timestamp, monotonic_elapsed.as_secs_f64()),
+                    wall_timestamp(),
+                    boottime_elapsed
+                        .saturating_sub(monotonic_elapsed)
+                        .as_secs_f64(),
+                )
+            }
+            Source::Simulated {
+                timestamp,
+                wall_timestamp,
+                suspended_secs,
+            } => (*timestamp, *wall_timestamp, *suspended_secs),
+        }
+    }
+}
+
+/// Time since boot including the time suspended
+fn boottime() -> Duration {
+    let mut timespec = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `timespec` is a valid pointer to write the time to
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut timespec) };
+    if ret != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
+}
+
+/// Milliseconds since unix epoch of the wall clock, the same as `Date.now()` in extension
+pub fn wall_timestamp() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        * 1000.0
+}
+
+/// Seconds from `from` to `to`
+pub fn secs_between(from: Timestamp, to: Timestamp) -> f64 {
+    (to - from) / 1000.0
+}
+
+/// `secs` after `timestamp`, before it if negative
+pub fn add_secs(timestamp: Timestamp, secs: f64) -> Timestamp {
+    timestamp + secs * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: Timestamp = 1_700_000_000_000.0;
+
+    #[test]
+    fn browser_timestamps_are_converted_with_the_skew_at_that_time() {
+        let clock = Clock::simulated(START);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.from_browser(START + 5_000.0), START + 5_000.0);
+
+        // Suspended for an hour, the monotonic clock stopped meanwhile
+        clock.suspend(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.now(), START + 11_000.0);
+        assert_eq!(clock.resume_timestamp(), Some(START + 11_000.0));
+        assert_eq!(clock.from_browser(START + 5_000.0), START + 5_000.0);
+        assert_eq!(clock.from_browser(START + 3_611_000.0), START + 11_000.0);
+        assert_eq!(clock.to_wall(START + 11_000.0), START + 3_611_000.0);
+    }
+
+    #[test]
+    fn browser_timestamps_after_now_are_now() {
+        let clock = Clock::simulated(START);
+        clock.advance(Duration::from_secs(10));
+        // The wall clock was set back by a minute
+        clock.set(START + 11_000.0, START - 49_000.0);
+        assert_eq!(clock.now(), START + 11_000.0);
+        assert_eq!(clock.resume_timestamp(), None);
+        assert_eq!(clock.from_browser(START + 5_000.0), START + 11_000.0);
+        assert_eq!(clock.from_browser(START - 48_000.0), START + 11_000.0);
+    }
+
+    #[test]
+    fn small_skew_is_not_a_jump() {
+        let clock = Clock::simulated(START);
+        clock.set(START + 10_000.0, START + 10_500.0);
+        assert_eq!(clock.from_browser(START + 10_500.0), START + 10_000.0);
+        assert_eq!(clock.to_wall(START), START);
+    }
+}
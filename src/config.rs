@@ -1,24 +1,59 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use regex::Regex;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::PROJECT_NAME;
+use crate::{browser::Browser, PROJECT_NAME};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
+    // Which browser the extension runs in, decide how tab processes are found
+    #[serde(default)]
+    pub browser: Browser,
     // The browser executable name
     pub browser_name: String,
     pub kill_tab_strategies: Vec<KillTabStrategy>,
     // The interval of applying strategy, in secs
     pub check_interval_secs: f32,
+    // Time limits don't kill tabs for this long after the system resumed from suspend, in secs
+    #[serde(default = "default_resume_grace_secs")]
+    pub resume_grace_secs: f64,
     // Don't discard if the tab produce sound recently
     pub whitelist_audible_tab: bool,
     // A list of regex, they will not be killed if matched
-    #[serde(deserialize_with = "deserialize_regex")]
+    #[serde(
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_regex"
+    )]
     pub whitelist: Vec<Regex>,
     // The detail configuration of strategies
     pub strategy: Strategy,
+    // Who is allowed to connect the websocket
+    #[serde(default)]
+    pub websocket: Websocket,
+    // Put tab processes into cgroup v2 tiers, memory of background tabs is limited by kernel
+    #[serde(default)]
+    pub cgroup: Cgroup,
+    // Log level, targets and format
+    #[serde(default)]
+    pub log: Log,
+    // Desktop notifications about killed tabs
+    #[serde(default)]
+    pub notification: Notification,
+    // Time series of tab metrics kept in memory
+    #[serde(default)]
+    pub history: History,
+}
+
+impl Config {
+    /// How long to wait for tab data from extension, older tab data is considered stale
+    pub fn update_status_timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.check_interval_secs) * 4
+    }
+}
+
+fn default_resume_grace_secs() -> f64 {
+    60.0
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
@@ -32,6 +67,17 @@ pub enum KillTabStrategy {
     CpuIdleTimeLimit,
 }
 
+impl KillTabStrategy {
+    /// The name in config
+    pub fn name(&self) -> &'static str {
+        match self {
+            KillTabStrategy::RssLimit => "rss_limit",
+            KillTabStrategy::BackgroundTimeLimit => "background_time_limit",
+            KillTabStrategy::CpuIdleTimeLimit => "cpu_idle_time_limit",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Strategy {
     pub rss_limit: RssLimit,
@@ -42,6 +88,9 @@ pub struct Strategy {
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct RssLimit {
     pub max_bytes: u64,
+    // Count browser processes without tabs (gpu, utility, extension...) toward the limit, only tabs are killed
+    #[serde(default)]
+    pub include_non_tab_processes: bool,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
@@ -55,6 +104,116 @@ pub struct CpuIdleTimeLimit {
     pub max_idle_cpu_usage: f64,
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Websocket {
+    // Only accept connections from these extensions, by "Origin" header
+    // "chrome-extension://<id>" or "moz-extension://<id>", all are rejected if empty
+    pub extension_ids: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Cgroup {
+    pub enable: bool,
+    // A delegated cgroup directory, "{uid}" is replaced with current user id
+    pub root: PathBuf,
+    // Throttle background tabs above this, unlimited if `None`, "max" in config
+    #[serde(
+        deserialize_with = "deserialize_memory_limit",
+        serialize_with = "serialize_memory_limit"
+    )]
+    pub memory_high: Option<u64>,
+    // Background tabs are OOM killed by kernel above this, unlimited if `None`, "max" in config
+    #[serde(
+        deserialize_with = "deserialize_memory_limit",
+        serialize_with = "serialize_memory_limit"
+    )]
+    pub memory_max: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Notification {
+    pub enable: bool,
+    // Warn before a tab is killed by background time limit, in secs, 0 to disable
+    pub warn_before_secs: f64,
+    // How long "Keep this tab" of the warning protects the tab, in secs
+    pub keep_secs: f64,
+}
+
+impl Default for Notification {
+    fn default() -> Self {
+        Notification {
+            enable: false,
+            warn_before_secs: 30.0,
+            keep_secs: 600.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct History {
+    // How long metrics are kept, in secs
+    pub retention_secs: f64,
+    // Metrics older than this are downsampled, in secs
+    pub full_resolution_secs: f64,
+    // Downsampled metrics are averaged over this interval, in secs
+    pub downsample_interval_secs: f64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            retention_secs: 24.0 * 60.0 * 60.0,
+            full_resolution_secs: 10.0 * 60.0,
+            downsample_interval_secs: 60.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Log {
+    // "level,target=level,...", overridden by env var "TAB_MEMORY_MANAGER_LOG"
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One json object per line
+    Json,
+}
+
+impl Default for Cgroup {
+    fn default() -> Self {
+        Cgroup {
+            enable: false,
+            root: PathBuf::from(
+                "/sys/fs/cgroup/user.slice/user-{uid}.slice/user@{uid}.service/app.slice/tab-memory-manager.slice",
+            ),
+            memory_high: Some(1_500_000_000),
+            memory_max: Some(2_000_000_000),
+        }
+    }
+}
+
+#[allow(clippy::let_and_return)]
 pub fn read_or_create_new_config() -> Config {
     let config_dir = dirs::config_dir().unwrap();
     let config_path = config_dir.join(format!("{PROJECT_NAME}.toml"));
@@ -68,7 +227,7 @@ pub fn read_or_create_new_config() -> Config {
     config
 }
 
-fn read_config(config_path: &PathBuf) -> Option<Config> {
+pub fn read_config(config_path: &PathBuf) -> Option<Config> {
     let config_string = std::fs::read_to_string(config_path.clone());
     match config_string {
         Ok(config_string) => match toml::from_str::<Config>(&config_string) {
@@ -116,3 +275,69 @@ where
         .map(|s| Regex::new(&s).map_err(serde::de::Error::custom))
         .collect()
 }
+
+fn serialize_regex<S>(regexes: &[Regex], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(regexes.iter().map(Regex::as_str))
+}
+
+/// Bytes, or "max" for unlimited like cgroup, a missing limit takes the default instead
+fn deserialize_memory_limit<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MemoryLimit {
+        Bytes(u64),
+        Max(String),
+    }
+
+    match MemoryLimit::deserialize(deserializer)? {
+        MemoryLimit::Bytes(bytes) => Ok(Some(bytes)),
+        MemoryLimit::Max(max) if max == "max" => Ok(None),
+        MemoryLimit::Max(other) => Err(serde::de::Error::custom(format!(
+            "invalid memory limit {:?}, expect bytes or \"max\"",
+            other
+        ))),
+    }
+}
+
+fn serialize_memory_limit<S>(limit: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match limit {
+        Some(bytes) => serializer.serialize_u64(*bytes),
+        None => serializer.serialize_str("max"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_defaults_match_the_default_config() {
+        let config: Config = toml::from_str(include_str!("config.toml")).unwrap();
+        let default = Cgroup::default();
+        assert_eq!(config.cgroup.enable, default.enable);
+        assert_eq!(config.cgroup.root, default.root);
+        assert_eq!(config.cgroup.memory_high, default.memory_high);
+        assert_eq!(config.cgroup.memory_max, default.memory_max);
+    }
+
+    #[test]
+    fn cgroup_memory_limits_are_bytes_or_max() {
+        let cgroup: Cgroup = toml::from_str("memory_high = 0\nmemory_max = \"max\"").unwrap();
+        assert_eq!(cgroup.memory_high, Some(0));
+        assert_eq!(cgroup.memory_max, None);
+        assert!(toml::from_str::<Cgroup>("memory_max = \"unlimited\"").is_err());
+        assert_eq!(
+            serde_json::to_value(&cgroup).unwrap()["memory_max"],
+            serde_json::json!("max")
+        );
+    }
+}
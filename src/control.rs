@@ -0,0 +1,63 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{protection::Protections, protocol::Timestamp};
+
+/// Changed at runtime through the control api, read by the tab killer on each tick
+pub type SharedControl = Arc<Mutex<Control>>;
+
+#[derive(Debug, Default)]
+pub struct Control {
+    pause: Option<Pause>,
+    pub protections: Protections,
+    // Pids of tabs to kill on the next tick, requested by user
+    kill_requests: Vec<u32>,
+}
+
+/// Strategies don't kill any tab while paused
+#[derive(Clone, Copy, Debug)]
+struct Pause {
+    // Resumed automatically at this timestamp, `None` until resumed by user
+    until: Option<Timestamp>,
+}
+
+/// What the tab killer applies in a tick
+#[derive(Debug, Default)]
+pub struct ControlState {
+    pub paused: bool,
+    pub protections: Protections,
+    pub kill_requests: Vec<u32>,
+}
+
+impl Control {
+    pub fn pause(&mut self, until: Option<Timestamp>) {
+        self.pause = Some(Pause { until });
+    }
+
+    /// Return false if it's not paused
+    pub fn resume(&mut self) -> bool {
+        self.pause.take().is_some()
+    }
+
+    pub fn request_kill(&mut self, pid: u32) {
+        if !self.kill_requests.contains(&pid) {
+            self.kill_requests.push(pid);
+        }
+    }
+
+    /// Drop what is expired, then copy the state for a tick and take kill requests
+    pub fn take_state(&mut self, timestamp: Timestamp) -> ControlState {
+        if self
+            .pause
+            .is_some_and(|pause| pause.until.is_some_and(|until| until <= timestamp))
+        {
+            self.pause = None;
+        }
+        self.protections.remove_expired(timestamp);
+
+        ControlState {
+            paused: self.pause.is_some(),
+            protections: self.protections.clone(),
+            kill_requests: std::mem::take(&mut self.kill_requests),
+        }
+    }
+}
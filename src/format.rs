@@ -0,0 +1,29 @@
+/// Bytes in decimal units like the byte sizes in config, e.g. "1.2 GB", "350 MB"
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    if unit >= 3 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_are_formatted_in_decimal_units() {
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(350_000_000), "350 MB");
+        assert_eq!(format_bytes(1_500_000_000), "1.5 GB");
+        // Pss read in KiB
+        assert_eq!(format_bytes(2048 * 1024), "2 MB");
+    }
+}
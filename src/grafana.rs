@@ -0,0 +1,164 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::{
+    history::{History, TabPoint},
+    protocol::Timestamp,
+};
+
+/// Targets of `/query`, listed by `/search`
+const METRICS: [&str; 7] = [
+    "total_rss",
+    "available_memory",
+    "tab_count",
+    "tab_rss",
+    "tab_cpu_usage",
+    "tab_background_time",
+    "tab_cpu_idle_time",
+];
+
+/// Time range of the dashboard, in RFC 3339 e.g. "2016-10-31T06:33:44.866Z"
+#[derive(Debug, Deserialize)]
+pub struct Range {
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    from: Timestamp,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    to: Timestamp,
+}
+
+/// Body of `POST /query`
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    range: Range,
+    targets: Vec<Target>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    // One of `METRICS`, empty if not chosen yet
+    #[serde(default)]
+    target: String,
+}
+
+/// Body of `POST /annotations`
+#[derive(Debug, Deserialize)]
+pub struct AnnotationRequest {
+    range: Range,
+    // Sent back as is
+    #[serde(default)]
+    annotation: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeSeries {
+    target: String,
+    // [value, timestamp]
+    datapoints: Vec<(f64, Timestamp)>,
+}
+
+/// A tab killed, shown as a marker on the dashboard
+#[derive(Debug, Serialize)]
+pub struct Annotation {
+    annotation: Value,
+    time: Timestamp,
+    title: String,
+    text: String,
+    tags: Vec<&'static str>,
+}
+
+/// `POST /search`, metric names for the query editor
+pub fn search() -> &'static [&'static str] {
+    &METRICS
+}
+
+/// `POST /query`, a series for each global target, a series per tab for each `tab_` target
+pub fn query(request: &QueryRequest, history: &History) -> Result<Vec<TimeSeries>, String> {
+    let (from, to) = (request.range.from, request.range.to);
+    let mut time_series = Vec::new();
+    for target in &request.targets {
+        let target = target.target.as_str();
+        match target {
+            "" => {}
+            "total_rss" | "available_memory" | "tab_count" => {
+                time_series.push(TimeSeries {
+                    target: target.to_string(),
+                    datapoints: history
+                        .global_points(from, to)
+                        .iter()
+                        .map(|point| {
+                            let value = match target {
+                                "total_rss" => point.total_rss as f64,
+                                "available_memory" => point.available_memory as f64,
+                                _ => point.tab_count,
+                            };
+                            (value, point.timestamp)
+                        })
+                        .collect(),
+                });
+            }
+            "tab_rss" => {
+                time_series.extend(tab_series(history, from, to, |point| point.rss as f64))
+            }
+            "tab_cpu_usage" => time_series.extend(tab_series(history, from, to, |point| {
+                point.cpu_usage as f64
+            })),
+            "tab_background_time" => time_series.extend(tab_series(history, from, to, |point| {
+                point.background_time_secs
+            })),
+            "tab_cpu_idle_time" => time_series.extend(tab_series(history, from, to, |point| {
+                point.cpu_idle_time_secs
+            })),
+            target => return Err(format!("Unknown target {:?}", target)),
+        }
+    }
+    Ok(time_series)
+}
+
+/// `POST /annotations`, tabs killed in the range
+pub fn annotations(request: &AnnotationRequest, history: &History) -> Vec<Annotation> {
+    history
+        .killed_tabs(request.range.from, request.range.to)
+        .into_iter()
+        .map(|killed_tab| Annotation {
+            annotation: request.annotation.clone(),
+            time: killed_tab.timestamp,
+            title: format!("Killed {}", killed_tab.title),
+            text: format!("{} ({})", killed_tab.reason, killed_tab.url),
+            tags: vec![killed_tab.strategy],
+        })
+        .collect()
+}
+
+/// A series for each tab process, named by its latest title
+fn tab_series(
+    history: &History,
+    from: Timestamp,
+    to: Timestamp,
+    value: impl Fn(&TabPoint) -> f64,
+) -> Vec<TimeSeries> {
+    history
+        .tab_histories(from, to)
+        .into_iter()
+        .map(|tab_history| TimeSeries {
+            target: format!("{} ({})", tab_history.title, tab_history.pid),
+            datapoints: tab_history
+                .points
+                .iter()
+                .map(|point| (value(point), point.timestamp))
+                .collect(),
+        })
+        .collect()
+}
+
+fn deserialize_rfc3339<'de, D>(deserializer: D) -> Result<Timestamp, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let time = String::deserialize(deserializer)?;
+    let system_time = humantime::parse_rfc3339_weak(&time).map_err(serde::de::Error::custom)?;
+    Ok(system_time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(serde::de::Error::custom)?
+        .as_secs_f64()
+        * 1000.0)
+}
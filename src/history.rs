@@ -0,0 +1,457 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, RwLock},
+};
+
+use serde::Serialize;
+
+use crate::{
+    clock::{add_secs, Clock},
+    config,
+    protocol::{TabId, Timestamp},
+    snapshot::Snapshot,
+    status::Rss,
+};
+
+/// Time series of tab metrics and kills, appended by the tab killer thread after each tick.
+/// Timestamps are kept by the wall clock, like ranges of queries from dashboards
+pub type SharedHistory = Arc<RwLock<History>>;
+
+/// A tab which is sent the kill signal
+#[derive(Clone, Debug, Serialize)]
+pub struct KilledTab {
+    // By the clock of the daemon, history keeps it by the wall clock
+    pub timestamp: Timestamp,
+    pub tab_id: TabId,
+    pub pid: u32,
+    pub title: String,
+    pub url: String,
+    pub rss: Rss,
+    // Name of the strategy in config, "requested" if killed by user
+    pub strategy: &'static str,
+    pub reason: String,
+}
+
+/// Metrics of all tabs in a tick, or the average over an interval once downsampled
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct GlobalPoint {
+    pub timestamp: Timestamp,
+    pub total_rss: Rss,
+    // Memory of the system available for starting new applications
+    pub available_memory: u64,
+    pub tab_count: f64,
+}
+
+/// Metrics of a tab in a tick, or the average over an interval once downsampled
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct TabPoint {
+    pub timestamp: Timestamp,
+    pub rss: Rss,
+    pub cpu_usage: f32,
+    pub background_time_secs: f64,
+    pub cpu_idle_time_secs: f64,
+}
+
+/// Points of a tab process in a time range
+#[derive(Debug, Serialize)]
+pub struct TabHistory {
+    // The latest tab hosted by the process
+    pub id: TabId,
+    pub pid: u32,
+    // The latest title
+    pub title: String,
+    pub points: Vec<TabPoint>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    config: config::History,
+    global: Series<GlobalPoint>,
+    // By pid, a series is of the tab process rather than the tab, like the rest of the api and
+    // strategies, kept until all points of the process expire
+    tabs: BTreeMap<u32, TabSeries>,
+    // Oldest first
+    killed_tabs: VecDeque<KilledTab>,
+}
+
+#[derive(Debug)]
+struct TabSeries {
+    id: TabId,
+    title: String,
+    series: Series<TabPoint>,
+}
+
+/// Recent points at full resolution, older points averaged per interval
+#[derive(Debug)]
+struct Series<P> {
+    // Oldest first
+    downsampled: VecDeque<P>,
+    // Oldest first
+    recent: VecDeque<P>,
+}
+
+trait Point: Copy {
+    fn timestamp(&self) -> Timestamp;
+
+    /// One point for an interval, `points` is not empty
+    fn average(points: &[Self]) -> Self;
+}
+
+impl History {
+    pub fn new(config: config::History) -> Self {
+        History {
+            config,
+            global: Series::default(),
+            tabs: BTreeMap::new(),
+            killed_tabs: VecDeque::new(),
+        }
+    }
+
+    /// Append a tick, then downsample and drop what is older than retention. `clock` converts
+    /// timestamps of the tick to the wall clock
+    pub fn push(&mut self, snapshot: &Snapshot, killed_tabs: &[KilledTab], clock: &Clock) {
+        let timestamp = clock.to_wall(snapshot.timestamp);
+        self.global.push(GlobalPoint {
+            timestamp,
+            total_rss: snapshot.tab_infos.iter().map(|tab_info| tab_info.rss).sum(),
+            available_memory: snapshot.available_memory,
+            tab_count: snapshot.tab_infos.len() as f64,
+        });
+        for tab_info in &snapshot.tab_infos {
+            let tab_series = self.tabs.entry(tab_info.pid).or_insert_with(|| TabSeries {
+                id: tab_info.id,
+                title: tab_info.title.clone(),
+                series: Series::default(),
+            });
+            // The process may be reused by another tab, or the page navigated
+            tab_series.id = tab_info.id;
+            if tab_series.title != tab_info.title {
+                tab_series.title.clone_from(&tab_info.title);
+            }
+            tab_series.series.push(TabPoint {
+                timestamp,
+                rss: tab_info.rss,
+                cpu_usage: tab_info.cpu_usage,
+                background_time_secs: tab_info.background_time_secs,
+                cpu_idle_time_secs: tab_info.cpu_idle_time_secs,
+            });
+        }
+        self.killed_tabs
+            .extend(killed_tabs.iter().map(|killed_tab| KilledTab {
+                timestamp: clock.to_wall(killed_tab.timestamp),
+                ..killed_tab.clone()
+            }));
+
+        self.global.compact(timestamp, &self.config);
+        for tab_series in self.tabs.values_mut() {
+            tab_series.series.compact(timestamp, &self.config);
+        }
+        self.tabs
+            .retain(|_, tab_series| !tab_series.series.is_empty());
+        let oldest_timestamp = add_secs(timestamp, -self.config.retention_secs);
+        while self
+            .killed_tabs
+            .front()
+            .is_some_and(|killed_tab| killed_tab.timestamp < oldest_timestamp)
+        {
+            self.killed_tabs.pop_front();
+        }
+    }
+
+    /// Global points between `from` and `to` inclusive
+    pub fn global_points(&self, from: Timestamp, to: Timestamp) -> Vec<GlobalPoint> {
+        self.global.points(from, to)
+    }
+
+    /// Tabs with any point between `from` and `to` inclusive
+    pub fn tab_histories(&self, from: Timestamp, to: Timestamp) -> Vec<TabHistory> {
+        self.tabs
+            .iter()
+            .filter_map(|(&pid, tab_series)| tab_series.history(pid, from, to))
+            .collect()
+    }
+
+    /// Points of the tab process between `from` and `to` inclusive
+    pub fn tab_history(&self, pid: u32, from: Timestamp, to: Timestamp) -> Option<TabHistory> {
+        self.tabs.get(&pid)?.history(pid, from, to)
+    }
+
+    /// Tabs killed between `from` and `to` inclusive
+    pub fn killed_tabs(&self, from: Timestamp, to: Timestamp) -> Vec<KilledTab> {
+        self.killed_tabs
+            .iter()
+            .filter(|killed_tab| from <= killed_tab.timestamp && killed_tab.timestamp <= to)
+            .cloned()
+            .collect()
+    }
+}
+
+impl TabSeries {
+    fn history(&self, pid: u32, from: Timestamp, to: Timestamp) -> Option<TabHistory> {
+        let points = self.series.points(from, to);
+        if points.is_empty() {
+            return None;
+        }
+        Some(TabHistory {
+            id: self.id,
+            pid,
+            title: self.title.clone(),
+            points,
+        })
+    }
+}
+
+impl<P> Default for Series<P> {
+    fn default() -> Self {
+        Series {
+            downsampled: VecDeque::new(),
+            recent: VecDeque::new(),
+        }
+    }
+}
+
+impl<P: Point> Series<P> {
+    fn push(&mut self, point: P) {
+        self.recent.push_back(point);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.downsampled.is_empty() && self.recent.is_empty()
+    }
+
+    /// Average recent points older than `full_resolution_secs` per interval,
+    /// then drop points older than `retention_secs`
+    fn compact(&mut self, timestamp: Timestamp, config: &config::History) {
+        let interval = config.downsample_interval_secs * 1000.0;
+        if interval > 0.0 {
+            // Only whole intervals, so each interval is averaged once
+            let downsample_before =
+                ((timestamp - config.full_resolution_secs * 1000.0) / interval).floor() * interval;
+            while let Some(point) = self.recent.front() {
+                if point.timestamp() >= downsample_before {
+                    break;
+                }
+                let interval_end = ((point.timestamp() / interval).floor() + 1.0) * interval;
+                let count = self
+                    .recent
+                    .iter()
+                    .take_while(|point| point.timestamp() < interval_end)
+                    .count();
+                let points: Vec<P> = self.recent.drain(..count).collect();
+                self.downsampled.push_back(P::average(&points));
+            }
+        }
+
+        let oldest_timestamp = add_secs(timestamp, -config.retention_secs);
+        for points in [&mut self.downsampled, &mut self.recent] {
+            while points
+                .front()
+                .is_some_and(|point| point.timestamp() < oldest_timestamp)
+            {
+                points.pop_front();
+            }
+        }
+    }
+
+    fn points(&self, from: Timestamp, to: Timestamp) -> Vec<P> {
+        self.downsampled
+            .iter()
+            .chain(&self.recent)
+            .filter(|point| from <= point.timestamp() && point.timestamp() <= to)
+            .copied()
+            .collect()
+    }
+}
+
+impl Point for GlobalPoint {
+    fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn average(points: &[Self]) -> Self {
+        GlobalPoint {
+            timestamp: mean(points, |point| point.timestamp),
+            total_rss: mean(points, |point| point.total_rss as f64) as Rss,
+            available_memory: mean(points, |point| point.available_memory as f64) as u64,
+            tab_count: mean(points, |point| point.tab_count),
+        }
+    }
+}
+
+impl Point for TabPoint {
+    fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    fn average(points: &[Self]) -> Self {
+        TabPoint {
+            timestamp: mean(points, |point| point.timestamp),
+            rss: mean(points, |point| point.rss as f64) as Rss,
+            cpu_usage: mean(points, |point| point.cpu_usage as f64) as f32,
+            background_time_secs: mean(points, |point| point.background_time_secs),
+            cpu_idle_time_secs: mean(points, |point| point.cpu_idle_time_secs),
+        }
+    }
+}
+
+fn mean<P>(points: &[P], value: impl Fn(&P) -> f64) -> f64 {
+    points.iter().map(value).sum::<f64>() / points.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::snapshot::SnapshotTabInfo;
+
+    const START: Timestamp = 1_700_000_000_000.0;
+
+    fn config() -> config::History {
+        config::History::default()
+    }
+
+    fn snapshot(timestamp: Timestamp) -> Snapshot {
+        Snapshot {
+            timestamp,
+            tab_infos: vec![SnapshotTabInfo {
+                id: 1,
+                title: "Tab".to_string(),
+                url: "https://example.com".to_string(),
+                pid: 100,
+                rss: 100_000_000,
+                audible: false,
+                foreground: false,
+                background_time_secs: 0.0,
+                cpu_usage: 0.0,
+                cpu_idle_time_secs: 0.0,
+                protected: false,
+                next_kill: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn points_and_kills_are_kept_by_the_wall_clock() {
+        let clock = Clock::simulated(START);
+        let mut history = History::new(config());
+        history.push(&snapshot(clock.now()), &[], &clock);
+
+        // Suspended for an hour, the clock of the daemon only went on by a second
+        clock.suspend(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(1));
+        let killed_tab = KilledTab {
+            timestamp: clock.now(),
+            tab_id: 1,
+            pid: 100,
+            title: "Tab".to_string(),
+            url: "https://example.com".to_string(),
+            rss: 100_000_000,
+            strategy: "background_time_limit",
+            reason: String::new(),
+        };
+        history.push(&snapshot(clock.now()), &[killed_tab], &clock);
+
+        let wall_now = START + 3_601_000.0;
+        let timestamps: Vec<Timestamp> = history
+            .global_points(START, wall_now)
+            .iter()
+            .map(|point| point.timestamp)
+            .collect();
+        assert_eq!(timestamps, [START, wall_now]);
+        assert_eq!(
+            history
+                .tab_history(100, START, wall_now)
+                .unwrap()
+                .points
+                .len(),
+            2
+        );
+        let killed_tabs = history.killed_tabs(wall_now - 1000.0, wall_now);
+        assert_eq!(killed_tabs.len(), 1);
+        assert_eq!(killed_tabs[0].timestamp, wall_now);
+    }
+
+    #[test]
+    fn older_points_are_averaged_per_interval() {
+        let config = config::History {
+            retention_secs: 3600.0,
+            full_resolution_secs: 10.0,
+            downsample_interval_secs: 5.0,
+        };
+        let clock = Clock::simulated(START);
+        let mut history = History::new(config);
+        // A point per second, rss grows by 1 MB each second
+        for secs in 0..30 {
+            clock.set(add_secs(START, secs as f64), add_secs(START, secs as f64));
+            let mut snapshot = snapshot(clock.now());
+            snapshot.tab_infos[0].rss = 100_000_000 + secs * 1_000_000;
+            history.push(&snapshot, &[], &clock);
+        }
+
+        let points = history.tab_history(100, START, clock.now()).unwrap().points;
+        // 0~14s are older than 10s, up to the last whole interval, averaged per 5s
+        let timestamps: Vec<f64> = points
+            .iter()
+            .map(|point| (point.timestamp - START) / 1000.0)
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                2.0, 7.0, 12.0, 15.0, 16.0, 17.0, 18.0, 19.0, 20.0, 21.0, 22.0, 23.0, 24.0, 25.0,
+                26.0, 27.0, 28.0, 29.0
+            ]
+        );
+        assert_eq!(points[0].rss, 102_000_000);
+        assert_eq!(points[2].rss, 112_000_000);
+        assert_eq!(points[3].rss, 115_000_000);
+        assert_eq!(history.global_points(START, clock.now()).len(), 18);
+    }
+
+    #[test]
+    fn points_and_kills_older_than_retention_are_dropped() {
+        let config = config::History {
+            retention_secs: 60.0,
+            full_resolution_secs: 600.0,
+            downsample_interval_secs: 60.0,
+        };
+        let clock = Clock::simulated(START);
+        let mut history = History::new(config);
+        let killed_tab = KilledTab {
+            timestamp: START,
+            tab_id: 1,
+            pid: 100,
+            title: "Tab".to_string(),
+            url: "https://example.com".to_string(),
+            rss: 100_000_000,
+            strategy: "requested",
+            reason: String::new(),
+        };
+        history.push(&snapshot(START), &[killed_tab], &clock);
+
+        let other_tab = |timestamp| {
+            let mut snapshot = snapshot(timestamp);
+            snapshot.tab_infos[0].pid = 200;
+            snapshot
+        };
+        clock.advance(Duration::from_secs(30));
+        history.push(&other_tab(clock.now()), &[], &clock);
+        assert_eq!(history.tab_histories(0.0, f64::INFINITY).len(), 2);
+
+        clock.advance(Duration::from_secs(31));
+        history.push(&other_tab(clock.now()), &[], &clock);
+        // Only the tab process with points left is kept
+        assert!(history.tab_history(100, 0.0, f64::INFINITY).is_none());
+        assert_eq!(
+            history
+                .tab_history(200, 0.0, f64::INFINITY)
+                .unwrap()
+                .points
+                .len(),
+            2
+        );
+        assert!(history.killed_tabs(0.0, f64::INFINITY).is_empty());
+        assert_eq!(history.global_points(0.0, f64::INFINITY).len(), 2);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::PROJECT_NAME;
+
+/// Where the running daemon is listening, written in the lock file for clients
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InstanceInfo {
+    pub pid: u32,
+    // `None` if not listening, e.g. http when its port is taken
+    pub websocket_addr: Option<String>,
+    pub http_addr: Option<String>,
+}
+
+/// Held for the lifetime of the daemon, the lock is released by the kernel when the process exits
+pub struct InstanceLock {
+    // Locked, never written
+    _file: File,
+    // Replaced as a whole, so readers never see it half written
+    info_path: PathBuf,
+}
+
+/// Why the lock is not acquired
+pub enum LockError {
+    AlreadyRunning(InstanceInfo),
+    Io(io::Error),
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+/// "$XDG_RUNTIME_DIR/tab-memory-manager.lock", temp dir if there is no runtime dir
+pub fn lock_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(format!("{PROJECT_NAME}.lock"))
+}
+
+/// The instance info next to the lock file, e.g. "tab-memory-manager.json"
+fn info_path(lock_path: &Path) -> PathBuf {
+    lock_path.with_extension("json")
+}
+
+impl InstanceLock {
+    /// Lock the file so only one daemon runs, the lock file is left in place on exit
+    pub fn acquire(lock_path: &Path) -> Result<Self, LockError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(lock_path)?;
+        if !try_lock(&file, libc::LOCK_EX)? {
+            return Err(LockError::AlreadyRunning(
+                read_instance_info(lock_path).unwrap_or_default(),
+            ));
+        }
+
+        let mut instance_lock = InstanceLock {
+            _file: file,
+            info_path: info_path(lock_path),
+        };
+        instance_lock.write_info(&InstanceInfo {
+            pid: std::process::id(),
+            ..Default::default()
+        })?;
+        Ok(instance_lock)
+    }
+
+    /// Publish the addresses once the listeners are bound
+    pub fn write_info(&mut self, instance_info: &InstanceInfo) -> io::Result<()> {
+        let temp_path = self.info_path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string(instance_info)?)?;
+        fs::rename(temp_path, &self.info_path)
+    }
+}
+
+/// The running daemon locking `lock_path`, `None` if it's not running
+pub fn running_instance(lock_path: &Path) -> io::Result<Option<InstanceInfo>> {
+    let file = match File::open(lock_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // The lock file of an exited daemon can be locked
+    if try_lock(&file, libc::LOCK_SH)? {
+        return Ok(None);
+    }
+    read_instance_info(lock_path).map(Some)
+}
+
+/// The info is written right after locked, it's only missing while the first daemon starts
+fn read_instance_info(lock_path: &Path) -> io::Result<InstanceInfo> {
+    let content = fs::read_to_string(info_path(lock_path))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Return false if someone else holds a conflicting lock
+fn try_lock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    // SAFETY: The fd is valid as long as the file is
+    let ret = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+impl InstanceInfo {
+    /// e.g. "ports 60000/60001"
+    pub fn describe_ports(&self) -> String {
+        let port = |addr: &Option<String>| {
+            addr.as_deref()
+                .and_then(|addr| addr.rsplit_once(':'))
+                .map(|(_, port)| port.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        };
+        format!(
+            "ports {}/{}",
+            port(&self.websocket_addr),
+            port(&self.http_addr)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_one_instance_locks_and_its_info_is_read_back() {
+        let dir = env::temp_dir().join(format!("instance-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lock_path = dir.join(format!("{PROJECT_NAME}.lock"));
+        assert!(running_instance(&lock_path).unwrap().is_none());
+
+        let mut instance_lock = InstanceLock::acquire(&lock_path).ok().unwrap();
+        let instance_info = InstanceInfo {
+            pid: std::process::id(),
+            websocket_addr: Some("127.0.0.1:60000".to_string()),
+            http_addr: None,
+        };
+        instance_lock.write_info(&instance_info).unwrap();
+        let running = running_instance(&lock_path).unwrap().unwrap();
+        assert_eq!(running.pid, instance_info.pid);
+        assert_eq!(running.websocket_addr, instance_info.websocket_addr);
+        assert_eq!(running.http_addr, None);
+
+        // Locks of another open file conflict, even in the same process
+        match InstanceLock::acquire(&lock_path) {
+            Err(LockError::AlreadyRunning(running)) => {
+                assert_eq!(running.describe_ports(), "ports 60000/-")
+            }
+            _ => panic!("Locked twice"),
+        }
+
+        drop(instance_lock);
+        assert!(running_instance(&lock_path).unwrap().is_none());
+        assert!(InstanceLock::acquire(&lock_path).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
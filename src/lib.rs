@@ -0,0 +1,26 @@
+//! The policy engine of tab-memory-manager, for tools embedding it instead of running the daemon.
+//!
+//! Tabs reported by the browser extension (`protocol`) are paired with browser processes in
+//! `status::Status`, published as a `snapshot::Snapshot`, and `evaluate` decides which tabs the
+//! strategies in `config::Config` kill.
+
+pub mod browser;
+pub mod cgroup;
+pub mod clock;
+pub mod config;
+pub mod control;
+mod process_tree;
+pub mod processes;
+pub mod protection;
+pub mod protocol;
+pub mod replay;
+pub mod snapshot;
+pub mod status;
+pub mod strategy;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timers;
+
+pub use strategy::{evaluate, KillDecision};
+
+pub const PROJECT_NAME: &str = "tab-memory-manager";
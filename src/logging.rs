@@ -0,0 +1,39 @@
+use std::{
+    env,
+    io::{self, IsTerminal},
+};
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Log, LogFormat};
+
+/// Overrides `log.filter` of config, e.g. "TAB_MEMORY_MANAGER_LOG=tab_killer=debug"
+pub const LOG_ENV: &str = "TAB_MEMORY_MANAGER_LOG";
+
+/// Install the global subscriber, logs are written to stderr because stdout may be native messaging
+pub fn init_logging(config: &Log) {
+    let filter = match env::var(LOG_ENV) {
+        Ok(filter) if !filter.trim().is_empty() => filter,
+        _ => config.filter.clone(),
+    };
+    let env_filter = match EnvFilter::try_new(&filter) {
+        Ok(env_filter) => env_filter,
+        Err(e) => {
+            eprintln!(
+                "Invalid log filter {:?}, fallback to \"info\": {}",
+                filter, e
+            );
+            EnvFilter::new("info")
+        }
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_writer(io::stderr)
+        // No color codes in journal or files
+        .with_ansi(io::stderr().is_terminal());
+    match config.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
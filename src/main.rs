@@ -1,47 +1,475 @@
-mod config;
+mod format;
+mod grafana;
+mod history;
+mod instance;
+mod logging;
+mod native_messaging;
+mod notification;
 mod output_tab_data_server;
-mod status;
+mod shutdown;
+mod systemd;
 mod tab_data_requester;
 mod tab_killer;
+mod token;
+mod top;
 
-use std::sync::{mpsc::sync_channel, Arc, Mutex};
+use std::{
+    net::TcpListener,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, Mutex, RwLock},
+};
 
-use config::read_or_create_new_config;
-use output_tab_data_server::spawn_output_tab_data_server;
-use status::Status;
-use tab_data_requester::spawn_tab_data_requester;
+// Modules of the daemon refer to the library as `crate::<module>`
+use tab_memory_manager::{
+    cgroup, clock, config, control, processes, protocol, replay, snapshot, status, strategy,
+    timers, PROJECT_NAME,
+};
+// Only tests of the daemon need process classes and fakes
+#[cfg(test)]
+use tab_memory_manager::{browser, protection, testing};
+
+use clock::Clock;
+use config::{read_config, read_or_create_new_config};
+use control::SharedControl;
+use history::{History, SharedHistory};
+use instance::{running_instance, InstanceInfo, InstanceLock, LockError};
+use logging::init_logging;
+use native_messaging::{
+    install_native_messaging_host, relay_native_messages, take_native_messaging_stdout,
+    NATIVE_MESSAGING_ORIGIN_PREFIX,
+};
+use output_tab_data_server::{spawn_output_tab_data_server, OUTPUT_TAB_DATA_ADDR};
+use replay::{replay, Recorder};
+use shutdown::Shutdown;
+use snapshot::SharedSnapshot;
+use status::Connections;
+use systemd::{install_systemd_unit, HTTP_SOCKET_NAME, WEBSOCKET_SOCKET_NAME};
+use tab_data_requester::{spawn_tab_data_requester, WEBSOCKET_ADDR};
 use tab_killer::spawn_tab_killer_thread;
+use thousands::Separable;
+use token::{read_or_create_token, token_path};
+use top::run_top;
+use tracing::{error, info, warn};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("install-native-messaging-host") {
+        return install_native_messaging_host_command(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("install-systemd-unit") {
+        return install_systemd_unit_command(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("status") {
+        return status_command();
+    }
+    if args.first().map(String::as_str) == Some("top") {
+        return top_command(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("replay") {
+        return replay_command(&args[1..]);
+    }
 
-const PROJECT_NAME: &str = "tab-memory-manager";
+    // Launched by browser extension as native messaging host, it relays to the running instance
+    if let Some(origin) = args
+        .first()
+        .filter(|origin| origin.starts_with(NATIVE_MESSAGING_ORIGIN_PREFIX))
+    {
+        return native_messaging_command(origin);
+    }
+
+    // Before touching the config or ports, which belong to the running instance
+    let mut instance_lock = match InstanceLock::acquire(&instance::lock_path()) {
+        Ok(instance_lock) => instance_lock,
+        Err(LockError::AlreadyRunning(instance_info)) => {
+            eprintln!(
+                "{PROJECT_NAME} is already running as pid {} on {}",
+                instance_info.pid,
+                instance_info.describe_ports()
+            );
+            return ExitCode::FAILURE;
+        }
+        Err(LockError::Io(e)) => {
+            eprintln!("Failed to lock {:?}: {}", instance::lock_path(), e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-fn main() {
     let config = read_or_create_new_config();
+    init_logging(&config.log);
+
+    // `--record <file>`, tab data and process samples for the `replay` subcommand
+    let recorder = match args.iter().position(|arg| arg == "--record") {
+        Some(index) => {
+            let Some(record_path) = args.get(index + 1).map(PathBuf::from) else {
+                eprintln!("Usage: {PROJECT_NAME} --record <file>");
+                return ExitCode::FAILURE;
+            };
+            match Recorder::create(&record_path) {
+                Ok(recorder) => {
+                    info!(target: "main", "Record to {:?}", record_path);
+                    Some(recorder)
+                }
+                Err(e) => {
+                    error!(target: "main", "Failed to create {:?}: {}", record_path, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => None,
+    };
+
+    // Sockets passed by systemd socket activation, the rest are bound here
+    let mut listeners = systemd::take_listeners();
+    let websocket_listener =
+        match systemd::listen(&mut listeners, WEBSOCKET_SOCKET_NAME, WEBSOCKET_ADDR) {
+            Ok(websocket_listener) => websocket_listener,
+            Err(e) => {
+                error!(target: "main", "Failed to listen on ws://{WEBSOCKET_ADDR}: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+    // Tab data output is optional, e.g. the port is taken by another program
+    let http_listener = match systemd::listen(
+        &mut listeners,
+        HTTP_SOCKET_NAME,
+        OUTPUT_TAB_DATA_ADDR,
+    ) {
+        Ok(http_listener) => Some(http_listener),
+        Err(e) => {
+            warn!(
+                target: "main",
+                "Failed to listen on http://{OUTPUT_TAB_DATA_ADDR}, tab data output is disabled: {}",
+                e
+            );
+            None
+        }
+    };
+
+    // For clients to find the listening addresses
+    let local_addr =
+        |listener: &TcpListener| listener.local_addr().ok().map(|addr| addr.to_string());
+    if let Err(e) = instance_lock.write_info(&InstanceInfo {
+        pid: std::process::id(),
+        websocket_addr: local_addr(&websocket_listener),
+        http_addr: http_listener.as_ref().and_then(local_addr),
+    }) {
+        warn!(target: "main", "Failed to write the instance info: {}", e);
+    }
+
+    // Every thread stops when a signal arrives or any of them ends
+    let shutdown = match Shutdown::new() {
+        Ok(shutdown) => Arc::new(shutdown),
+        Err(e) => {
+            error!(target: "main", "Failed to register signal handlers: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Extension sends it to connect the websocket, clients of the control api send it too
+    let token = match read_or_create_token(&token_path()) {
+        Ok(token) => token,
+        Err(e) => {
+            error!(target: "main", "Cannot read or create token {:?}: {}", token_path(), e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    // Sharing tab information between threads
-    let status = Arc::new(Mutex::new(Status::default()));
-    // Request update status from browser extension if possible
-    let (update_request_tx, update_request_rx) = sync_channel::<()>(1);
-    let (update_result_tx, update_result_rx) = sync_channel::<Result<(), String>>(1);
+    // Time of tab timers, steady when the wall clock jumps
+    let clock = Clock::system();
+    // Tabs from extension, written by the connection handlers and copied by the tab killer
+    let connections = Arc::new(Mutex::new(Connections::new(
+        recorder.clone(),
+        clock.clone(),
+    )));
+    // Published by the tab killer after each tick, for readers
+    let snapshot = SharedSnapshot::default();
+    // Time series of tab metrics and kills, for time range queries
+    let history: SharedHistory = Arc::new(RwLock::new(History::new(config.history)));
+    // Pause and protections from the control api, applied by the tab killer
+    let control = SharedControl::default();
 
-    // Waiting for json data and update tab_infos, bind on ws://127.0.0.1:60000
+    // Waiting for json data and update tab_infos, on ws://127.0.0.1:60000, native messaging
+    // hosts relay to it too
     let tab_data_requester = spawn_tab_data_requester(
-        Arc::clone(&status),
+        Arc::clone(&connections),
         config.clone(),
-        update_request_rx,
-        update_result_tx,
-        config.browser_name.clone(),
+        token.clone(),
+        websocket_listener,
+        Arc::clone(&shutdown),
     );
 
     // Terminate tab by given strategy
-    let _tab_killer = spawn_tab_killer_thread(
-        Arc::clone(&status),
-        config,
-        update_request_tx,
-        update_result_rx,
+    let tab_killer = spawn_tab_killer_thread(
+        Arc::clone(&connections),
+        Arc::clone(&snapshot),
+        Arc::clone(&history),
+        Arc::clone(&control),
+        clock.clone(),
+        recorder,
+        config.clone(),
+        Arc::clone(&shutdown),
     );
 
-    // Sharing vec of MiniTabInfo in json format, bind on http://127.0.0.1:60001
-    let _mini_tab_data_server = spawn_output_tab_data_server(Arc::clone(&status));
+    // Sharing snapshots, history and the control api in json format, on http://127.0.0.1:60001
+    let output_tab_data_server = http_listener.map(|http_listener| {
+        spawn_output_tab_data_server(
+            Arc::clone(&snapshot),
+            Arc::clone(&history),
+            Arc::clone(&control),
+            clock,
+            config,
+            token,
+            http_listener,
+            Arc::clone(&shutdown),
+        )
+    });
+
+    // Both listeners are bound
+    let notify_socket = systemd::notify_socket();
+    systemd::notify(notify_socket.as_deref(), "READY=1");
+
+    shutdown.wait();
+    systemd::notify(notify_socket.as_deref(), "STOPPING=1");
+    let mut exit_code = ExitCode::SUCCESS;
+    let threads = [
+        ("tab data requester", Some(tab_data_requester)),
+        ("tab killer", Some(tab_killer)),
+        ("output tab data server", output_tab_data_server),
+    ];
+    for (name, thread) in threads
+        .into_iter()
+        .filter_map(|(name, thread)| Some((name, thread?)))
+    {
+        if thread.join().is_err() {
+            error!(target: "main", "The {name} thread panicked");
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+    drop(instance_lock);
+    exit_code
+}
+
+/// `chrome-extension://<id>/`, the browser launched this process as native messaging host, relay
+/// the extension to the running instance through stdin/stdout
+fn native_messaging_command(origin: &str) -> ExitCode {
+    // Stdout is reserved for messages, anything else printed goes to stderr
+    let native_messaging_stdout = match take_native_messaging_stdout() {
+        Ok(native_messaging_stdout) => native_messaging_stdout,
+        Err(e) => {
+            eprintln!("Failed to take stdout for native messaging: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = read_or_create_new_config();
+    init_logging(&config.log);
+
+    let shutdown = match Shutdown::new() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            error!(target: "main", "Failed to register signal handlers: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match relay_native_messages(origin, native_messaging_stdout, &shutdown) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!(target: "native_messaging", "Failed to relay to {PROJECT_NAME}: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `status`, where the running instance is listening
+fn status_command() -> ExitCode {
+    match running_instance(&instance::lock_path()) {
+        Ok(Some(instance_info)) => {
+            println!("{PROJECT_NAME} is running as pid {}", instance_info.pid);
+            if let Some(websocket_addr) = instance_info.websocket_addr {
+                println!("Websocket: ws://{websocket_addr}");
+            }
+            if let Some(http_addr) = instance_info.http_addr {
+                println!("Tab data: http://{http_addr}");
+            }
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            println!("{PROJECT_NAME} is not running");
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("Failed to read {:?}: {}", instance::lock_path(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `top [--addr <host:port>]`, the address of the running instance by default
+fn top_command(args: &[String]) -> ExitCode {
+    let mut http_addr = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => http_addr = args.next().cloned(),
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                eprintln!("Usage: {PROJECT_NAME} top [--addr <host:port>]");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let http_addr = http_addr.unwrap_or_else(|| {
+        running_instance(&instance::lock_path())
+            .ok()
+            .flatten()
+            .and_then(|instance_info| instance_info.http_addr)
+            .unwrap_or_else(|| OUTPUT_TAB_DATA_ADDR.to_string())
+    });
 
-    tab_data_requester.join().unwrap();
+    match run_top(http_addr) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to run top: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `replay <file> [--config <path>]`, the user config by default
+fn replay_command(args: &[String]) -> ExitCode {
+    let mut record_path = None;
+    let mut config_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = args.next().map(PathBuf::from),
+            _ if record_path.is_none() && !arg.starts_with("--") => {
+                record_path = Some(PathBuf::from(arg))
+            }
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                eprintln!("Usage: {PROJECT_NAME} replay <file> [--config <path>]");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(record_path) = record_path else {
+        eprintln!("Usage: {PROJECT_NAME} replay <file> [--config <path>]");
+        return ExitCode::FAILURE;
+    };
+    let config = match config_path {
+        Some(config_path) => match read_config(&config_path) {
+            Some(config) => config,
+            None => return ExitCode::FAILURE,
+        },
+        None => read_or_create_new_config(),
+    };
+
+    match replay(&record_path, &config) {
+        Ok(replayed) => {
+            for kill in &replayed.kills {
+                println!(
+                    "+{:.1}s kill tab {} (pid {}, rss {}) {:?} by {}: {}",
+                    kill.offset_secs,
+                    kill.decision.tab_id,
+                    kill.decision.pid,
+                    kill.rss.separate_with_commas(),
+                    kill.title,
+                    kill.decision.reason.strategy(),
+                    kill.decision.reason
+                );
+            }
+            println!(
+                "Replayed {} ticks over {:.1}s, {} tabs killed",
+                replayed.tick_count,
+                replayed.duration_secs,
+                replayed.kills.len()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to replay {:?}: {}", record_path, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `install-native-messaging-host --extension-id <id> [--target-dir <dir>]`
+fn install_native_messaging_host_command(args: &[String]) -> ExitCode {
+    let mut extension_id = None;
+    let mut target_dir = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--extension-id" => extension_id = args.next().cloned(),
+            "--target-dir" => target_dir = args.next().map(PathBuf::from),
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(extension_id) = extension_id else {
+        eprintln!("Usage: {PROJECT_NAME} install-native-messaging-host --extension-id <id> [--target-dir <dir>]");
+        return ExitCode::FAILURE;
+    };
+
+    let config = read_or_create_new_config();
+    match install_native_messaging_host(&config, &extension_id, target_dir) {
+        Ok(manifest_path) => {
+            println!(
+                "Installed native messaging host manifest {:?}",
+                manifest_path
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to install native messaging host manifest: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// `install-systemd-unit [--target-dir <dir>] [--socket-activation]`
+fn install_systemd_unit_command(args: &[String]) -> ExitCode {
+    let mut target_dir = None;
+    let mut socket_activation = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target-dir" => target_dir = args.next().map(PathBuf::from),
+            "--socket-activation" => socket_activation = true,
+            _ => {
+                eprintln!("Unknown argument: {arg}");
+                eprintln!("Usage: {PROJECT_NAME} install-systemd-unit [--target-dir <dir>] [--socket-activation]");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let config = read_or_create_new_config();
+    match install_systemd_unit(
+        &config,
+        target_dir,
+        socket_activation,
+        WEBSOCKET_ADDR,
+        OUTPUT_TAB_DATA_ADDR,
+    ) {
+        Ok(unit_paths) => {
+            for unit_path in unit_paths {
+                println!("Installed systemd unit {:?}", unit_path);
+            }
+            let unit = if socket_activation {
+                "socket"
+            } else {
+                "service"
+            };
+            println!("Enable it with: systemctl --user daemon-reload && systemctl --user enable --now {PROJECT_NAME}.{unit}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to install systemd unit: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
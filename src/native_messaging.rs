@@ -0,0 +1,306 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::TcpStream,
+    os::fd::FromRawFd,
+    path::PathBuf,
+    sync::mpsc::{self, TryRecvError},
+    thread::spawn,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{info, trace, warn};
+use tungstenite::{client::IntoClientRequest, http::HeaderValue, Message, WebSocket};
+
+use crate::{
+    config::Config,
+    instance::{lock_path, running_instance},
+    shutdown::Shutdown,
+    tab_data_requester::WEBSOCKET_ADDR,
+    token::{read_or_create_token, token_path},
+    PROJECT_NAME,
+};
+
+/// Chromium passes the caller origin as the first argument when launching a native messaging host
+pub const NATIVE_MESSAGING_ORIGIN_PREFIX: &str = "chrome-extension://";
+
+/// Chromium sends messages up to 64 MiB to the host, a longer length prefix is corrupt
+const MAX_NATIVE_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// How long the relay waits for the daemon before it checks for messages from the extension
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Host names only allow lowercase alphanumeric, underscores and dots
+fn native_messaging_host_name() -> String {
+    PROJECT_NAME.replace('-', "_")
+}
+
+#[derive(Debug, Serialize)]
+struct NativeMessagingHostManifest {
+    name: String,
+    description: String,
+    path: PathBuf,
+    #[serde(rename = "type")]
+    host_type: String,
+    allowed_origins: Vec<String>,
+}
+
+/// Take stdout for the native messaging protocol, anything printed to stdout afterward goes to stderr
+pub fn take_native_messaging_stdout() -> io::Result<File> {
+    // SAFETY: Only duplicating standard file descriptors, the new fd is owned by the returned File
+    unsafe {
+        let stdout_fd = libc::dup(libc::STDOUT_FILENO);
+        if stdout_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(File::from_raw_fd(stdout_fd))
+    }
+}
+
+/// Relay messages between the extension on stdin/stdout and the websocket of the running daemon,
+/// until either side disconnects. The browser launches a host per profile, they all feed one daemon
+pub fn relay_native_messages(
+    origin: &str,
+    native_messaging_stdout: File,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    // Systemd holds the default address with socket activation, the daemon starts on connect
+    let websocket_addr = running_instance(&lock_path())?
+        .and_then(|instance_info| instance_info.websocket_addr)
+        .unwrap_or_else(|| WEBSOCKET_ADDR.to_string());
+    let token = read_or_create_token(&token_path())?;
+    let websocket = connect_daemon(&websocket_addr, origin, &token)?;
+    info!(target: "native_messaging", "Relay to ws://{}", websocket_addr);
+    relay(io::stdin(), native_messaging_stdout, websocket, shutdown)
+}
+
+/// Connect as the extension would, with its origin and the token
+fn connect_daemon(
+    websocket_addr: &str,
+    origin: &str,
+    token: &str,
+) -> io::Result<WebSocket<TcpStream>> {
+    let stream = TcpStream::connect(websocket_addr)?;
+    let mut request = format!("ws://{websocket_addr}/")
+        .into_client_request()
+        .map_err(io::Error::other)?;
+    let headers = request.headers_mut();
+    headers.insert(
+        "Origin",
+        HeaderValue::from_str(origin).map_err(io::Error::other)?,
+    );
+    headers.insert(
+        "Sec-WebSocket-Protocol",
+        HeaderValue::from_str(token).map_err(io::Error::other)?,
+    );
+    let (websocket, _) = tungstenite::client(request, stream)
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e.to_string()))?;
+    // Messages from the extension are sent between reads
+    websocket
+        .get_ref()
+        .set_read_timeout(Some(RELAY_POLL_INTERVAL))?;
+    Ok(websocket)
+}
+
+fn relay(
+    stdin: impl Read + Send + 'static,
+    mut stdout: impl Write,
+    mut websocket: WebSocket<TcpStream>,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    // Reading stdin blocks, messages are passed to the loop reading the websocket
+    let (sender, receiver) = mpsc::channel();
+    spawn(move || {
+        let mut stdin = stdin;
+        loop {
+            let msg = read_native_message(&mut stdin);
+            let last = !matches!(msg, Ok(Some(_)));
+            if sender.send(msg).is_err() || last {
+                break;
+            }
+        }
+    });
+
+    while !shutdown.is_requested() {
+        loop {
+            match receiver.try_recv() {
+                Ok(Ok(Some(msg))) => {
+                    trace!(target: "native_messaging", "Recieved a native message!");
+                    websocket
+                        .send(Message::text(msg))
+                        .map_err(io::Error::other)?;
+                }
+                Ok(Ok(None)) | Err(TryRecvError::Disconnected) => {
+                    info!(target: "native_messaging", "Browser extension disconnected, quitting");
+                    let _ = websocket.close(None);
+                    let _ = websocket.flush();
+                    return Ok(());
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        match websocket.read() {
+            Ok(Message::Text(msg)) => write_native_message(&mut stdout, msg.as_str())?,
+            // Ping, pong and close are answered by tungstenite
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                warn!(target: "native_messaging", "Daemon closed the connection, quitting");
+                return Ok(());
+            }
+            Err(e) => return Err(io::Error::other(e)),
+        }
+    }
+    let _ = websocket.close(None);
+    let _ = websocket.flush();
+    Ok(())
+}
+
+/// Read a message prefixed with 32-bit length in native byte order, `None` if stdin is closed
+fn read_native_message(reader: &mut impl Read) -> io::Result<Option<String>> {
+    let mut len_bytes = [0u8; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_ne_bytes(len_bytes) as usize;
+    if len > MAX_NATIVE_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message length {len} exceeds {MAX_NATIVE_MESSAGE_LEN}"),
+        ));
+    }
+
+    let mut msg = vec![0u8; len];
+    reader.read_exact(&mut msg)?;
+    String::from_utf8(msg)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_native_message(writer: &mut impl Write, msg: &str) -> io::Result<()> {
+    let len =
+        u32::try_from(msg.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    writer.write_all(&len.to_ne_bytes())?;
+    writer.write_all(msg.as_bytes())?;
+    writer.flush()
+}
+
+/// Write the host manifest so the extension can launch this executable, return the manifest path
+pub fn install_native_messaging_host(
+    config: &Config,
+    extension_id: &str,
+    target_dir: Option<PathBuf>,
+) -> io::Result<PathBuf> {
+    let target_dir = match target_dir {
+        Some(target_dir) => target_dir,
+        // e.g. "~/.config/chromium/NativeMessagingHosts"
+        None => dirs::config_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config dir"))?
+            .join(&config.browser_name)
+            .join("NativeMessagingHosts"),
+    };
+    fs::create_dir_all(&target_dir)?;
+
+    let manifest = NativeMessagingHostManifest {
+        name: native_messaging_host_name(),
+        description: format!("{PROJECT_NAME} receive tab data from extension tab-infos"),
+        path: std::env::current_exe()?,
+        host_type: "stdio".to_string(),
+        allowed_origins: vec![format!("{NATIVE_MESSAGING_ORIGIN_PREFIX}{extension_id}/")],
+    };
+    let manifest_path = target_dir.join(format!("{}.json", manifest.name));
+    let json = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(&manifest_path, json)?;
+
+    Ok(manifest_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, os::unix::net::UnixStream, sync::Arc};
+
+    use tungstenite::handshake::server::{Request, Response};
+
+    use super::*;
+
+    #[test]
+    fn messages_longer_than_the_limit_are_rejected() {
+        let mut reader = io::Cursor::new(u32::MAX.to_ne_bytes());
+        let e = read_native_message(&mut reader).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = Vec::new();
+        write_native_message(&mut buf, "{}").unwrap();
+        let mut reader = io::Cursor::new(buf);
+        assert_eq!(
+            read_native_message(&mut reader).unwrap().as_deref(),
+            Some("{}")
+        );
+        assert_eq!(read_native_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    // The handshake callback signature is defined by tungstenite
+    #[allow(clippy::result_large_err)]
+    fn messages_are_relayed_between_the_extension_and_the_daemon() {
+        // The daemon answers each message, and tells what it received once the relay disconnects
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let websocket_addr = listener.local_addr().unwrap().to_string();
+        let daemon = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket =
+                tungstenite::accept_hdr(stream, |req: &Request, mut res: Response| {
+                    assert_eq!(req.headers()["Origin"], "chrome-extension://abc/");
+                    let protocol = req.headers()["Sec-WebSocket-Protocol"].clone();
+                    res.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+                    Ok(res)
+                })
+                .unwrap();
+            let mut received = Vec::new();
+            while let Ok(msg) = websocket.read() {
+                if let Message::Text(msg) = msg {
+                    websocket
+                        .send(Message::text(format!("reply to {}", msg.as_str())))
+                        .unwrap();
+                    received.push(msg.as_str().to_string());
+                }
+            }
+            received
+        });
+
+        let websocket =
+            connect_daemon(&websocket_addr, "chrome-extension://abc/", "token").unwrap();
+        let (mut extension_stdin, stdin) = UnixStream::pair().unwrap();
+        let (mut extension_stdout, stdout) = UnixStream::pair().unwrap();
+        let shutdown = Arc::new(Shutdown::new().unwrap());
+        let relay = {
+            let shutdown = Arc::clone(&shutdown);
+            spawn(move || relay(stdin, stdout, websocket, &shutdown))
+        };
+
+        write_native_message(&mut extension_stdin, r#"{"type":"hello"}"#).unwrap();
+        assert_eq!(
+            read_native_message(&mut extension_stdout)
+                .unwrap()
+                .as_deref(),
+            Some(r#"reply to {"type":"hello"}"#)
+        );
+        // The browser closes stdin when the extension disconnects
+        drop(extension_stdin);
+        relay.join().unwrap().unwrap();
+        assert_eq!(daemon.join().unwrap(), vec![r#"{"type":"hello"}"#]);
+    }
+}
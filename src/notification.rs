@@ -0,0 +1,385 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread::spawn,
+};
+
+use futures_lite::{future, StreamExt};
+use tracing::warn;
+use zbus::{blocking::Connection, message::Type, zvariant::Value, MatchRule, MessageStream};
+
+use crate::{format::format_bytes, history::KilledTab, status::Rss, PROJECT_NAME};
+
+const NOTIFICATIONS_DESTINATION: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFICATIONS_INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// Action key of the "Keep this tab" button
+const KEEP_ACTION: &str = "keep";
+
+/// A notification for the notify thread to show
+struct Notification {
+    summary: String,
+    body: String,
+    // Shown with a "Keep this tab" button for the tab process, its notification id is sent back
+    keep_pid: Option<u32>,
+}
+
+/// Desktop notifications over D-Bus (`org.freedesktop.Notifications`) of the session bus
+pub struct Notifier {
+    // "Notify" blocks until the notification server replies, it's called on the notify thread
+    notifications: Sender<Notification>,
+    // Notification ids of warnings with their tab process, once shown
+    warning_ids: Receiver<(u32, u32)>,
+    // "ActionInvoked" signals, polled without blocking on each tick
+    action_invoked: MessageStream,
+    // Notification id of the advance warning shown for each tab, by pid of the tab process,
+    // `None` until shown
+    warnings: HashMap<u32, Option<u32>>,
+}
+
+impl Notifier {
+    /// Connect to the session bus, "DBUS_SESSION_BUS_ADDRESS" decides which
+    pub fn new() -> zbus::Result<Self> {
+        Self::with_connection(Connection::session()?)
+    }
+
+    /// Notify through the connection, the notify thread stops once the notifier is dropped
+    fn with_connection(connection: Connection) -> zbus::Result<Self> {
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .path(NOTIFICATIONS_PATH)?
+            .interface(NOTIFICATIONS_INTERFACE)?
+            .member("ActionInvoked")?
+            .build();
+        let action_invoked = future::block_on(MessageStream::for_match_rule(
+            rule,
+            connection.inner(),
+            None,
+        ))?;
+        let (notifications, notification_receiver) = channel();
+        let (warning_id_sender, warning_ids) = channel();
+        spawn(move || notify_until_dropped(&connection, notification_receiver, warning_id_sender));
+        Ok(Notifier {
+            notifications,
+            warning_ids,
+            action_invoked,
+            warnings: HashMap::new(),
+        })
+    }
+
+    /// Summarise tabs killed in a tick, e.g. "Closed 3 tabs to free 1.2 GB"
+    pub fn notify_killed_tabs(&mut self, killed_tabs: &[KilledTab]) {
+        if killed_tabs.is_empty() {
+            return;
+        }
+        for killed_tab in killed_tabs {
+            self.warnings.remove(&killed_tab.pid);
+        }
+
+        let freed_rss: Rss = killed_tabs.iter().map(|killed_tab| killed_tab.rss).sum();
+        let summary = format!(
+            "Closed {} {} to free {}",
+            killed_tabs.len(),
+            if killed_tabs.len() == 1 {
+                "tab"
+            } else {
+                "tabs"
+            },
+            format_bytes(freed_rss)
+        );
+        let body = killed_tabs
+            .iter()
+            .map(|killed_tab| escape_markup(&killed_tab.title))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.notify(summary, body, None);
+    }
+
+    /// Warn once that the tab is going to be killed, with a "Keep this tab" button
+    pub fn warn_before_kill(&mut self, pid: u32, title: &str, secs_left: f64) {
+        if self.warnings.contains_key(&pid) {
+            return;
+        }
+        let summary = format!("Closing a background tab in {:.0}s", secs_left.max(0.0));
+        self.notify(summary, escape_markup(title), Some(pid));
+        self.warnings.insert(pid, None);
+    }
+
+    /// Forget warnings of tabs which are no longer going to be killed, e.g. brought to foreground
+    pub fn retain_warnings(&mut self, mut should_keep: impl FnMut(u32) -> bool) {
+        self.warnings.retain(|&pid, _| should_keep(pid));
+    }
+
+    /// Pids of tabs that user clicked "Keep this tab" for since last call
+    pub fn take_kept_tabs(&mut self) -> Vec<u32> {
+        for (pid, notification_id) in self.warning_ids.try_iter() {
+            // Forgotten in the meantime, e.g. the tab was killed
+            if let Some(warning) = self.warnings.get_mut(&pid) {
+                *warning = Some(notification_id);
+            }
+        }
+
+        let mut kept_tabs = Vec::new();
+        // Only signals already received, don't wait for more
+        while let Some(Some(message)) =
+            future::block_on(future::poll_once(self.action_invoked.next()))
+        {
+            let Ok(message) = message else {
+                continue;
+            };
+            let Ok((notification_id, action_key)) = message.body().deserialize::<(u32, String)>()
+            else {
+                continue;
+            };
+            if action_key != KEEP_ACTION {
+                continue;
+            }
+            let kept_tab = self
+                .warnings
+                .iter()
+                .find(|(_, &id)| id == Some(notification_id))
+                .map(|(&pid, _)| pid);
+            if let Some(pid) = kept_tab {
+                self.warnings.remove(&pid);
+                kept_tabs.push(pid);
+            }
+        }
+        kept_tabs
+    }
+
+    /// Queue the notification for the notify thread, without waiting for it to be shown
+    fn notify(&self, summary: String, body: String, keep_pid: Option<u32>) {
+        let notification = Notification {
+            summary,
+            body,
+            keep_pid,
+        };
+        if self.notifications.send(notification).is_err() {
+            warn!(target: "notification", "Notify thread is gone, drop the notification");
+        }
+    }
+}
+
+/// Show notifications in the order they are queued, send back notification ids of warnings
+fn notify_until_dropped(
+    connection: &Connection,
+    notifications: Receiver<Notification>,
+    warning_ids: Sender<(u32, u32)>,
+) {
+    for notification in notifications {
+        let actions: &[&str] = match notification.keep_pid {
+            Some(_) => &[KEEP_ACTION, "Keep this tab"],
+            None => &[],
+        };
+        match call_notify(
+            connection,
+            &notification.summary,
+            &notification.body,
+            actions,
+        ) {
+            Ok(notification_id) => {
+                if let Some(pid) = notification.keep_pid {
+                    let _ = warning_ids.send((pid, notification_id));
+                }
+            }
+            Err(e) => warn!(
+                target: "notification",
+                "Failed to notify {:?}: {}",
+                notification.summary,
+                e
+            ),
+        }
+    }
+}
+
+/// Call `Notify`, return the notification id
+fn call_notify(
+    connection: &Connection,
+    summary: &str,
+    body: &str,
+    actions: &[&str],
+) -> zbus::Result<u32> {
+    let hints: HashMap<&str, Value> = HashMap::new();
+    // Server default expire timeout
+    let expire_timeout = -1i32;
+    let reply = connection.call_method(
+        Some(NOTIFICATIONS_DESTINATION),
+        NOTIFICATIONS_PATH,
+        Some(NOTIFICATIONS_INTERFACE),
+        "Notify",
+        &(
+            PROJECT_NAME,
+            0u32,
+            "",
+            summary,
+            body,
+            actions,
+            hints,
+            expire_timeout,
+        ),
+    )?;
+    reply.body().deserialize::<u32>()
+}
+
+/// Bodies may be shown as markup, titles are text
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::{Arc, Mutex},
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    use zbus::{blocking::connection::Builder, zvariant::OwnedValue};
+
+    use super::*;
+
+    /// A session bus of its own, killed when dropped
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                // e.g. it fails to raise the fd limit in containers
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            PrivateBus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+
+        fn connect(&self) -> Builder<'_> {
+            Builder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Summary, body and actions of each notification
+    type Shown = Arc<Mutex<Vec<(String, String, Vec<String>)>>>;
+
+    struct FakeNotifications {
+        shown: Shown,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl FakeNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: &str,
+            _replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: Vec<String>,
+            _hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let mut shown = self.shown.lock().unwrap();
+            shown.push((summary.to_string(), body.to_string(), actions));
+            shown.len() as u32
+        }
+    }
+
+    fn wait_until<T>(mut poll: impl FnMut() -> Option<T>) -> T {
+        let start = Instant::now();
+        loop {
+            if let Some(value) = poll() {
+                return value;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn warnings_are_kept_and_killed_tabs_are_summarised() {
+        let bus = PrivateBus::start();
+        let shown = Shown::default();
+        let server = bus
+            .connect()
+            .name(NOTIFICATIONS_DESTINATION)
+            .unwrap()
+            .serve_at(
+                NOTIFICATIONS_PATH,
+                FakeNotifications {
+                    shown: Arc::clone(&shown),
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut notifier = Notifier::with_connection(bus.connect().build().unwrap()).unwrap();
+
+        notifier.warn_before_kill(21, "<b>Slides</b> & notes", 12.4);
+        // Warned once until forgotten
+        notifier.warn_before_kill(21, "<b>Slides</b> & notes", 11.4);
+        wait_until(|| (shown.lock().unwrap().len() == 1).then_some(()));
+        assert_eq!(
+            shown.lock().unwrap()[0],
+            (
+                "Closing a background tab in 12s".to_string(),
+                "&lt;b&gt;Slides&lt;/b&gt; &amp; notes".to_string(),
+                vec![KEEP_ACTION.to_string(), "Keep this tab".to_string()]
+            )
+        );
+
+        server
+            .emit_signal(
+                None::<()>,
+                NOTIFICATIONS_PATH,
+                NOTIFICATIONS_INTERFACE,
+                "ActionInvoked",
+                &(1u32, KEEP_ACTION),
+            )
+            .unwrap();
+        let kept_tabs = wait_until(|| Some(notifier.take_kept_tabs()).filter(|t| !t.is_empty()));
+        assert_eq!(kept_tabs, [21]);
+
+        let killed_tab = |title: &str| KilledTab {
+            timestamp: 0.0,
+            tab_id: 1,
+            pid: 31,
+            title: title.to_string(),
+            url: String::new(),
+            rss: 1_500_000_000,
+            strategy: "requested",
+            reason: String::new(),
+        };
+        notifier.notify_killed_tabs(&[killed_tab("a < b"), killed_tab("Mail")]);
+        wait_until(|| (shown.lock().unwrap().len() == 2).then_some(()));
+        let (summary, body, actions) = shown.lock().unwrap()[1].clone();
+        assert_eq!(
+            summary,
+            format!("Closed 2 tabs to free {}", format_bytes(3_000_000_000))
+        );
+        assert_eq!(body, "a &lt; b\nMail");
+        assert!(actions.is_empty());
+    }
+}
@@ -1,90 +1,410 @@
 use std::{
-    net::ToSocketAddrs,
-    sync::{Arc, Mutex},
+    io::Cursor,
+    net::TcpListener,
+    sync::Arc,
     thread::{spawn, JoinHandle},
 };
 
-use astra::{Body, Response, Server};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tracing::{info, warn};
 
-use crate::Status;
+use crate::{
+    clock::{add_secs, Clock},
+    config::Config,
+    control::SharedControl,
+    grafana::{self, AnnotationRequest, QueryRequest},
+    history::SharedHistory,
+    protocol::Timestamp,
+    shutdown::Shutdown,
+    snapshot::SharedSnapshot,
+    token::token_matches,
+};
+
+pub const OUTPUT_TAB_DATA_ADDR: &str = "127.0.0.1:60001";
+
+type JsonResponse = Response<Cursor<Vec<u8>>>;
+
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_output_tab_data_server(
+    snapshot: SharedSnapshot,
+    history: SharedHistory,
+    control: SharedControl,
+    clock: Clock,
+    config: Config,
+    token: String,
+    listener: TcpListener,
+    shutdown: Arc<Shutdown>,
+) -> JoinHandle<()> {
+    spawn(move || {
+        let _shutdown_guard = shutdown.guard();
+        let server = Arc::new(Server::from_listener(listener, None).unwrap());
+        let stopping_server = Arc::clone(&server);
+        shutdown.on_request(move || stopping_server.unblock());
+        serve_output_tab_data(
+            &server, &snapshot, &history, &control, &clock, &config, &token,
+        );
+    })
+}
+
+/// Serve until the server is unblocked
+fn serve_output_tab_data(
+    server: &Server,
+    snapshot: &SharedSnapshot,
+    history: &SharedHistory,
+    control: &SharedControl,
+    clock: &Clock,
+    config: &Config,
+    token: &str,
+) {
+    for mut request in server.incoming_requests() {
+        let response = route(
+            &mut request,
+            snapshot,
+            history,
+            control,
+            clock,
+            config,
+            token,
+        );
+        if let Err(e) = request.respond(response) {
+            warn!(target: "output_tab_data_server", "Failed to respond: {}", e);
+        }
+    }
+}
+
+/// `GET /` the latest snapshot, `/api/*` tab data and history, the Grafana json datasource api
+/// (`/search`, `/query`, `/annotations`), and the control api which changes what the tab killer does,
+/// only with the token
+fn route(
+    request: &mut Request,
+    snapshot: &SharedSnapshot,
+    history: &SharedHistory,
+    control: &SharedControl,
+    clock: &Clock,
+    config: &Config,
+    token: &str,
+) -> JsonResponse {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    // Web pages can send simple POST requests to localhost too, but not without "Origin"
+    let control_request = *request.method() == Method::Post
+        && matches!(segments.as_slice(), ["pause" | "resume"] | ["tabs", ..]);
+    if control_request
+        && request
+            .headers()
+            .iter()
+            .any(|header| header.field.equiv("Origin"))
+    {
+        return error_response(403, "Cross-origin requests are not allowed");
+    }
+    // Other users of the system can reach localhost too, only those who can read the token file
+    // are allowed to control
+    if control_request && !has_token(request, token) {
+        warn!(target: "output_tab_data_server", "Reject control request {}, wrong token", path);
+        return error_response(
+            401,
+            "Wrong token, send it as \"Authorization: Bearer <token>\"",
+        );
+    }
+
+    match (request.method(), segments.as_slice()) {
+        // One consistent snapshot for the whole response, without blocking the tab killer
+        (Method::Get, []) => json_response(200, &**snapshot.load()),
+        (Method::Get, ["config"]) => json_response(200, config),
+        (Method::Get, ["api", "tabs"]) => json_response(200, &snapshot.load().tab_infos),
+        (Method::Get, ["api", "tabs", pid]) => {
+            let Ok(pid) = pid.parse::<u32>() else {
+                return error_response(400, &format!("Invalid pid {:?}", pid));
+            };
+            let snapshot = snapshot.load();
+            match snapshot
+                .tab_infos
+                .iter()
+                .find(|tab_info| tab_info.pid == pid)
+            {
+                Some(tab_info) => json_response(200, tab_info),
+                None => error_response(404, &format!("Tab of pid {} is not found", pid)),
+            }
+        }
+        (Method::Get, ["api", "history"]) => match parse_range(query) {
+            Ok((from, to)) => json_response(200, &history.read().unwrap().global_points(from, to)),
+            Err(response) => response,
+        },
+        (Method::Get, ["api", "history", "tabs"]) => match parse_range(query) {
+            Ok((from, to)) => json_response(200, &history.read().unwrap().tab_histories(from, to)),
+            Err(response) => response,
+        },
+        (Method::Get, ["api", "history", "tabs", pid]) => {
+            let Ok(pid) = pid.parse::<u32>() else {
+                return error_response(400, &format!("Invalid pid {:?}", pid));
+            };
+            let (from, to) = match parse_range(query) {
+                Ok(range) => range,
+                Err(response) => return response,
+            };
+            match history.read().unwrap().tab_history(pid, from, to) {
+                Some(tab_history) => json_response(200, &tab_history),
+                None => error_response(404, &format!("History of pid {} is not found", pid)),
+            }
+        }
+        (Method::Get, ["api", "kills"]) => match parse_range(query) {
+            Ok((from, to)) => json_response(200, &history.read().unwrap().killed_tabs(from, to)),
+            Err(response) => response,
+        },
+        (Method::Post, ["search"]) => json_response(200, &grafana::search()),
+        (Method::Post, ["query"]) => {
+            let query_request: QueryRequest = match read_json_body(request) {
+                Ok(query_request) => query_request,
+                Err(response) => return response,
+            };
+            match grafana::query(&query_request, &history.read().unwrap()) {
+                Ok(time_series) => json_response(200, &time_series),
+                Err(e) => error_response(400, &e),
+            }
+        }
+        (Method::Post, ["annotations"]) => {
+            let annotation_request: AnnotationRequest = match read_json_body(request) {
+                Ok(annotation_request) => annotation_request,
+                Err(response) => return response,
+            };
+            json_response(
+                200,
+                &grafana::annotations(&annotation_request, &history.read().unwrap()),
+            )
+        }
+        (Method::Post, ["pause"]) => {
+            let until = match parse_until(query, clock) {
+                Ok(until) => until,
+                Err(response) => return response,
+            };
+            control.lock().unwrap().pause(until);
+            info!(target: "output_tab_data_server", until, "Pause killing tabs");
+            json_response(200, &json!({ "paused": true, "until": until }))
+        }
+        (Method::Post, ["resume"]) => {
+            if control.lock().unwrap().resume() {
+                info!(target: "output_tab_data_server", "Resume killing tabs");
+            }
+            json_response(200, &json!({ "paused": false }))
+        }
+        (Method::Post, ["tabs", pid, action]) => {
+            let Ok(pid) = pid.parse::<u32>() else {
+                return error_response(400, &format!("Invalid pid {:?}", pid));
+            };
+            if !snapshot
+                .load()
+                .tab_infos
+                .iter()
+                .any(|tab_info| tab_info.pid == pid)
+            {
+                return error_response(404, &format!("Tab of pid {} is not found", pid));
+            }
+            control_tab(pid, action, query, control, clock)
+        }
+        _ => error_response(404, "Not found"),
+    }
+}
 
-/// The data is for sharing to frontend
-#[derive(Debug, Deserialize, Serialize)]
-struct OutputTabData {
-    timestamp: f64,
-    tab_infos: Vec<OutputTabInfo>,
+/// Whether the request has "Authorization: Bearer <token>"
+fn has_token(request: &Request, token: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|header| header.field.equiv("Authorization"))
+        .filter_map(|header| header.value.as_str().strip_prefix("Bearer "))
+        .any(|bearer_token| token_matches(bearer_token.trim(), token))
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct OutputTabInfo {
-    title: String,
+/// `POST /tabs/{pid}/protect[?secs=N]`, `/tabs/{pid}/unprotect` and `/tabs/{pid}/kill`, by pid
+/// of the tab process like `/api/tabs/{pid}`, tab ids of the extension collide across browsers
+fn control_tab(
     pid: u32,
-    rss: u64,
-    audible: bool,
-    foreground: bool,
-    background_time_secs: f64,
-    cpu_usage: f32,
-    cpu_idle_time_secs: f64,
+    action: &str,
+    query: &str,
+    control: &SharedControl,
+    clock: &Clock,
+) -> JsonResponse {
+    match action {
+        "protect" => {
+            let until = match parse_until(query, clock) {
+                Ok(until) => until,
+                Err(response) => return response,
+            };
+            control.lock().unwrap().protections.protect(pid, until);
+            info!(target: "output_tab_data_server", pid, until, "Protect tab");
+            json_response(
+                200,
+                &json!({ "pid": pid, "protected": true, "until": until }),
+            )
+        }
+        "unprotect" => {
+            if control.lock().unwrap().protections.unprotect(pid) {
+                info!(target: "output_tab_data_server", pid, "Unprotect tab");
+            }
+            json_response(200, &json!({ "pid": pid, "protected": false }))
+        }
+        "kill" => {
+            control.lock().unwrap().request_kill(pid);
+            info!(target: "output_tab_data_server", pid, "Request to kill tab");
+            // Killed on the next tick of the tab killer
+            json_response(202, &json!({ "pid": pid, "kill": "requested" }))
+        }
+        _ => error_response(404, "Not found"),
+    }
 }
 
-pub fn spawn_output_tab_data_server(status: Arc<Mutex<Status>>) -> JoinHandle<()> {
-    spawn(move || {
-        let addr = "127.0.0.1:60001";
-        serve_output_tab_data(status, addr);
+/// Expire timestamp from `secs=N` of query by the clock of the tab killer, `None` if not given
+fn parse_until(query: &str, clock: &Clock) -> Result<Option<f64>, JsonResponse> {
+    let Some(secs) = query_param(query, "secs") else {
+        return Ok(None);
+    };
+    match secs.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => Ok(Some(add_secs(clock.now(), secs))),
+        _ => Err(error_response(
+            400,
+            &format!("Invalid secs {:?}, expect a positive number", secs),
+        )),
+    }
+}
+
+/// `from` and `to` timestamps in milliseconds of query, unbounded if not given
+fn parse_range(query: &str) -> Result<(Timestamp, Timestamp), JsonResponse> {
+    let parse = |name, default| match query_param(query, name) {
+        None => Ok(default),
+        Some(timestamp) => timestamp.parse::<Timestamp>().map_err(|_| {
+            error_response(
+                400,
+                &format!("Invalid {} {:?}, expect milliseconds", name, timestamp),
+            )
+        }),
+    };
+    Ok((parse("from", 0.0)?, parse("to", f64::INFINITY)?))
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        pair.split_once('=')
+            .filter(|(key, _)| *key == name)
+            .map(|(_, value)| value)
     })
 }
 
-fn serve_output_tab_data(status: Arc<Mutex<Status>>, addr: impl ToSocketAddrs) {
-    // Must ramain stat for statistics cpu_usage
-    Server::bind(addr)
-        .serve(move |_, _| {
-            let output_tab_data = generate_output_tab_data(&status);
-            let json = serde_json::to_string(&output_tab_data).unwrap();
-            Response::new(Body::new(json))
-        })
-        .unwrap();
+fn read_json_body<T: DeserializeOwned>(request: &mut Request) -> Result<T, JsonResponse> {
+    serde_json::from_reader(request.as_reader())
+        .map_err(|e| error_response(400, &format!("Invalid request body: {}", e)))
 }
 
-fn generate_output_tab_data(status: &Arc<Mutex<Status>>) -> OutputTabData {
-    let output_tab_infos = generate_output_tab_infos(status);
-    OutputTabData {
-        timestamp: status.lock().unwrap().timestamp,
-        tab_infos: output_tab_infos,
-    }
+fn json_response(status_code: u16, body: &impl Serialize) -> JsonResponse {
+    let json = serde_json::to_string(body).unwrap();
+    Response::from_string(json)
+        .with_status_code(StatusCode(status_code))
+        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
 }
 
-fn generate_output_tab_infos(status: &Arc<Mutex<Status>>) -> Vec<OutputTabInfo> {
-    let status = status.lock().unwrap();
+fn error_response(status_code: u16, message: &str) -> JsonResponse {
+    json_response(status_code, &json!({ "error": message }))
+}
 
-    // Get each minimum tab info
-    status
-        .tab_infos
-        .iter()
-        .map(|(pid, tab_info)| {
-            if let (
-                Some(process),
-                Some(begin_background_timestamp),
-                Some(begin_cpu_idle_timestamp),
-            ) = (
-                status.system.processes().get(pid),
-                status.begin_background_timestamps.get(pid),
-                status.begin_cpu_idle_timestamps.get(pid),
-            ) {
-                Some(OutputTabInfo {
-                    title: tab_info.title.clone(),
-                    pid: pid.as_u32(),
-                    rss: process.memory(),
-                    audible: tab_info.audible,
-                    foreground: tab_info.active,
-                    cpu_usage: process.cpu_usage(),
-                    background_time_secs: (status.timestamp - begin_background_timestamp) / 1000.0,
-                    cpu_idle_time_secs: (status.timestamp - begin_cpu_idle_timestamp) / 1000.0,
-                })
-            } else {
-                None
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::{Mutex, RwLock},
+        thread,
+    };
+
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::{
+        control::Control,
+        history::History,
+        snapshot::{Snapshot, SnapshotTabInfo},
+    };
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    /// Status code of a POST request with extra header lines
+    fn post(addr: &str, path: &str, headers: &str) -> u16 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST {path} HTTP/1.0\r\nHost: {addr}\r\n{headers}Content-Length: 0\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.split(' ').nth(1).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn tabs_are_controlled_by_pid_only_with_the_token_and_without_origin() {
+        let config: Config = toml::from_str(include_str!("config.toml")).unwrap();
+        let clock = Clock::simulated(1_700_000_000_000.0);
+        let tab_info = |pid| SnapshotTabInfo {
+            // Tab ids of different browsers collide
+            id: 1,
+            title: String::new(),
+            url: String::new(),
+            pid,
+            rss: 0,
+            audible: false,
+            foreground: false,
+            background_time_secs: 0.0,
+            cpu_usage: 0.0,
+            cpu_idle_time_secs: 0.0,
+            protected: false,
+            next_kill: None,
+        };
+        let snapshot: SharedSnapshot = Arc::new(ArcSwap::from_pointee(Snapshot {
+            tab_infos: vec![tab_info(11), tab_info(21)],
+            ..Default::default()
+        }));
+        let history: SharedHistory = Arc::new(RwLock::new(History::new(config.history)));
+        let control: SharedControl = Arc::new(Mutex::new(Control::default()));
+
+        let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+        let addr = server.server_addr().to_ip().unwrap().to_string();
+        let serving = thread::spawn({
+            let server = Arc::clone(&server);
+            let control = Arc::clone(&control);
+            let clock = clock.clone();
+            move || {
+                serve_output_tab_data(
+                    &server, &snapshot, &history, &control, &clock, &config, TOKEN,
+                )
             }
-        })
-        .filter_map(|mini_tab_info| mini_tab_info)
-        .collect()
+        });
+
+        let authorization = format!("Authorization: Bearer {TOKEN}\r\n");
+        assert_eq!(post(&addr, "/tabs/21/kill", ""), 401);
+        assert_eq!(
+            post(&addr, "/tabs/21/kill", "Authorization: Bearer wrong\r\n"),
+            401
+        );
+        assert_eq!(
+            post(
+                &addr,
+                "/tabs/21/kill",
+                &format!("{authorization}Origin: https://example.com\r\n")
+            ),
+            403
+        );
+        assert_eq!(post(&addr, "/tabs/31/kill", &authorization), 404);
+        assert_eq!(post(&addr, "/tabs/21/kill", &authorization), 202);
+        assert_eq!(post(&addr, "/tabs/11/protect", &authorization), 200);
+
+        let control_state = control.lock().unwrap().take_state(clock.now());
+        assert_eq!(control_state.kill_requests, [21]);
+        assert!(control_state.protections.is_protected(11, clock.now()));
+        assert!(!control_state.protections.is_protected(21, clock.now()));
+
+        server.unblock();
+        serving.join().unwrap();
+    }
 }
@@ -0,0 +1,134 @@
+use std::{collections::HashSet, fs, io, path::Path};
+
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+use crate::browser::BrowserBackend;
+
+/// Browser processes, found by walking down from the browser main processes,
+/// so only they need to be refreshed instead of every process on the system
+#[derive(Debug, Default)]
+pub struct ProcessTree {
+    // Browser main processes, whose parent is not a browser process
+    root_pids: Vec<Pid>,
+    // Root pids and all their descendants
+    pids: Vec<Pid>,
+    // Cleared when tabs changed or a tracked process exited, the tree must be walked again
+    up_to_date: bool,
+    // Set when a browser may be running outside of the roots, all processes must be scanned
+    roots_outdated: bool,
+}
+
+impl ProcessTree {
+    pub fn pids(&self) -> &[Pid] {
+        &self.pids
+    }
+
+    pub fn mark_outdated(&mut self) {
+        self.up_to_date = false;
+    }
+
+    pub fn mark_roots_outdated(&mut self) {
+        self.up_to_date = false;
+        self.roots_outdated = true;
+    }
+
+    /// Walk the tree again if outdated, scan all processes only if the browser main processes are
+    /// gone or the roots are outdated
+    pub fn rescan_if_outdated(
+        &mut self,
+        system: &mut System,
+        backend: &dyn BrowserBackend,
+        browser_name: &str,
+    ) {
+        if self.up_to_date {
+            return;
+        }
+
+        let descendants = match self.roots_outdated {
+            true => None,
+            false => find_descendants(&self.root_pids),
+        };
+        self.roots_outdated = false;
+        self.pids = match descendants {
+            Some(pids) => pids,
+            None => {
+                // Also runs every tick while the browser is not running, cmdline is read once per process
+                system.refresh_processes_specifics(
+                    ProcessesToUpdate::All,
+                    true,
+                    ProcessRefreshKind::nothing().with_cmd(UpdateKind::OnlyIfNotSet),
+                );
+                let browser_pids: HashSet<Pid> = system
+                    .processes()
+                    .values()
+                    .filter(|process| backend.is_browser_process(&(*process).into(), browser_name))
+                    .map(|process| process.pid())
+                    .collect();
+                self.root_pids = system
+                    .processes()
+                    .values()
+                    .filter(|process| browser_pids.contains(&process.pid()))
+                    .filter(|process| {
+                        !process
+                            .parent()
+                            .is_some_and(|parent_pid| browser_pids.contains(&parent_pid))
+                    })
+                    .map(|process| process.pid())
+                    .collect();
+                // Fallback to the scanned processes if the kernel doesn't expose children
+                find_descendants(&self.root_pids)
+                    .unwrap_or_else(|| browser_pids.into_iter().collect())
+            }
+        };
+        self.up_to_date = !self.root_pids.is_empty();
+    }
+}
+
+/// The root pids and all their descendants, `None` if any root is gone or there is no root
+fn find_descendants(root_pids: &[Pid]) -> Option<Vec<Pid>> {
+    if root_pids.is_empty() {
+        return None;
+    }
+
+    let mut pids = Vec::new();
+    let mut pending_pids = Vec::new();
+    for &root_pid in root_pids {
+        pids.push(root_pid);
+        pending_pids.extend(read_children(root_pid).ok()?);
+    }
+    while let Some(pid) = pending_pids.pop() {
+        pids.push(pid);
+        // The process may exit while walking
+        if let Ok(children) = read_children(pid) {
+            pending_pids.extend(children);
+        }
+    }
+    Some(pids)
+}
+
+/// Children of all threads of the process, from "/proc/<pid>/task/<tid>/children"
+fn read_children(pid: Pid) -> io::Result<Vec<Pid>> {
+    let task_dir = Path::new("/proc").join(pid.to_string()).join("task");
+    let parse_children = |content: String| -> Vec<Pid> {
+        content
+            .split_whitespace()
+            .filter_map(|child| child.parse::<Pid>().ok())
+            .collect()
+    };
+
+    // Fails if the process exited or the kernel doesn't have "children" files
+    let mut children = parse_children(fs::read_to_string(
+        task_dir.join(pid.to_string()).join("children"),
+    )?);
+    for task_entry in fs::read_dir(&task_dir)? {
+        let task_path = task_entry?.path();
+        if task_path.file_name() == Some(pid.to_string().as_ref()) {
+            continue;
+        }
+        // The thread may exit while reading
+        if let Ok(content) = fs::read_to_string(task_path.join("children")) {
+            children.extend(parse_children(content));
+        }
+    }
+    Ok(children)
+}
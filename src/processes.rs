@@ -0,0 +1,141 @@
+use std::{collections::HashMap, io};
+
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, Signal, System, UpdateKind};
+
+use crate::{config::Config, process_tree::ProcessTree, status::ProcessInfo};
+
+/// Where browser processes are sampled from on each tick
+pub trait ProcessSource {
+    /// Memory, cpu usage, start time and class of every browser process, `tabs_changed` tells
+    /// a tab may be hosted by a process not seen yet
+    fn browser_processes(
+        &mut self,
+        tabs_changed: bool,
+        config: &Config,
+    ) -> HashMap<Pid, ProcessInfo>;
+
+    /// Memory of the system available for starting new applications, as of the last sample
+    fn available_memory(&self) -> u64;
+
+    /// Scan all processes for browser main processes on the next sample, e.g. a browser started
+    /// while others are running, which is not under the known ones
+    fn rescan_browsers(&mut self);
+}
+
+/// How tab processes are killed
+pub trait ProcessTerminator {
+    /// Send SIGTERM to the process
+    fn terminate(&mut self, pid: Pid) -> io::Result<()>;
+}
+
+/// Processes of this system, read by sysinfo and from "/proc"
+#[derive(Debug, Default)]
+pub struct SystemProcesses {
+    system: System,
+    // Only processes in it are refreshed
+    process_tree: ProcessTree,
+    // The last sample, class and browser inner pid are parsed from cmdline once per process
+    browser_processes: HashMap<Pid, ProcessInfo>,
+}
+
+impl ProcessSource for SystemProcesses {
+    fn browser_processes(
+        &mut self,
+        tabs_changed: bool,
+        config: &Config,
+    ) -> HashMap<Pid, ProcessInfo> {
+        let backend = config.browser.backend();
+        let browser_name = config.browser_name.as_str();
+        if tabs_changed {
+            self.process_tree.mark_outdated();
+        }
+        self.process_tree
+            .rescan_if_outdated(&mut self.system, backend, browser_name);
+        // Only browser processes, cmdline is read once for new processes
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(self.process_tree.pids()),
+            true,
+            ProcessRefreshKind::nothing()
+                .with_memory()
+                .with_cpu()
+                .with_cmd(UpdateKind::OnlyIfNotSet),
+        );
+        // Available memory of the system, recorded in history
+        self.system.refresh_memory();
+
+        let processes = self.system.processes();
+        if self
+            .process_tree
+            .pids()
+            .iter()
+            .any(|pid| !processes.contains_key(pid))
+        {
+            // Some process exited, the browser may have started others
+            self.process_tree.mark_outdated();
+        }
+        let last_browser_processes = std::mem::take(&mut self.browser_processes);
+        self.browser_processes = self
+            .process_tree
+            .pids()
+            .iter()
+            .filter_map(|pid| processes.get(pid))
+            .filter(|process| backend.is_browser_process(&(*process).into(), browser_name))
+            .map(|process| {
+                // Cmdline doesn't change, unless the pid is reused by another process
+                let (class, browser_inner_pid) = match last_browser_processes.get(&process.pid()) {
+                    Some(last) if last.start_time == process.start_time() => {
+                        (last.class.clone(), last.browser_inner_pid)
+                    }
+                    _ => {
+                        let cmdline = process.into();
+                        (
+                            backend.process_class(&cmdline),
+                            backend.browser_inner_pid(&cmdline),
+                        )
+                    }
+                };
+                let process_info = ProcessInfo {
+                    parent: process.parent().map(Pid::as_u32),
+                    start_time: process.start_time(),
+                    rss: process.memory(),
+                    cpu_usage: process.cpu_usage(),
+                    class,
+                    browser_inner_pid,
+                };
+                (process.pid(), process_info)
+            })
+            .collect();
+        self.browser_processes.clone()
+    }
+
+    fn available_memory(&self) -> u64 {
+        self.system.available_memory()
+    }
+
+    fn rescan_browsers(&mut self) {
+        self.process_tree.mark_roots_outdated();
+    }
+}
+
+impl ProcessTerminator for SystemProcesses {
+    fn terminate(&mut self, pid: Pid) -> io::Result<()> {
+        let process = self
+            .system
+            .process(pid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such process"))?;
+        match process.kill_with(Signal::Term) {
+            Some(true) => Ok(()),
+            Some(false) => Err(io::Error::other(format!(
+                "Failed to send signal {}",
+                Signal::Term
+            ))),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "The signal {} is not supported on this platform",
+                    Signal::Term
+                ),
+            )),
+        }
+    }
+}
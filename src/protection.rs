@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use crate::protocol::Timestamp;
+
+/// Tabs temporarily excluded from every kill strategy, e.g. by "Keep this tab" of a notification.
+/// Keyed by the pid of the tab process, tab ids of the extension collide across browsers
+#[derive(Clone, Debug, Default)]
+pub struct Protections {
+    // When the protection of each tab process expires, `None` until unprotected
+    expire_timestamps: HashMap<u32, Option<Timestamp>>,
+}
+
+impl Protections {
+    pub fn protect(&mut self, pid: u32, expire_timestamp: Option<Timestamp>) {
+        self.expire_timestamps.insert(pid, expire_timestamp);
+    }
+
+    /// Return false if the tab is not protected
+    pub fn unprotect(&mut self, pid: u32) -> bool {
+        self.expire_timestamps.remove(&pid).is_some()
+    }
+
+    pub fn is_protected(&self, pid: u32, timestamp: Timestamp) -> bool {
+        self.expire_timestamps
+            .get(&pid)
+            .is_some_and(|&expire_timestamp| !is_expired(expire_timestamp, timestamp))
+    }
+
+    pub fn remove_expired(&mut self, timestamp: Timestamp) {
+        self.expire_timestamps
+            .retain(|_, &mut expire_timestamp| !is_expired(expire_timestamp, timestamp));
+    }
+
+    /// Drop protections of processes which exited, so a reused pid isn't protected
+    pub fn retain_pids(&mut self, mut is_running: impl FnMut(u32) -> bool) {
+        self.expire_timestamps.retain(|&pid, _| is_running(pid));
+    }
+}
+
+fn is_expired(expire_timestamp: Option<Timestamp>, timestamp: Timestamp) -> bool {
+    expire_timestamp.is_some_and(|expire_timestamp| expire_timestamp <= timestamp)
+}
@@ -0,0 +1,289 @@
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tracing::warn;
+
+use crate::PROJECT_NAME;
+
+pub type BrowserInnerPid = u64;
+pub type Timestamp = f64;
+pub type TabId = u64;
+/// Each extension connection (e.g. a browser profile) has its own tabs
+pub type ConnectionId = u32;
+/// Extension numbers every message it sends, a missing number means events were lost
+pub type Seq = u64;
+
+/// Bump it on any change that older extensions or daemons can't understand
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What this daemon can do, announced in `ack`
+pub const DAEMON_CAPABILITIES: &[&str] = &["snapshotCommand"];
+
+/// Every message starts with `{"v": <version>, "type": <type>}`, peek the version before parsing the rest
+#[derive(Debug, Deserialize)]
+struct VersionHeader {
+    v: Option<u32>,
+}
+
+/// A message from extension
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ExtensionMessage {
+    /// The first message of a connection
+    Hello(Hello),
+    /// The full snapshot of tabs
+    Snapshot(TabData),
+    /// A change of a tab
+    Event(TabEventData),
+    /// Sent by a newer extension, ignored
+    #[serde(other)]
+    Unknown,
+}
+
+/// A message to extension
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum DaemonMessage {
+    /// Reply of `hello`
+    #[serde(rename_all = "camelCase")]
+    Ack {
+        daemon_version: String,
+        capabilities: Vec<String>,
+    },
+    Command {
+        command: Command,
+    },
+    /// The connection is closed after it if `fatal`
+    Error {
+        message: String,
+        fatal: bool,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Command {
+    /// Send a snapshot back, e.g. events were missed
+    Snapshot,
+}
+
+/// Adds the protocol version to a daemon message
+#[derive(Debug, Serialize)]
+struct VersionedDaemonMessage<'a> {
+    v: u32,
+    #[serde(flatten)]
+    message: &'a DaemonMessage,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Hello {
+    pub extension_version: String,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabData {
+    #[serde(default)]
+    pub seq: Option<Seq>,
+    pub timestamp: Timestamp,
+    #[serde(deserialize_with = "deserialize_tab_infos")]
+    pub tab_infos: Vec<TabInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabEventData {
+    pub seq: Seq,
+    // When the event happened
+    pub timestamp: Timestamp,
+    pub event: TabEvent,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TabEvent {
+    /// Tab created, activated, backgrounded, or any property changed
+    #[serde(rename_all = "camelCase")]
+    Updated { tab_info: TabInfo },
+    #[serde(rename_all = "camelCase")]
+    Removed { tab_id: TabId },
+    /// Sent by a newer extension, ignored
+    #[serde(other)]
+    Unknown,
+}
+
+/// Missing fields are defaulted, so browsers or extension versions without some of them still work,
+/// except those strategies rely on, a tab without them is skipped rather than killed by mistake
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TabInfo {
+    pub active: bool,
+    #[serde(default)]
+    pub audible: bool,
+    #[serde(default)]
+    pub auto_discardable: bool,
+    #[serde(default)]
+    pub discarded: bool,
+    #[serde(default)]
+    pub fav_icon_url: Option<String>,
+    #[serde(default)]
+    pub group_id: i32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub highlighted: bool,
+    pub id: TabId,
+    #[serde(default)]
+    pub incognito: bool,
+    #[serde(default)]
+    pub index: u32,
+    pub last_accessed: Timestamp,
+    #[serde(default)]
+    pub muted_info: MutedInfo,
+    #[serde(default)]
+    pub pinned: bool,
+    #[serde(default)]
+    pub selected: bool,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub window_id: usize,
+    // Tabs without a process (e.g. discarded) are reported as -1 or null
+    #[serde(default, deserialize_with = "deserialize_browser_inner_pid")]
+    pub browser_inner_pid: Option<BrowserInnerPid>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MutedInfo {
+    pub muted: bool,
+}
+
+/// Skip tabs that can't be parsed instead of dropping the whole snapshot
+fn deserialize_tab_infos<'de, D>(deserializer: D) -> Result<Vec<TabInfo>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(values
+        .into_iter()
+        .filter_map(|value| match serde_json::from_value::<TabInfo>(value) {
+            Ok(tab_info) => Some(tab_info),
+            Err(e) => {
+                warn!(target: "tab_data_requester", "Skip a tab which can't be parsed: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn deserialize_browser_inner_pid<'de, D>(
+    deserializer: D,
+) -> Result<Option<BrowserInnerPid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<i64>::deserialize(deserializer)? {
+        Some(browser_inner_pid) if browser_inner_pid >= 0 => {
+            BrowserInnerPid::try_from(browser_inner_pid)
+                .map(Some)
+                .map_err(de::Error::custom)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parse a message from extension, the error should be sent back to extension
+pub fn parse_extension_message(msg: &str) -> Result<ExtensionMessage, DaemonMessage> {
+    let version_header =
+        serde_json::from_str::<VersionHeader>(msg).map_err(|e| DaemonMessage::Error {
+            message: format!("Failed to parse json: {e}"),
+            fatal: false,
+        })?;
+    match version_header.v {
+        Some(PROTOCOL_VERSION) => (),
+        Some(v) => {
+            return Err(DaemonMessage::Error {
+                message: format!(
+                    "Incompatible protocol version {v}, {PROJECT_NAME} {} speaks version {PROTOCOL_VERSION}",
+                    env!("CARGO_PKG_VERSION")
+                ),
+                fatal: true,
+            })
+        }
+        None => {
+            return Err(DaemonMessage::Error {
+                message: format!(
+                    "Missing protocol version, update the extension to speak version {PROTOCOL_VERSION}"
+                ),
+                fatal: true,
+            })
+        }
+    }
+
+    serde_json::from_str::<ExtensionMessage>(msg).map_err(|e| DaemonMessage::Error {
+        message: format!("Failed to parse message: {e}"),
+        fatal: false,
+    })
+}
+
+impl DaemonMessage {
+    pub fn ack() -> Self {
+        DaemonMessage::Ack {
+            daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: DAEMON_CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+        }
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, DaemonMessage::Error { fatal: true, .. })
+    }
+
+    /// Json with the protocol version
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&VersionedDaemonMessage {
+            v: PROTOCOL_VERSION,
+            message: self,
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tabs_without_fields_strategies_rely_on_are_skipped() {
+        let msg = r#"{
+            "v": 1,
+            "type": "snapshot",
+            "timestamp": 1700000000000,
+            "tabInfos": [
+                {"id": 1, "active": false, "lastAccessed": 1699999990000, "browserInnerPid": 5},
+                {"id": 2, "active": false, "browserInnerPid": 6},
+                {"active": false, "lastAccessed": 1699999990000, "browserInnerPid": 7},
+                {"id": 4, "lastAccessed": 1699999990000, "browserInnerPid": 8}
+            ]
+        }"#;
+        let Ok(ExtensionMessage::Snapshot(tab_data)) = parse_extension_message(msg) else {
+            panic!("Expect a snapshot");
+        };
+        let tab_ids: Vec<TabId> = tab_data
+            .tab_infos
+            .iter()
+            .map(|tab_info| tab_info.id)
+            .collect();
+        assert_eq!(tab_ids, [1]);
+        assert_eq!(tab_data.tab_infos[0].browser_inner_pid, Some(5));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+use tracing::warn;
+
+use crate::{
+    clock::{secs_between, Clock},
+    config::Config,
+    control::ControlState,
+    protocol::{ConnectionId, TabData, TabEventData, Timestamp},
+    snapshot::Snapshot,
+    status::{Connections, ProcessInfo, Status},
+    strategy::{evaluate, KillDecision},
+};
+
+/// A line of a recording, in the order the daemon saw them
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Connected {
+        connection_id: ConnectionId,
+        // A process of the browser of the connection
+        #[serde(default)]
+        browser_pid: Option<u32>,
+    },
+    Disconnected {
+        connection_id: ConnectionId,
+    },
+    Snapshot {
+        connection_id: ConnectionId,
+        tab_data: TabData,
+    },
+    Event {
+        connection_id: ConnectionId,
+        tab_event_data: TabEventData,
+    },
+    // Browser processes sampled by the tab killer, strategies are applied right after it
+    Tick {
+        timestamp: Timestamp,
+        // The wall clock at the same time, tells jumps of it, `None` in older recordings
+        #[serde(default)]
+        wall_timestamp: Option<Timestamp>,
+        // Total time the system was suspended since the daemon started
+        #[serde(default)]
+        suspended_secs: f64,
+        available_memory: u64,
+        processes: Vec<RecordedProcess>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecordedProcess {
+    pid: u32,
+    #[serde(flatten)]
+    process_info: ProcessInfo,
+}
+
+/// Appends records to a file as json lines, shared by the connection handlers and the tab killer
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    // Flushed on each line, the daemon may be killed any time
+    writer: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Recorder {
+    /// Truncate the file if it exists, only readable by the user since it has urls of tabs
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Recorder {
+            writer: Arc::new(Mutex::new(LineWriter::new(file))),
+        })
+    }
+
+    pub fn record(&self, record: &Record) {
+        let result = serde_json::to_string(record)
+            .map_err(io::Error::from)
+            .and_then(|json| writeln!(self.writer.lock().unwrap(), "{json}"));
+        if let Err(e) = result {
+            warn!(target: "replay", "Failed to record: {}", e);
+        }
+    }
+
+    /// Processes of `status` just refreshed by `clock`
+    pub fn record_tick(&self, status: &Status, clock: &Clock) {
+        self.record(&Record::Tick {
+            timestamp: status.timestamp,
+            wall_timestamp: Some(clock.to_wall(status.timestamp)),
+            suspended_secs: clock.suspended_secs(),
+            available_memory: status.available_memory,
+            processes: status
+                .browser_processes
+                .iter()
+                .map(|(pid, process_info)| RecordedProcess {
+                    pid: pid.as_u32(),
+                    process_info: process_info.clone(),
+                })
+                .collect(),
+        });
+    }
+}
+
+/// Tabs the strategies would kill in a recording
+#[derive(Debug, Default)]
+pub struct Replayed {
+    pub tick_count: usize,
+    // From the first tick to the last one
+    pub duration_secs: f64,
+    pub kills: Vec<ReplayedKill>,
+}
+
+#[derive(Debug)]
+pub struct ReplayedKill {
+    // Since the first tick
+    pub offset_secs: f64,
+    pub title: String,
+    pub rss: u64,
+    pub decision: KillDecision,
+}
+
+/// Feed a recording through status and strategies with the clock of the recording, return tabs
+/// that would be killed and when. Nothing is protected or paused, and killed processes are
+/// left out of later ticks
+pub fn replay(path: &Path, config: &Config) -> io::Result<Replayed> {
+    let reader = BufReader::new(File::open(path)?);
+    // Set by ticks, so timers and snapshot requests go on as they did when recorded
+    let clock = Clock::simulated(0.0);
+    let mut connections = Connections::new(None, clock.clone());
+    let mut status = Status::default();
+    let mut killed_pids = HashSet::new();
+    let mut first_timestamp = None;
+    let mut replayed = Replayed::default();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid record at line {}: {}", index + 1, e),
+            )
+        })?;
+        match record {
+            Record::Connected {
+                connection_id,
+                browser_pid,
+            } => connections.add_connection(connection_id, browser_pid),
+            Record::Disconnected { connection_id } => connections.remove_connection(connection_id),
+            Record::Snapshot {
+                connection_id,
+                tab_data,
+            } => connections.apply_snapshot(connection_id, tab_data),
+            Record::Event {
+                connection_id,
+                tab_event_data,
+            } => {
+                connections.apply_event(connection_id, tab_event_data, config);
+            }
+            Record::Tick {
+                timestamp,
+                wall_timestamp,
+                suspended_secs,
+                available_memory,
+                processes,
+            } => {
+                let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+                let newly_suspended_secs = suspended_secs - clock.suspended_secs();
+                if newly_suspended_secs > 0.0 {
+                    clock.suspend(Duration::from_secs_f64(newly_suspended_secs));
+                }
+                clock.set(timestamp, wall_timestamp.unwrap_or(timestamp));
+                replayed.tick_count += 1;
+                status.available_memory = available_memory;
+                status.browser_processes = processes
+                    .into_iter()
+                    .filter(|process| !killed_pids.contains(&process.pid))
+                    .map(|process| (Pid::from_u32(process.pid), process.process_info))
+                    .collect();
+                // Processes are given, no rescan is needed
+                connections.take_tabs_changed();
+                status.update(&connections.fresh_connections(config), &clock, config);
+
+                let snapshot = Snapshot::new(&status, &ControlState::default());
+                for decision in evaluate(&snapshot, config) {
+                    let Some(tab_info) = snapshot
+                        .tab_infos
+                        .iter()
+                        .find(|tab_info| tab_info.pid == decision.pid)
+                    else {
+                        continue;
+                    };
+                    killed_pids.insert(tab_info.pid);
+                    replayed.kills.push(ReplayedKill {
+                        offset_secs: secs_between(first_timestamp, timestamp),
+                        title: tab_info.title.clone(),
+                        rss: tab_info.rss,
+                        decision,
+                    });
+                }
+            }
+        }
+    }
+
+    replayed.duration_secs = first_timestamp
+        .map(|first_timestamp| secs_between(first_timestamp, status.timestamp))
+        .unwrap_or_default();
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::KillTabStrategy,
+        protocol::{TabEvent, TabInfo},
+        strategy::KillReason,
+    };
+
+    // A background tab "Slides" and a private tab, recorded for 10s
+    const RECORDING: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/testdata/recording.jsonl");
+
+    #[test]
+    fn recording_is_replayed_with_its_clock() {
+        let mut config: Config = toml::from_str(include_str!("config.toml")).unwrap();
+        config.kill_tab_strategies = vec![KillTabStrategy::BackgroundTimeLimit];
+        config.strategy.background_time_limit.max_secs = 3.0;
+
+        let replayed = replay(Path::new(RECORDING), &config).unwrap();
+        assert_eq!(replayed.tick_count, 11);
+        assert_eq!(replayed.kills.len(), 1);
+        let kill = &replayed.kills[0];
+        assert_eq!(kill.decision.tab_id, 7);
+        // Paired with the renderer of browser inner pid 5 under the connected browser
+        assert_eq!(kill.decision.pid, 18021);
+        assert_eq!(kill.title, "Slides");
+        assert!(matches!(
+            kill.decision.reason,
+            KillReason::BackgroundTimeLimit { .. }
+        ));
+        // Reported in background 2.1s after the first tick, killed by the first tick 3s later
+        assert!((5.5..=6.5).contains(&kill.offset_secs));
+    }
+
+    #[test]
+    fn recording_connects_from_a_recorded_browser() {
+        let mut browser_pids = Vec::new();
+        let mut recorded_pids = HashSet::new();
+        for line in std::fs::read_to_string(RECORDING).unwrap().lines() {
+            match serde_json::from_str(line).unwrap() {
+                Record::Connected { browser_pid, .. } => browser_pids.extend(browser_pid),
+                Record::Tick { processes, .. } => {
+                    recorded_pids.extend(processes.iter().map(|process| process.pid))
+                }
+                _ => {}
+            }
+        }
+        // Tabs are paired by the browser of the connection, not guessed from inner pids
+        assert_eq!(browser_pids, [17977]);
+        assert!(browser_pids.iter().all(|pid| recorded_pids.contains(pid)));
+    }
+
+    #[test]
+    fn private_tabs_are_not_recorded() {
+        let path = std::env::temp_dir().join(format!("replay-test-{}.jsonl", std::process::id()));
+        let mut connections = Connections::new(
+            Some(Recorder::create(&path).unwrap()),
+            Clock::simulated(0.0),
+        );
+        let tab = |id, incognito| TabInfo {
+            id,
+            incognito,
+            url: format!("https://example.com/{id}"),
+            ..Default::default()
+        };
+        connections.apply_snapshot(
+            1,
+            TabData {
+                seq: Some(0),
+                timestamp: 0.0,
+                tab_infos: vec![tab(7, false), tab(8, true)],
+            },
+        );
+        connections.apply_event(
+            1,
+            TabEventData {
+                seq: 1,
+                timestamp: 0.0,
+                event: TabEvent::Updated {
+                    tab_info: tab(8, true),
+                },
+            },
+            &toml::from_str(include_str!("config.toml")).unwrap(),
+        );
+
+        let recording = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(recording.contains("https://example.com/7"));
+        assert!(!recording.contains("https://example.com/8"));
+        // The event is kept in place of the private tab
+        assert_eq!(recording.lines().count(), 2);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io::{self, Write},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{error, info};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+type StopHook = Box<dyn FnOnce() + Send>;
+
+/// Coordinates graceful shutdown, requested by SIGINT/SIGTERM or by any thread that ends.
+/// Blocking loops poll it, servers register hooks to stop themselves
+pub struct Shutdown {
+    // Readable forever once shutdown is requested, signal handlers write into it too
+    wake_reader: UnixStream,
+    wake_writer: UnixStream,
+    // The signal that requested shutdown, 0 if none
+    signal: Arc<AtomicUsize>,
+    // Set on the first signal, a second one terminates immediately
+    signaled: Arc<AtomicBool>,
+    // `None` once hooks are run
+    stop_hooks: Mutex<Option<Vec<StopHook>>>,
+}
+
+impl Shutdown {
+    /// Register the signal handlers
+    pub fn new() -> io::Result<Self> {
+        let (wake_reader, wake_writer) = UnixStream::pair()?;
+        wake_writer.set_nonblocking(true)?;
+        let signal = Arc::new(AtomicUsize::new(0));
+        let signaled = Arc::new(AtomicBool::new(false));
+        for sig in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(sig, 1, Arc::clone(&signaled))?;
+            signal_hook::flag::register(sig, Arc::clone(&signaled))?;
+            signal_hook::flag::register_usize(sig, Arc::clone(&signal), sig as usize)?;
+            signal_hook::low_level::pipe::register(sig, wake_writer.try_clone()?)?;
+        }
+
+        Ok(Shutdown {
+            wake_reader,
+            wake_writer,
+            signal,
+            signaled,
+            stop_hooks: Mutex::new(Some(Vec::new())),
+        })
+    }
+
+    pub fn request(&self) {
+        // Full buffer means it's already requested
+        let _ = (&self.wake_writer).write(&[0]);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        poll_readable(&[self.wake_reader.as_fd()], Some(Duration::ZERO))
+            .is_ok_and(|readable| readable[0])
+    }
+
+    /// Request shutdown when the returned guard drops, e.g. the thread holding it ended or panicked
+    pub fn guard(self: &Arc<Self>) -> ShutdownGuard {
+        ShutdownGuard(Arc::clone(self))
+    }
+
+    /// Sleep for the duration, return true if woken up by shutdown
+    pub fn sleep(&self, duration: Duration) -> bool {
+        match poll_readable(&[self.wake_reader.as_fd()], Some(duration)) {
+            Ok(readable) => readable[0],
+            Err(e) => {
+                error!(target: "shutdown", "Failed to wait for shutdown: {}", e);
+                std::thread::sleep(duration);
+                self.is_requested()
+            }
+        }
+    }
+
+    /// Block until `fd` is readable, return false if shutdown is requested instead
+    pub fn wait_readable(&self, fd: BorrowedFd) -> io::Result<bool> {
+        let readable = poll_readable(&[self.wake_reader.as_fd(), fd], None)?;
+        Ok(!readable[0])
+    }
+
+    /// Run `stop_hook` on shutdown, e.g. to stop a server blocking its thread
+    pub fn on_request(&self, stop_hook: impl FnOnce() + Send + 'static) {
+        let mut stop_hooks = self.stop_hooks.lock().unwrap();
+        match stop_hooks.as_mut() {
+            Some(stop_hooks) => stop_hooks.push(Box::new(stop_hook)),
+            // Already shutting down
+            None => stop_hook(),
+        }
+    }
+
+    /// Block until shutdown is requested, then stop everything registered
+    pub fn wait(&self) {
+        while !self.sleep(Duration::MAX) {}
+
+        if self.signaled.load(Ordering::SeqCst) {
+            info!(
+                target: "shutdown",
+                "Received signal {}, shutting down, send it again to force quit",
+                self.signal.load(Ordering::SeqCst)
+            );
+        } else {
+            info!(target: "shutdown", "Shutting down");
+        }
+        let stop_hooks = self.stop_hooks.lock().unwrap().take();
+        for stop_hook in stop_hooks.into_iter().flatten() {
+            stop_hook();
+        }
+    }
+}
+
+pub struct ShutdownGuard(Arc<Shutdown>);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.0.request();
+    }
+}
+
+/// Which of `fds` are readable, waiting until any is readable or timeout, `None` waits forever
+fn poll_readable(fds: &[BorrowedFd], timeout: Option<Duration>) -> io::Result<Vec<bool>> {
+    let mut poll_fds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|fd| libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout_millis = match timeout {
+        Some(timeout) => i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX),
+        None => -1,
+    };
+    loop {
+        // SAFETY: `poll_fds` is a valid array of `poll_fds.len()` elements
+        let ret = unsafe {
+            libc::poll(
+                poll_fds.as_mut_ptr(),
+                poll_fds.len() as libc::nfds_t,
+                timeout_millis,
+            )
+        };
+        if ret >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        // Interrupted by signals, including the shutdown ones
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(poll_fds
+        .iter()
+        .map(|poll_fd| poll_fd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process::Command};
+
+    use super::*;
+
+    // Signals are process wide, they are raised in a child running `signaled_child`
+    const SIGNAL_COUNT_VAR: &str = "SHUTDOWN_TEST_SIGNAL_COUNT";
+
+    fn run_signaled_child(signal_count: u32) -> std::process::Output {
+        Command::new(env::current_exe().unwrap())
+            .args([
+                "shutdown::tests::signaled_child",
+                "--exact",
+                "--ignored",
+                "--nocapture",
+            ])
+            .env(SIGNAL_COUNT_VAR, signal_count.to_string())
+            .output()
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "raises SIGTERM, run by the tests below in a child process"]
+    fn signaled_child() {
+        let signal_count: u32 = env::var(SIGNAL_COUNT_VAR).unwrap().parse().unwrap();
+        let shutdown = Shutdown::new().unwrap();
+        let (stopped_sender, stopped_receiver) = std::sync::mpsc::channel();
+        shutdown.on_request(move || stopped_sender.send(()).unwrap());
+        assert!(!shutdown.sleep(Duration::ZERO));
+
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert!(shutdown.sleep(Duration::from_secs(5)));
+        assert!(shutdown.signaled.load(Ordering::SeqCst));
+        assert_eq!(shutdown.signal.load(Ordering::SeqCst), SIGTERM as usize);
+        shutdown.wait();
+        stopped_receiver.try_recv().unwrap();
+        println!("Shut down gracefully");
+
+        for _ in 1..signal_count {
+            signal_hook::low_level::raise(SIGTERM).unwrap();
+        }
+    }
+
+    #[test]
+    fn a_signal_wakes_up_sleepers_and_runs_stop_hooks() {
+        let output = run_signaled_child(1);
+        assert!(output.status.success(), "{output:?}");
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Shut down gracefully"));
+    }
+
+    #[test]
+    fn a_second_signal_force_quits() {
+        let output = run_signaled_child(2);
+        assert_eq!(output.status.code(), Some(1), "{output:?}");
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Shut down gracefully"));
+        // Exited right away, the child test never finished
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("test result"));
+    }
+}
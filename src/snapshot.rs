@@ -0,0 +1,115 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cgroup::CgroupTierStatus,
+    clock::secs_between,
+    control::ControlState,
+    protocol::TabId,
+    status::{ProcessClassTotal, Status},
+};
+
+/// The latest snapshot, swapped by the tab killer thread after each tick
+pub type SharedSnapshot = Arc<ArcSwap<Snapshot>>;
+
+/// Immutable state of a tick, readers never block the tab killer thread.
+/// It's also the json shared to frontend (e.g. grafana dashboard)
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub timestamp: f64,
+    // Strategies don't kill tabs while paused by control api
+    pub paused: bool,
+    pub tab_infos: Vec<SnapshotTabInfo>,
+    // Memory of the system available for starting new applications
+    pub available_memory: u64,
+    // All browser processes, including those without tabs
+    pub process_classes: Vec<ProcessClassTotal>,
+    pub cgroup_tiers: Vec<CgroupTierStatus>,
+    // When the system last resumed from suspend, `None` if it didn't since the daemon started
+    pub resume_timestamp: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotTabInfo {
+    // Tab id of the extension, used by the control api
+    pub id: TabId,
+    pub title: String,
+    pub url: String,
+    pub pid: u32,
+    pub rss: u64,
+    pub audible: bool,
+    pub foreground: bool,
+    pub background_time_secs: f64,
+    pub cpu_usage: f32,
+    pub cpu_idle_time_secs: f64,
+    // Kept by user, skipped by every strategy
+    pub protected: bool,
+    // The strategy expected to kill the tab first, `None` if no strategy would
+    pub next_kill: Option<NextKill>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NextKill {
+    pub strategy: String,
+    // Until the time limit is hit, `None` for rss limit which depends on total rss
+    pub secs_left: Option<f64>,
+}
+
+impl Snapshot {
+    /// Tabs of the status with their timers, `next_kill` is filled by `predict_next_kills`
+    pub fn new(status: &Status, control_state: &ControlState) -> Self {
+        // Get each minimum tab info
+        let tab_infos = status
+            .tab_infos
+            .iter()
+            .filter_map(|(pid, tab_info)| {
+                if let (
+                    Some(process_info),
+                    Some(begin_background_timestamp),
+                    Some(begin_cpu_idle_timestamp),
+                ) = (
+                    status.browser_processes.get(pid),
+                    status.begin_background_timestamps.get(pid),
+                    status.begin_cpu_idle_timestamps.get(pid),
+                ) {
+                    Some(SnapshotTabInfo {
+                        id: tab_info.id,
+                        title: tab_info.title.clone(),
+                        url: tab_info.url.clone(),
+                        pid: pid.as_u32(),
+                        rss: process_info.rss,
+                        audible: tab_info.audible,
+                        foreground: tab_info.active,
+                        cpu_usage: process_info.cpu_usage,
+                        background_time_secs: secs_between(
+                            *begin_background_timestamp,
+                            status.timestamp,
+                        ),
+                        cpu_idle_time_secs: secs_between(
+                            *begin_cpu_idle_timestamp,
+                            status.timestamp,
+                        ),
+                        protected: control_state
+                            .protections
+                            .is_protected(pid.as_u32(), status.timestamp),
+                        next_kill: None,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Snapshot {
+            timestamp: status.timestamp,
+            paused: control_state.paused,
+            tab_infos,
+            available_memory: status.available_memory,
+            process_classes: status.process_class_totals(),
+            cgroup_tiers: status.cgroup_tiers.clone(),
+            resume_timestamp: status.resume_timestamp,
+        }
+    }
+}
@@ -1,64 +1,525 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use sysinfo::{Pid, System};
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+use tracing::{debug, info, warn};
 
 use crate::{
+    browser::ProcessClass,
+    cgroup::CgroupTierStatus,
+    clock::{secs_between, Clock},
     config::Config,
-    tab_data_requester::{TabInfo, Timestamp},
-    tab_killer::Rss,
+    processes::ProcessSource,
+    protocol::{
+        BrowserInnerPid, ConnectionId, Hello, Seq, TabData, TabEvent, TabEventData, TabId, TabInfo,
+        Timestamp,
+    },
+    replay::{Record, Recorder},
+    timers::{browser_start_time, TabKey, TabTimers},
 };
 
-/// App status that must be sharing between threads
+pub type Rss = u64;
+
+/// App status owned by the tab killer thread, other threads read the published `Snapshot` of it
 #[derive(Debug, Default)]
 pub struct Status {
-    pub system: System,
+    // Time of the last update by the clock of the daemon, timestamps of tabs are in this clock too
     pub timestamp: f64,
+    // Inner pids are only unique in a browser, keyed by the browser main process too
+    pub browser_inner_pid_to_pid: HashMap<(Pid, BrowserInnerPid), Pid>,
+    // All processes of the browser, with or without tabs
+    pub browser_processes: HashMap<Pid, ProcessInfo>,
+    // Memory of the system available for starting new applications
+    pub available_memory: u64,
+    // Tabs of all connections which are not stale
     pub tab_infos: HashMap<Pid, TabInfo>,
     pub begin_background_timestamps: HashMap<Pid, Timestamp>,
     pub begin_cpu_idle_timestamps: HashMap<Pid, Timestamp>,
+    // Timers saved before the daemon restarted, of tabs not active since then
+    pub restored_timers: HashMap<TabKey, TabTimers>,
+    // Empty if cgroup is not enabled
+    pub cgroup_tiers: Vec<CgroupTierStatus>,
+    // When the system last resumed from suspend, time limits wait for a grace period after it
+    pub resume_timestamp: Option<Timestamp>,
+    // Browser pids of connections not found in the browser processes, even after a rescan of
+    // browsers, they are not rescanned again
+    pub unknown_browser_pids: HashSet<u32>,
+}
+
+/// What strategies need to know about a browser process, sampled on each tick
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessInfo {
+    // Pid of the parent process
+    pub parent: Option<u32>,
+    // In secs since unix epoch
+    pub start_time: u64,
+    pub rss: Rss,
+    pub cpu_usage: f32,
+    pub class: ProcessClass,
+    // `None` if the process doesn't host tabs
+    pub browser_inner_pid: Option<BrowserInnerPid>,
+}
+
+/// The browser main process which the process belongs to, the process itself if it's the main
+/// process, `None` if it's not a browser process
+pub fn browser_main_pid(browser_processes: &HashMap<Pid, ProcessInfo>, pid: Pid) -> Option<Pid> {
+    let mut pid = pid;
+    let mut process_info = browser_processes.get(&pid)?;
+    while let Some(parent_pid) = process_info.parent.map(Pid::from_u32) {
+        let Some(parent) = browser_processes.get(&parent_pid) else {
+            break;
+        };
+        pid = parent_pid;
+        process_info = parent;
+    }
+    Some(pid)
+}
+
+/// Memory of browser processes of a class, e.g. all gpu processes
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessClassTotal {
+    pub class: String,
+    pub process_count: usize,
+    pub rss: Rss,
+}
+
+/// Tabs reported by each extension connection (browser profile), shared between the connection
+/// handlers and the tab killer thread, which copies them on each tick so it doesn't block handlers
+#[derive(Debug)]
+pub struct Connections {
+    connections: HashMap<ConnectionId, ConnectionTabInfos>,
+    // Tells when snapshot requests time out, the same clock as the tab killer
+    clock: Clock,
+    // Set when a tab is added, removed or moved to another process, browser processes must be rescanned
+    tabs_changed: bool,
+    // Set with `--record`, everything applied is written to it for replay
+    recorder: Option<Recorder>,
+}
+
+/// Tabs of a connection, kept up to date by snapshots and events from extension
+#[derive(Clone, Debug)]
+pub struct ConnectionTabInfos {
+    // A process of the browser the connection comes from, `None` if unknown
+    pub browser_pid: Option<u32>,
+    // Extension version and capabilities, `None` until the handshake is done
+    pub hello: Option<Hello>,
+    pub tab_infos: HashMap<TabId, TabInfo>,
+    // When the background tabs went to background by the browser, active tabs are not in it
+    pub begin_background_timestamps: HashMap<TabId, Timestamp>,
+    pub last_seq: Option<Seq>,
+    // Set when a snapshot is requested but not received yet, by the clock of the daemon
+    pub snapshot_request_timestamp: Option<Timestamp>,
+    // When the snapshot was requested again last, it's requested again once this times out too
+    last_snapshot_request_timestamp: Option<Timestamp>,
+    // Whether it was stale at the last `fresh_connections`, to log when it changes
+    stale: bool,
+}
+
+impl ConnectionTabInfos {
+    /// A snapshot is requested at `timestamp` right after connected
+    fn new(timestamp: Timestamp) -> Self {
+        ConnectionTabInfos {
+            browser_pid: None,
+            hello: None,
+            tab_infos: HashMap::new(),
+            begin_background_timestamps: HashMap::new(),
+            last_seq: None,
+            snapshot_request_timestamp: Some(timestamp),
+            last_snapshot_request_timestamp: Some(timestamp),
+            stale: false,
+        }
+    }
+
+    /// The connection didn't answer the snapshot request for too long, its tabs may be wrong
+    pub fn is_stale(&self, now: Timestamp, config: &Config) -> bool {
+        self.snapshot_request_timestamp.is_some_and(|timestamp| {
+            secs_between(timestamp, now) > config.update_status_timeout().as_secs_f64()
+        })
+    }
+}
+
+impl Connections {
+    pub fn new(recorder: Option<Recorder>, clock: Clock) -> Self {
+        Connections {
+            connections: HashMap::new(),
+            clock,
+            tabs_changed: false,
+            recorder,
+        }
+    }
+
+    /// The extension sends a snapshot right after connected, `browser_pid` is a process of the
+    /// browser it runs in, tabs are only paired with processes of that browser
+    pub fn add_connection(&mut self, connection_id: ConnectionId, browser_pid: Option<u32>) {
+        self.record(|| Record::Connected {
+            connection_id,
+            browser_pid,
+        });
+        self.connections.insert(
+            connection_id,
+            ConnectionTabInfos {
+                browser_pid,
+                ..ConnectionTabInfos::new(self.clock.now())
+            },
+        );
+    }
+
+    /// Remember who is on the other side of the connection
+    pub fn set_hello(&mut self, connection_id: ConnectionId, hello: Hello) {
+        self.connections
+            .entry(connection_id)
+            .or_insert_with(|| ConnectionTabInfos::new(self.clock.now()))
+            .hello = Some(hello);
+    }
+
+    /// Whether the connection finished the handshake
+    pub fn has_hello(&self, connection_id: ConnectionId) -> bool {
+        self.connections
+            .get(&connection_id)
+            .is_some_and(|connection| connection.hello.is_some())
+    }
+
+    /// Forget tabs of the disconnected connection
+    pub fn remove_connection(&mut self, connection_id: ConnectionId) {
+        self.record(|| Record::Disconnected { connection_id });
+        if let Some(connection) = self.connections.remove(&connection_id) {
+            info!(
+                target: "status",
+                "Remove connection {}, forget its {} tabs",
+                connection_id,
+                connection.tab_infos.len()
+            );
+        }
+    }
+
+    /// Replace all tabs of the connection
+    pub fn apply_snapshot(&mut self, connection_id: ConnectionId, tab_data: TabData) {
+        // Urls of private tabs are never written to disk
+        self.record(|| Record::Snapshot {
+            connection_id,
+            tab_data: TabData {
+                seq: tab_data.seq,
+                timestamp: tab_data.timestamp,
+                tab_infos: tab_data
+                    .tab_infos
+                    .iter()
+                    .filter(|tab_info| !tab_info.incognito)
+                    .cloned()
+                    .collect(),
+            },
+        });
+        let connection = self
+            .connections
+            .entry(connection_id)
+            .or_insert_with(|| ConnectionTabInfos::new(self.clock.now()));
+        connection.last_seq = tab_data.seq;
+        connection.snapshot_request_timestamp = None;
+        connection.last_snapshot_request_timestamp = None;
+        self.tabs_changed = true;
+
+        let last_begin_background_timestamps =
+            std::mem::take(&mut connection.begin_background_timestamps);
+        connection.tab_infos = tab_data
+            .tab_infos
+            .into_iter()
+            .map(|tab_info| (tab_info.id, tab_info))
+            .collect();
+        connection.begin_background_timestamps = connection
+            .tab_infos
+            .values()
+            .filter(|tab_info| !tab_info.active)
+            .map(|tab_info| {
+                let begin_background_timestamp =
+                    match last_begin_background_timestamps.get(&tab_info.id) {
+                        Some(&last_begin_background_timestamp) => {
+                            last_begin_background_timestamp.max(tab_info.last_accessed)
+                        }
+                        None => tab_info.last_accessed,
+                    };
+                (tab_info.id, begin_background_timestamp)
+            })
+            .collect();
+    }
+
+    /// Apply a tab event of the connection, return true if events were missed and a snapshot should
+    /// be requested, or the last request timed out and it should be requested again
+    pub fn apply_event(
+        &mut self,
+        connection_id: ConnectionId,
+        tab_event_data: TabEventData,
+        config: &Config,
+    ) -> bool {
+        // Private tabs are left out, the event is kept so the sequence has no gap
+        self.record(|| Record::Event {
+            connection_id,
+            tab_event_data: match &tab_event_data.event {
+                TabEvent::Updated { tab_info } if tab_info.incognito => TabEventData {
+                    event: TabEvent::Unknown,
+                    ..tab_event_data.clone()
+                },
+                _ => tab_event_data.clone(),
+            },
+        });
+        let connection = self
+            .connections
+            .entry(connection_id)
+            .or_insert_with(|| ConnectionTabInfos::new(self.clock.now()));
+
+        let now = self.clock.now();
+        let sequence_gap = connection
+            .last_seq
+            .is_some_and(|last_seq| tab_event_data.seq != last_seq + 1);
+        connection.last_seq = Some(tab_event_data.seq);
+        // The request or its reply may be lost, e.g. the extension reloaded in between
+        let snapshot_request_timed_out =
+            connection
+                .last_snapshot_request_timestamp
+                .is_some_and(|timestamp| {
+                    secs_between(timestamp, now) > config.update_status_timeout().as_secs_f64()
+                });
+        let request_snapshot = (sequence_gap && connection.snapshot_request_timestamp.is_none())
+            || snapshot_request_timed_out;
+        if request_snapshot {
+            // Stale since the first request which is not answered
+            connection.snapshot_request_timestamp.get_or_insert(now);
+            connection.last_snapshot_request_timestamp = Some(now);
+        }
+
+        match tab_event_data.event {
+            TabEvent::Updated { tab_info } => {
+                if tab_info.active {
+                    connection.begin_background_timestamps.remove(&tab_info.id);
+                } else {
+                    let was_active = connection
+                        .tab_infos
+                        .get(&tab_info.id)
+                        .map(|last_tab_info| last_tab_info.active);
+                    let begin_background_timestamp = match was_active {
+                        // Went to background just now
+                        Some(true) => tab_event_data.timestamp,
+                        _ => tab_info.last_accessed,
+                    };
+                    connection
+                        .begin_background_timestamps
+                        .entry(tab_info.id)
+                        .or_insert(begin_background_timestamp);
+                }
+                // New tab or the tab moved to another process
+                let process_changed =
+                    connection
+                        .tab_infos
+                        .get(&tab_info.id)
+                        .is_none_or(|last_tab_info| {
+                            last_tab_info.browser_inner_pid != tab_info.browser_inner_pid
+                        });
+                if process_changed {
+                    self.tabs_changed = true;
+                }
+                connection.tab_infos.insert(tab_info.id, tab_info);
+            }
+            TabEvent::Removed { tab_id } => {
+                self.tabs_changed = true;
+                connection.tab_infos.remove(&tab_id);
+                connection.begin_background_timestamps.remove(&tab_id);
+            }
+            TabEvent::Unknown => (),
+        }
+
+        request_snapshot
+    }
+
+    /// Copy tabs of all connections which are not stale
+    pub fn fresh_connections(&mut self, config: &Config) -> Vec<ConnectionTabInfos> {
+        let now = self.clock.now();
+        let mut fresh_connections = Vec::new();
+        for (connection_id, connection) in &mut self.connections {
+            let stale = connection.is_stale(now, config);
+            if stale != connection.stale {
+                connection.stale = stale;
+                if stale {
+                    warn!(
+                        target: "status",
+                        "Connection {} didn't send a snapshot for {:?}, ignore its {} tabs until it does",
+                        connection_id,
+                        config.update_status_timeout(),
+                        connection.tab_infos.len()
+                    );
+                } else {
+                    info!(target: "status", "Connection {} is fresh again", connection_id);
+                }
+            }
+            if !stale {
+                fresh_connections.push(connection.clone());
+            }
+        }
+        fresh_connections
+    }
+
+    /// Whether tabs changed since the last call
+    pub fn take_tabs_changed(&mut self) -> bool {
+        std::mem::take(&mut self.tabs_changed)
+    }
+
+    fn record(&self, record: impl FnOnce() -> Record) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&record());
+        }
+    }
 }
 
 impl Status {
+    /// Refresh processes, pair tabs of fresh connections with processes and update timers of tabs
+    pub fn refresh(
+        &mut self,
+        processes: &mut dyn ProcessSource,
+        fresh_connections: &[ConnectionTabInfos],
+        tabs_changed: bool,
+        clock: &Clock,
+        config: &Config,
+    ) {
+        self.sample(processes, tabs_changed, config);
+
+        // A browser started later is not under the known browser main processes
+        let unknown_browser_pids: HashSet<u32> = fresh_connections
+            .iter()
+            .filter_map(|connection| connection.browser_pid)
+            .filter(|&browser_pid| {
+                browser_main_pid(&self.browser_processes, Pid::from_u32(browser_pid)).is_none()
+            })
+            .collect();
+        if !unknown_browser_pids.is_subset(&self.unknown_browser_pids) {
+            debug!(
+                target: "status",
+                "Rescan browsers for connections from pids {:?}",
+                unknown_browser_pids
+            );
+            processes.rescan_browsers();
+            self.sample(processes, true, config);
+        }
+        // Those found by the rescan are left out next time
+        self.unknown_browser_pids = unknown_browser_pids
+            .into_iter()
+            .filter(|&browser_pid| {
+                browser_main_pid(&self.browser_processes, Pid::from_u32(browser_pid)).is_none()
+            })
+            .collect();
+
+        self.update(fresh_connections, clock, config);
+    }
+
+    /// Sample browser processes and available memory of the system
+    pub fn sample(
+        &mut self,
+        processes: &mut dyn ProcessSource,
+        tabs_changed: bool,
+        config: &Config,
+    ) {
+        self.browser_processes = processes.browser_processes(tabs_changed, config);
+        self.available_memory = processes.available_memory();
+    }
+
+    /// Pair tabs of fresh connections with sampled browser processes and update timers of tabs,
+    /// `clock` tells the time of the sample and converts timestamps of extension
     pub fn update(
         &mut self,
+        fresh_connections: &[ConnectionTabInfos],
+        clock: &Clock,
         config: &Config,
-        new_tab_infos: HashMap<Pid, TabInfo>,
-        timestamp: Timestamp,
-        browser_name: &String,
     ) {
-        self.tab_infos = new_tab_infos;
-        self.timestamp = timestamp;
+        self.timestamp = clock.now();
+        self.resume_timestamp = clock.resume_timestamp();
+
         // Clear stat if browser closed
-        let browser_process_count = self
-            .system
-            .processes_by_exact_name(browser_name.as_ref())
-            .count();
-        if browser_process_count == 0 {
-            self.tab_infos.clear();
-        }
+        let fresh_connections = if !self.browser_processes.is_empty() {
+            fresh_connections
+        } else {
+            &[]
+        };
 
-        // Update begin_background_timestamps
-        let data_timestamp = self.timestamp;
-        let mut last_access_timestamps: HashMap<Pid, Timestamp> = self
-            .tab_infos
+        // Each connection only sees processes of its own browser
+        let connection_browser_main_pids: Vec<Option<Pid>> = fresh_connections
             .iter()
-            .map(|(&pid, tab_info)| {
-                if tab_info.active {
-                    (pid, data_timestamp)
-                } else {
-                    (pid, tab_info.last_accessed)
-                }
+            .map(|connection| {
+                connection.browser_pid.and_then(|browser_pid| {
+                    browser_main_pid(&self.browser_processes, Pid::from_u32(browser_pid))
+                })
             })
             .collect();
-        last_access_timestamps
-            .iter_mut()
-            .for_each(|(pid, last_accessd_time)| {
-                if let Some(&begin_background_timestamp) = self.begin_background_timestamps.get(pid)
-                {
-                    *last_accessd_time = last_accessd_time.max(begin_background_timestamp);
-                }
+
+        // If all tabs processes are still in the pid map, use the old pid map
+        let pid_map_outdated = fresh_connections
+            .iter()
+            .zip(&connection_browser_main_pids)
+            .flat_map(|(connection, &browser_main_pid)| {
+                connection.tab_infos.values().filter_map(move |tab_info| {
+                    Some((browser_main_pid, tab_info.browser_inner_pid?))
+                })
+            })
+            .any(|(browser_main_pid, browser_inner_pid)| {
+                self.tab_pid(browser_main_pid, browser_inner_pid).is_none()
             });
-        self.begin_background_timestamps = last_access_timestamps;
+        if pid_map_outdated {
+            // Get new pid map
+            debug!(target: "status", "Rebuild pid map of tab processes");
+            self.browser_inner_pid_to_pid = self
+                .browser_processes
+                .iter()
+                .filter_map(|(&pid, process_info)| {
+                    Some((
+                        (
+                            browser_main_pid(&self.browser_processes, pid)?,
+                            process_info.browser_inner_pid?,
+                        ),
+                        pid,
+                    ))
+                })
+                .collect();
+        }
+
+        // Update tab infos and begin_background_timestamps
+        let mut tab_infos = HashMap::<Pid, TabInfo>::new();
+        let mut begin_background_timestamps = HashMap::<Pid, Timestamp>::new();
+        for (connection, &browser_main_pid) in
+            fresh_connections.iter().zip(&connection_browser_main_pids)
+        {
+            for tab_info in connection.tab_infos.values() {
+                // Discarded tabs have no process
+                let Some(browser_inner_pid) = tab_info.browser_inner_pid else {
+                    continue;
+                };
+                let Some(pid) = self.tab_pid(browser_main_pid, browser_inner_pid) else {
+                    debug!(
+                        target: "status",
+                        "Skip tab {} {:?}, no process with browser inner pid {}",
+                        tab_info.id,
+                        tab_info.title,
+                        browser_inner_pid
+                    );
+                    continue;
+                };
+
+                let begin_background_timestamp = if tab_info.active {
+                    self.timestamp
+                } else {
+                    connection
+                        .begin_background_timestamps
+                        .get(&tab_info.id)
+                        .map(|&timestamp| clock.from_browser(timestamp))
+                        .unwrap_or(self.timestamp)
+                };
+                // Tabs sharing a process, it's in background since the last of them
+                begin_background_timestamps
+                    .entry(pid)
+                    .and_modify(|timestamp| *timestamp = timestamp.max(begin_background_timestamp))
+                    .or_insert(begin_background_timestamp);
+                // Don't let a background tab hide the active tab sharing the process
+                if !tab_infos.get(&pid).is_some_and(|tab_info| tab_info.active) {
+                    tab_infos.insert(pid, tab_info.clone());
+                }
+            }
+        }
+        self.tab_infos = tab_infos;
+        self.begin_background_timestamps = begin_background_timestamps;
 
         // Update begin_cpu_idle_timestamps
         let new_begin_cpu_idle_timestamps: HashMap<Pid, Timestamp> = self
@@ -66,8 +527,8 @@ impl Status {
             .keys()
             .filter_map(|&pid| match self.begin_cpu_idle_timestamps.get(&pid) {
                 Some(&old_begin_cpu_idle_timestamp) => {
-                    if let Some(process) = self.system.processes().get(&pid) {
-                        if (process.cpu_usage() as f64)
+                    if let Some(process_info) = self.browser_processes.get(&pid) {
+                        if (process_info.cpu_usage as f64)
                             <= config.strategy.cpu_idle_time_limit.max_idle_cpu_usage
                         {
                             // Still idle
@@ -84,22 +545,412 @@ impl Status {
             })
             .collect();
         self.begin_cpu_idle_timestamps = new_begin_cpu_idle_timestamps;
+
+        self.apply_restored_timers(clock);
     }
 
-    pub fn get_sorted_pid_rss(&self) -> Vec<(Pid, Rss)> {
-        // The background tab processes pid and rss, sorted by rss
-        let mut sorted_pid_rss: Vec<(Pid, u64)> = self
-            .tab_infos
-            .iter()
-            .filter(|(_pid, tab_info)| !tab_info.active)
-            .filter_map(
-                |(&pid, _tab_info)| match self.system.processes().get(&pid) {
-                    Some(process) => Some((pid, process.memory())),
-                    None => None,
+    /// Process hosting a tab by its inner pid in the browser. If the browser is unknown, the
+    /// inner pid must be in only one browser, inner pids of browsers may be the same
+    fn tab_pid(
+        &self,
+        browser_main_pid: Option<Pid>,
+        browser_inner_pid: BrowserInnerPid,
+    ) -> Option<Pid> {
+        let pid = match browser_main_pid {
+            Some(browser_main_pid) => *self
+                .browser_inner_pid_to_pid
+                .get(&(browser_main_pid, browser_inner_pid))?,
+            None => {
+                let mut pids = self
+                    .browser_inner_pid_to_pid
+                    .iter()
+                    .filter(|(&(_, inner_pid), _)| inner_pid == browser_inner_pid)
+                    .map(|(_, &pid)| pid);
+                let pid = pids.next()?;
+                if pids.next().is_some() {
+                    return None;
+                }
+                pid
+            }
+        };
+        self.browser_processes.contains_key(&pid).then_some(pid)
+    }
+
+    /// Timers saved before the daemon restarted are earlier than those started since then, they
+    /// are saved by the wall clock
+    fn apply_restored_timers(&mut self, clock: &Clock) {
+        if self.restored_timers.is_empty() {
+            return;
+        }
+        for (&pid, tab_info) in &self.tab_infos {
+            let Some(browser_start_time) = browser_start_time(&self.browser_processes, pid) else {
+                continue;
+            };
+            let Some(restored_timers) = self
+                .restored_timers
+                .get_mut(&TabKey::new(tab_info, browser_start_time))
+            else {
+                continue;
+            };
+
+            // Background timer of the extension starts over, until the tab is active again
+            if tab_info.active {
+                restored_timers.begin_background_timestamp = None;
+            }
+            if let (Some(restored_timestamp), Some(timestamp)) = (
+                restored_timers.begin_background_timestamp,
+                self.begin_background_timestamps.get_mut(&pid),
+            ) {
+                *timestamp = timestamp.min(clock.from_browser(restored_timestamp));
+            }
+            // Cpu idle timer is kept by status, only needed when the tab is first seen
+            if let (Some(restored_timestamp), Some(timestamp)) = (
+                restored_timers.begin_cpu_idle_timestamp.take(),
+                self.begin_cpu_idle_timestamps.get_mut(&pid),
+            ) {
+                *timestamp = timestamp.min(clock.from_browser(restored_timestamp));
+            }
+        }
+        self.restored_timers.retain(|_, restored_timers| {
+            restored_timers.begin_background_timestamp.is_some()
+                || restored_timers.begin_cpu_idle_timestamp.is_some()
+        });
+    }
+
+    /// Process count and rss of each class of browser processes, sorted by class
+    pub fn process_class_totals(&self) -> Vec<ProcessClassTotal> {
+        let mut totals = BTreeMap::<&ProcessClass, (usize, Rss)>::new();
+        for process_info in self.browser_processes.values() {
+            let (process_count, rss) = totals.entry(&process_info.class).or_default();
+            *process_count += 1;
+            *rss += process_info.rss;
+        }
+        totals
+            .into_iter()
+            .map(|(process_class, (process_count, rss))| ProcessClassTotal {
+                class: process_class.to_string(),
+                process_count,
+                rss,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        clock::add_secs,
+        testing::{process, renderer, FakeProcesses, START},
+    };
+
+    fn config() -> Config {
+        toml::from_str(include_str!("config.toml")).unwrap()
+    }
+
+    fn fresh_connections(tab_infos: Vec<TabInfo>, config: &Config) -> Vec<ConnectionTabInfos> {
+        let mut connections = Connections::new(None, Clock::simulated(START));
+        connections.apply_snapshot(
+            0,
+            TabData {
+                seq: Some(0),
+                timestamp: START,
+                tab_infos,
+            },
+        );
+        connections.fresh_connections(config)
+    }
+
+    #[test]
+    fn tabs_sharing_a_process_are_in_background_since_the_last_of_them() {
+        let config = config();
+        let mut processes = FakeProcesses::default();
+        processes
+            .browser_processes
+            .insert(Pid::from_u32(100), renderer(5));
+        let tab_info = |id, active, last_accessed| TabInfo {
+            id,
+            active,
+            last_accessed,
+            browser_inner_pid: Some(5),
+            ..Default::default()
+        };
+
+        let clock = Clock::simulated(START);
+        let mut status = Status::default();
+        status.sample(&mut processes, true, &config);
+        status.update(
+            &fresh_connections(
+                vec![
+                    tab_info(1, false, START - 2000.0),
+                    tab_info(2, false, START - 1000.0),
+                ],
+                &config,
+            ),
+            &clock,
+            &config,
+        );
+        assert_eq!(
+            status.begin_background_timestamps[&Pid::from_u32(100)],
+            START - 1000.0
+        );
+
+        clock.advance(Duration::from_secs(1));
+        status.update(
+            &fresh_connections(
+                vec![tab_info(1, false, START - 2000.0), tab_info(2, true, START)],
+                &config,
+            ),
+            &clock,
+            &config,
+        );
+        // The active tab is not hidden by the background tab
+        assert_eq!(status.tab_infos[&Pid::from_u32(100)].id, 2);
+        assert_eq!(
+            status.begin_background_timestamps[&Pid::from_u32(100)],
+            START + 1000.0
+        );
+    }
+
+    #[test]
+    fn tabs_are_cleared_once_browser_is_closed() {
+        let config = config();
+        let mut processes = FakeProcesses::default();
+        processes
+            .browser_processes
+            .insert(Pid::from_u32(100), renderer(5));
+        let fresh_connections = fresh_connections(
+            vec![TabInfo {
+                id: 1,
+                browser_inner_pid: Some(5),
+                ..Default::default()
+            }],
+            &config,
+        );
+
+        let clock = Clock::simulated(START);
+        let mut status = Status::default();
+        status.sample(&mut processes, true, &config);
+        status.update(&fresh_connections, &clock, &config);
+        assert_eq!(status.tab_infos.len(), 1);
+
+        processes.browser_processes.clear();
+        clock.advance(Duration::from_secs(1));
+        status.sample(&mut processes, true, &config);
+        status.update(&fresh_connections, &clock, &config);
+        assert!(status.tab_infos.is_empty());
+        assert!(status.begin_cpu_idle_timestamps.is_empty());
+    }
+
+    #[test]
+    fn background_time_goes_on_by_the_monotonic_clock() {
+        let config = config();
+        let mut processes = FakeProcesses::default();
+        processes
+            .browser_processes
+            .insert(Pid::from_u32(100), renderer(5));
+        let fresh_connections = fresh_connections(
+            vec![TabInfo {
+                id: 1,
+                last_accessed: START - 60_000.0,
+                browser_inner_pid: Some(5),
+                ..Default::default()
+            }],
+            &config,
+        );
+
+        let clock = Clock::simulated(START);
+        let mut status = Status::default();
+        status.sample(&mut processes, true, &config);
+        status.update(&fresh_connections, &clock, &config);
+
+        // Suspended for an hour, the monotonic clock only went on by a second
+        clock.suspend(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(1));
+        status.update(&fresh_connections, &clock, &config);
+        assert_eq!(status.timestamp, START + 1000.0);
+        assert_eq!(status.resume_timestamp, Some(START + 1000.0));
+        assert_eq!(
+            status.begin_background_timestamps[&Pid::from_u32(100)],
+            START - 60_000.0
+        );
+        assert_eq!(status.begin_cpu_idle_timestamps[&Pid::from_u32(100)], START);
+    }
+
+    #[test]
+    fn connections_are_paired_with_processes_of_their_own_browser() {
+        let config = config();
+        // Two browsers, each has a renderer with inner pid 5
+        let mut processes = FakeProcesses::default();
+        for browser_pid in [10, 20] {
+            processes.browser_processes.insert(
+                Pid::from_u32(browser_pid),
+                process(None, ProcessClass::Browser, None),
+            );
+            processes.browser_processes.insert(
+                Pid::from_u32(browser_pid + 1),
+                process(Some(browser_pid), ProcessClass::Renderer, Some(5)),
+            );
+        }
+        let tab_info = |id| TabInfo {
+            id,
+            browser_inner_pid: Some(5),
+            ..Default::default()
+        };
+
+        let clock = Clock::simulated(START);
+        let mut connections = Connections::new(None, clock.clone());
+        for (connection_id, browser_pid, tab_id) in
+            [(1, Some(10), 1), (2, Some(20), 2), (3, None, 3)]
+        {
+            connections.add_connection(connection_id, browser_pid);
+            connections.apply_snapshot(
+                connection_id,
+                TabData {
+                    seq: Some(0),
+                    timestamp: START,
+                    tab_infos: vec![tab_info(tab_id)],
+                },
+            );
+        }
+
+        let mut status = Status::default();
+        status.sample(&mut processes, true, &config);
+        status.update(&connections.fresh_connections(&config), &clock, &config);
+        assert_eq!(status.tab_infos.len(), 2);
+        assert_eq!(status.tab_infos[&Pid::from_u32(11)].id, 1);
+        // The tab of the unknown browser may be in either of them, it's left out
+        assert_eq!(status.tab_infos[&Pid::from_u32(21)].id, 2);
+    }
+
+    #[test]
+    fn browsers_are_rescanned_for_connections_from_unknown_browsers() {
+        let config = config();
+        // The browser with pid 20 was started after the one with pid 10
+        let mut processes = FakeProcesses::default();
+        for (browser_pid, browser_processes) in [
+            (10, &mut processes.browser_processes),
+            (20, &mut processes.unscanned_processes),
+        ] {
+            browser_processes.insert(
+                Pid::from_u32(browser_pid),
+                process(None, ProcessClass::Browser, None),
+            );
+            browser_processes.insert(
+                Pid::from_u32(browser_pid + 1),
+                process(Some(browser_pid), ProcessClass::Renderer, Some(5)),
+            );
+        }
+
+        let clock = Clock::simulated(START);
+        let mut connections = Connections::new(None, clock.clone());
+        for (connection_id, browser_pid) in [(1, 10), (2, 20), (3, 30)] {
+            connections.add_connection(connection_id, Some(browser_pid));
+            connections.apply_snapshot(
+                connection_id,
+                TabData {
+                    seq: Some(0),
+                    timestamp: START,
+                    tab_infos: vec![TabInfo {
+                        id: 1,
+                        browser_inner_pid: Some(5),
+                        ..Default::default()
+                    }],
+                },
+            );
+        }
+        let fresh_connections = connections.fresh_connections(&config);
+
+        let mut status = Status::default();
+        status.refresh(&mut processes, &fresh_connections, true, &clock, &config);
+        assert_eq!(processes.browser_rescans, 1);
+        assert_eq!(status.tab_infos.len(), 2);
+        assert!(status.tab_infos.contains_key(&Pid::from_u32(21)));
+
+        // The connection from pid 30 is not from a browser, it isn't rescanned for again
+        clock.advance(Duration::from_secs(1));
+        status.refresh(&mut processes, &fresh_connections, false, &clock, &config);
+        assert_eq!(processes.browser_rescans, 1);
+        assert_eq!(status.unknown_browser_pids, HashSet::from([30]));
+    }
+
+    #[test]
+    fn connections_are_stale_by_the_clock_until_a_snapshot_is_sent() {
+        let config = config();
+        let clock = Clock::simulated(START);
+        let mut connections = Connections::new(None, clock.clone());
+        connections.add_connection(1, None);
+        assert_eq!(connections.fresh_connections(&config).len(), 1);
+
+        // A jump of the wall clock doesn't time out the snapshot request
+        clock.set(add_secs(START, 1.0), add_secs(START, 3600.0));
+        assert_eq!(connections.fresh_connections(&config).len(), 1);
+
+        clock.advance(config.update_status_timeout() + Duration::from_secs(1));
+        assert!(connections.fresh_connections(&config).is_empty());
+
+        connections.apply_snapshot(
+            1,
+            TabData {
+                seq: Some(0),
+                timestamp: START,
+                tab_infos: Vec::new(),
+            },
+        );
+        assert_eq!(connections.fresh_connections(&config).len(), 1);
+    }
+
+    #[test]
+    fn snapshots_are_requested_again_once_the_request_timed_out() {
+        let config = config();
+        let clock = Clock::simulated(START);
+        let mut connections = Connections::new(None, clock.clone());
+        let snapshot = |connections: &mut Connections, seq| {
+            connections.apply_snapshot(
+                1,
+                TabData {
+                    seq: Some(seq),
+                    timestamp: START,
+                    tab_infos: Vec::new(),
                 },
             )
-            .collect();
-        sorted_pid_rss.sort_unstable_by_key(|&(_, rss)| rss);
-        sorted_pid_rss
+        };
+        let event = |connections: &mut Connections, seq| {
+            connections.apply_event(
+                1,
+                TabEventData {
+                    seq,
+                    timestamp: START,
+                    event: TabEvent::Unknown,
+                },
+                &config,
+            )
+        };
+        connections.add_connection(1, None);
+        snapshot(&mut connections, 0);
+        assert!(!event(&mut connections, 1));
+
+        // Events 2 and 3 are missed, only requested once until the request times out
+        assert!(event(&mut connections, 4));
+        assert!(!event(&mut connections, 6));
+        clock.advance(config.update_status_timeout() + Duration::from_secs(1));
+        assert!(connections.fresh_connections(&config).is_empty());
+        assert!(event(&mut connections, 7));
+        assert!(!event(&mut connections, 8));
+
+        // The request timed out again, the connection is stale since the first one
+        clock.advance(config.update_status_timeout() / 2);
+        assert!(!event(&mut connections, 9));
+        assert!(connections.fresh_connections(&config).is_empty());
+        clock.advance(config.update_status_timeout());
+        assert!(event(&mut connections, 10));
+
+        // Recovered by the snapshot, a new gap is requested right away
+        snapshot(&mut connections, 10);
+        assert_eq!(connections.fresh_connections(&config).len(), 1);
+        assert!(!event(&mut connections, 11));
+        assert!(event(&mut connections, 13));
     }
 }
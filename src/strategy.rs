@@ -0,0 +1,619 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+use thousands::Separable;
+use tracing::{debug, info};
+
+use crate::{
+    clock::secs_between,
+    config::{Config, KillTabStrategy},
+    protocol::TabId,
+    snapshot::{NextKill, Snapshot, SnapshotTabInfo},
+    status::Rss,
+};
+
+/// A tab which a strategy decided to kill
+#[derive(Debug)]
+pub struct KillDecision {
+    pub tab_id: TabId,
+    pub pid: u32,
+    pub reason: KillReason,
+}
+
+/// Why a tab is killed, logged with every kill decision
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
+pub enum KillReason {
+    RssLimit { limited_rss: Rss, max_bytes: Rss },
+    BackgroundTimeLimit { background_secs: f64, max_secs: f64 },
+    CpuIdleTimeLimit { idle_secs: f64, max_secs: f64 },
+    // By user through control api
+    Requested,
+}
+
+/// Apply strategies in the order of config, return tabs to kill with the reason of the first
+/// strategy which hits each, nothing while paused
+pub fn evaluate(snapshot: &Snapshot, config: &Config) -> Vec<KillDecision> {
+    if snapshot.paused || snapshot.tab_infos.is_empty() {
+        return Vec::new();
+    }
+    debug!(
+        target: "tab_killer",
+        "Tabs: {:?}",
+        snapshot
+            .tab_infos
+            .iter()
+            .map(|tab_info| (tab_info.pid, tab_info.title.as_str()))
+            .collect::<BTreeMap<_, _>>()
+    );
+    let total_rss: Rss = snapshot.tab_infos.iter().map(|tab_info| tab_info.rss).sum();
+    debug!(target: "tab_killer", "Total rss: {}", total_rss.separate_with_commas());
+    let non_tab_rss = non_tab_rss(snapshot, total_rss);
+    debug!(target: "tab_killer", "Non-tab rss: {}", non_tab_rss.separate_with_commas());
+    // Rss counted toward the rss limit, only tabs can be killed to free it anyway
+    let limited_rss = if config.strategy.rss_limit.include_non_tab_processes {
+        total_rss + non_tab_rss
+    } else {
+        total_rss
+    };
+
+    let resume_grace_secs_left = resume_grace_secs_left(snapshot, config);
+    let mut decisions = Vec::new();
+    for kill_tab_strategy in &config.kill_tab_strategies {
+        match kill_tab_strategy {
+            KillTabStrategy::BackgroundTimeLimit | KillTabStrategy::CpuIdleTimeLimit
+                if resume_grace_secs_left > 0.0 =>
+            {
+                debug!(
+                    target: "tab_killer",
+                    "Skip {} for {:.1}s after resumed from suspend",
+                    kill_tab_strategy.name(),
+                    resume_grace_secs_left
+                );
+            }
+            KillTabStrategy::RssLimit => {
+                decisions.extend(kill_tabs_by_rss_limit(snapshot, config, limited_rss));
+            }
+            KillTabStrategy::BackgroundTimeLimit => {
+                decisions.extend(kill_tabs_by_background_time_limit(snapshot, config));
+            }
+            KillTabStrategy::CpuIdleTimeLimit => {
+                decisions.extend(kill_tabs_by_cpu_idle_time_limit(snapshot, config));
+            }
+        }
+    }
+    // A tab hit by several strategies is killed once
+    let mut decided_pids = HashSet::new();
+    decisions.retain(|decision| decided_pids.insert(decision.pid));
+    decisions
+}
+
+/// Fill `next_kill` of tabs, the strategy expected to kill each tab first, a time limit which is
+/// hit earliest, or rss limit for the largest tab which it kills first once total rss is over the
+/// limit
+pub fn predict_next_kills(snapshot: &mut Snapshot, config: &Config) {
+    for tab_info in &mut snapshot.tab_infos {
+        tab_info.next_kill = None;
+    }
+    let resume_grace_secs_left = resume_grace_secs_left(snapshot, config);
+    let largest_pid = snapshot
+        .tab_infos
+        .iter()
+        .filter(|tab_info| is_killable(tab_info, config) && !is_whitelisted(tab_info, config))
+        .max_by_key(|tab_info| tab_info.rss)
+        .map(|tab_info| tab_info.pid);
+
+    for tab_info in &mut snapshot.tab_infos {
+        if !is_killable(tab_info, config) {
+            continue;
+        }
+        for &kill_tab_strategy in &config.kill_tab_strategies {
+            let secs_left = match kill_tab_strategy {
+                KillTabStrategy::RssLimit if largest_pid == Some(tab_info.pid) => None,
+                KillTabStrategy::RssLimit => continue,
+                KillTabStrategy::BackgroundTimeLimit if tab_info.title != "New Tab" => Some(
+                    (config.strategy.background_time_limit.max_secs
+                        - tab_info.background_time_secs)
+                        .max(resume_grace_secs_left),
+                ),
+                KillTabStrategy::BackgroundTimeLimit => continue,
+                KillTabStrategy::CpuIdleTimeLimit => Some(
+                    (config.strategy.cpu_idle_time_limit.max_secs - tab_info.cpu_idle_time_secs)
+                        .max(resume_grace_secs_left),
+                ),
+            };
+            // A time limit hit earlier wins, rss limit only if no time limit applies
+            let earlier = match &tab_info.next_kill {
+                None => true,
+                Some(next_kill) => secs_left.is_some_and(|secs_left| {
+                    next_kill
+                        .secs_left
+                        .is_none_or(|last_secs_left| secs_left < last_secs_left)
+                }),
+            };
+            if earlier {
+                tab_info.next_kill = Some(NextKill {
+                    strategy: kill_tab_strategy.name().to_string(),
+                    secs_left,
+                });
+            }
+        }
+    }
+}
+
+/// Secs until time limits may kill tabs again after the system resumed from suspend, 0 if they
+/// may. Tabs were not used while suspended, the user may need them soon
+pub fn resume_grace_secs_left(snapshot: &Snapshot, config: &Config) -> f64 {
+    snapshot
+        .resume_timestamp
+        .map(|resume_timestamp| {
+            config.resume_grace_secs - secs_between(resume_timestamp, snapshot.timestamp)
+        })
+        .unwrap_or_default()
+        .max(0.0)
+}
+
+/// Tabs that background time limit kills, if they have been in background longer than `secs`
+pub fn background_tabs_longer_than<'a>(
+    snapshot: &'a Snapshot,
+    config: &Config,
+    secs: f64,
+) -> Vec<&'a SnapshotTabInfo> {
+    snapshot
+        .tab_infos
+        .iter()
+        // Only left those tabs which in background too long
+        .filter(|tab_info| tab_info.background_time_secs > secs.max(0.0))
+        // Don't kill new tab
+        .filter(|tab_info| tab_info.title != "New Tab")
+        .filter(|tab_info| is_killable(tab_info, config))
+        .collect()
+}
+
+/// Total rss of browser processes which host no tab
+fn non_tab_rss(snapshot: &Snapshot, total_rss: Rss) -> Rss {
+    snapshot
+        .process_classes
+        .iter()
+        .map(|process_class_total| process_class_total.rss)
+        .sum::<Rss>()
+        .saturating_sub(total_rss)
+}
+
+fn kill_tabs_by_rss_limit(
+    snapshot: &Snapshot,
+    config: &Config,
+    total_rss: Rss,
+) -> Vec<KillDecision> {
+    let max_bytes = config.strategy.rss_limit.max_bytes;
+    if total_rss <= max_bytes {
+        return Vec::new();
+    }
+    info!(
+        target: "tab_killer",
+        "Hit the rss limit({}/{}), apply RssLimit strategy",
+        total_rss.separate_with_commas(),
+        max_bytes.separate_with_commas()
+    );
+
+    // The background tabs, the largest first
+    let mut killable_tab_infos: Vec<&SnapshotTabInfo> = snapshot
+        .tab_infos
+        .iter()
+        .filter(|tab_info| is_killable(tab_info, config))
+        .collect();
+    killable_tab_infos.sort_unstable_by_key(|tab_info| std::cmp::Reverse(tab_info.rss));
+
+    // Kill until the freed rss covers what exceeds the limit
+    let exceed_rss = total_rss - max_bytes;
+    let mut expected_freed_rss = 0;
+    let mut decisions = Vec::new();
+    for tab_info in killable_tab_infos {
+        if exceed_rss < expected_freed_rss {
+            break;
+        }
+        if is_whitelisted(tab_info, config) {
+            continue;
+        }
+        expected_freed_rss += tab_info.rss;
+        decisions.push(KillDecision {
+            tab_id: tab_info.id,
+            pid: tab_info.pid,
+            reason: KillReason::RssLimit {
+                limited_rss: total_rss,
+                max_bytes,
+            },
+        });
+    }
+    decisions
+}
+
+/// Will not kill new tab, because the last_access_time is wrong
+fn kill_tabs_by_background_time_limit(snapshot: &Snapshot, config: &Config) -> Vec<KillDecision> {
+    let max_secs = config.strategy.background_time_limit.max_secs;
+    background_tabs_longer_than(snapshot, config, max_secs)
+        .into_iter()
+        .map(|tab_info| KillDecision {
+            tab_id: tab_info.id,
+            pid: tab_info.pid,
+            reason: KillReason::BackgroundTimeLimit {
+                background_secs: tab_info.background_time_secs,
+                max_secs,
+            },
+        })
+        .collect()
+}
+
+fn kill_tabs_by_cpu_idle_time_limit(snapshot: &Snapshot, config: &Config) -> Vec<KillDecision> {
+    let max_secs = config.strategy.cpu_idle_time_limit.max_secs;
+    snapshot
+        .tab_infos
+        .iter()
+        // Only left those tabs which cpu idle too long
+        .filter(|tab_info| tab_info.cpu_idle_time_secs > max_secs)
+        .filter(|tab_info| is_killable(tab_info, config))
+        .map(|tab_info| KillDecision {
+            tab_id: tab_info.id,
+            pid: tab_info.pid,
+            reason: KillReason::CpuIdleTimeLimit {
+                idle_secs: tab_info.cpu_idle_time_secs,
+                max_secs,
+            },
+        })
+        .collect()
+}
+
+/// Strategies don't kill active tabs, audible tabs if whitelisted, or tabs kept by user
+fn is_killable(tab_info: &SnapshotTabInfo, config: &Config) -> bool {
+    let audible = config.whitelist_audible_tab && tab_info.audible;
+    !(tab_info.foreground || audible || tab_info.protected)
+}
+
+/// Only rss limit skips tabs whose url matches the whitelist
+fn is_whitelisted(tab_info: &SnapshotTabInfo, config: &Config) -> bool {
+    config
+        .whitelist
+        .iter()
+        .any(|regex| regex.is_match(&tab_info.url))
+}
+
+impl KillReason {
+    /// Name of the strategy in config, "requested" if killed by user
+    pub fn strategy(&self) -> &'static str {
+        match self {
+            KillReason::RssLimit { .. } => "rss_limit",
+            KillReason::BackgroundTimeLimit { .. } => "background_time_limit",
+            KillReason::CpuIdleTimeLimit { .. } => "cpu_idle_time_limit",
+            KillReason::Requested => "requested",
+        }
+    }
+}
+
+impl fmt::Display for KillReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KillReason::RssLimit {
+                limited_rss,
+                max_bytes,
+            } => write!(
+                f,
+                "rss {} exceeds limit {}",
+                limited_rss.separate_with_commas(),
+                max_bytes.separate_with_commas()
+            ),
+            KillReason::BackgroundTimeLimit {
+                background_secs,
+                max_secs,
+            } => write!(
+                f,
+                "in background for {:.1}s, limit {:.1}s",
+                background_secs, max_secs
+            ),
+            KillReason::CpuIdleTimeLimit {
+                idle_secs,
+                max_secs,
+            } => write!(f, "cpu idle for {:.1}s, limit {:.1}s", idle_secs, max_secs),
+            KillReason::Requested => write!(f, "requested by user"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+    use crate::{
+        clock::add_secs,
+        control::ControlState,
+        protection::Protections,
+        protocol::TabInfo,
+        testing::{tab, tab_pid, FakeBrowser, MB, START},
+    };
+
+    /// The default config with only `strategies`
+    fn config(strategies: &[KillTabStrategy]) -> Config {
+        let mut config: Config = toml::from_str(include_str!("config.toml")).unwrap();
+        config.kill_tab_strategies = strategies.to_vec();
+        config
+    }
+
+    #[test]
+    fn rss_limit_kills_largest_background_tabs_until_under_limit() {
+        let mut config = config(&[KillTabStrategy::RssLimit]);
+        let browser = || {
+            FakeBrowser::new()
+                .with_tab(tab(1, "Large"), 300 * MB, 0.0)
+                .with_tab(tab(2, "Medium"), 200 * MB, 0.0)
+                .with_tab(tab(3, "Small"), 100 * MB, 0.0)
+                .with_tab(
+                    TabInfo {
+                        active: true,
+                        ..tab(4, "Active")
+                    },
+                    500 * MB,
+                    0.0,
+                )
+        };
+
+        config.strategy.rss_limit.max_bytes = 1200 * MB;
+        assert!(browser()
+            .kill_after(1.0, &config, &Protections::default())
+            .is_empty());
+        config.strategy.rss_limit.max_bytes = 900 * MB;
+        assert_eq!(
+            browser().kill_after(1.0, &config, &Protections::default()),
+            [1]
+        );
+        config.strategy.rss_limit.max_bytes = 750 * MB;
+        assert_eq!(
+            browser().kill_after(1.0, &config, &Protections::default()),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn rss_limit_counts_non_tab_processes_if_enabled() {
+        let mut config = config(&[KillTabStrategy::RssLimit]);
+        config.strategy.rss_limit.max_bytes = 320 * MB;
+        let browser = || FakeBrowser::new().with_tab(tab(1, "Tab"), 300 * MB, 0.0);
+
+        assert!(browser()
+            .kill_after(1.0, &config, &Protections::default())
+            .is_empty());
+        config.strategy.rss_limit.include_non_tab_processes = true;
+        assert_eq!(
+            browser().kill_after(1.0, &config, &Protections::default()),
+            [1]
+        );
+    }
+
+    #[test]
+    fn rss_limit_skips_whitelisted_urls() {
+        let mut config = config(&[KillTabStrategy::RssLimit]);
+        config.strategy.rss_limit.max_bytes = 400 * MB;
+        config.whitelist = vec![Regex::new(r"^https://docs\.rs/").unwrap()];
+        let mut browser = FakeBrowser::new()
+            .with_tab(
+                TabInfo {
+                    url: "https://docs.rs/regex".to_string(),
+                    ..tab(1, "Docs")
+                },
+                300 * MB,
+                0.0,
+            )
+            .with_tab(tab(2, "Other"), 200 * MB, 0.0);
+
+        assert_eq!(
+            browser.kill_after(1.0, &config, &Protections::default()),
+            [2]
+        );
+    }
+
+    #[test]
+    fn background_time_limit_kills_tabs_in_background_too_long() {
+        let config = config(&[KillTabStrategy::BackgroundTimeLimit]);
+        let browser = || {
+            FakeBrowser::new()
+                .with_tab(tab(1, "Background"), 100 * MB, 0.0)
+                .with_tab(
+                    TabInfo {
+                        active: true,
+                        ..tab(2, "Active")
+                    },
+                    100 * MB,
+                    0.0,
+                )
+        };
+
+        assert!(browser()
+            .kill_after(59.0, &config, &Protections::default())
+            .is_empty());
+        assert_eq!(
+            browser().kill_after(61.0, &config, &Protections::default()),
+            [1]
+        );
+    }
+
+    #[test]
+    fn background_time_limit_spares_new_tab() {
+        let config = config(&[KillTabStrategy::BackgroundTimeLimit]);
+        let mut browser = FakeBrowser::new().with_tab(tab(1, "New Tab"), 100 * MB, 0.0);
+
+        assert!(browser
+            .kill_after(61.0, &config, &Protections::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn cpu_idle_time_limit_kills_idle_background_tabs() {
+        let config = config(&[KillTabStrategy::CpuIdleTimeLimit]);
+        let mut browser = FakeBrowser::new()
+            .with_tab(tab(1, "Idle"), 100 * MB, 0.0)
+            .with_tab(tab(2, "Busy"), 100 * MB, 50.0)
+            .with_tab(
+                TabInfo {
+                    active: true,
+                    ..tab(3, "Active")
+                },
+                100 * MB,
+                0.0,
+            )
+            .with_tab(tab(4, "New Tab"), 100 * MB, 0.0);
+
+        // Unlike background time limit, "New Tab" is not spared
+        assert_eq!(
+            browser.kill_after(61.0, &config, &Protections::default()),
+            [1, 4]
+        );
+    }
+
+    #[test]
+    fn audible_tabs_are_spared_if_whitelisted() {
+        let mut config = config(&[
+            KillTabStrategy::RssLimit,
+            KillTabStrategy::BackgroundTimeLimit,
+            KillTabStrategy::CpuIdleTimeLimit,
+        ]);
+        config.strategy.rss_limit.max_bytes = 50 * MB;
+        let browser = || {
+            FakeBrowser::new().with_tab(
+                TabInfo {
+                    audible: true,
+                    ..tab(1, "Music")
+                },
+                100 * MB,
+                0.0,
+            )
+        };
+
+        assert!(browser()
+            .kill_after(61.0, &config, &Protections::default())
+            .is_empty());
+        config.whitelist_audible_tab = false;
+        assert_eq!(
+            browser().kill_after(61.0, &config, &Protections::default()),
+            [1]
+        );
+    }
+
+    #[test]
+    fn protected_tabs_are_spared_until_expired() {
+        let config = config(&[KillTabStrategy::BackgroundTimeLimit]);
+        let browser = || FakeBrowser::new().with_tab(tab(1, "Kept"), 100 * MB, 0.0);
+
+        let mut protections = Protections::default();
+        protections.protect(tab_pid(1).as_u32(), None);
+        assert!(browser().kill_after(61.0, &config, &protections).is_empty());
+        protections.protect(tab_pid(1).as_u32(), Some(START + 30_000.0));
+        assert_eq!(browser().kill_after(61.0, &config, &protections), [1]);
+    }
+
+    #[test]
+    fn tab_hit_by_several_strategies_is_killed_once() {
+        let config = config(&[
+            KillTabStrategy::BackgroundTimeLimit,
+            KillTabStrategy::CpuIdleTimeLimit,
+        ]);
+        let mut browser = FakeBrowser::new().with_tab(tab(1, "Idle"), 100 * MB, 0.0);
+
+        let status = browser.status_after(61.0, &config);
+        let decisions = evaluate(&Snapshot::new(&status, &ControlState::default()), &config);
+        assert_eq!(decisions.len(), 1);
+        // The first strategy in config
+        assert_eq!(decisions[0].reason.strategy(), "background_time_limit");
+    }
+
+    #[test]
+    fn nothing_is_killed_while_paused() {
+        let config = config(&[KillTabStrategy::BackgroundTimeLimit]);
+        let mut browser = FakeBrowser::new().with_tab(tab(1, "Background"), 100 * MB, 0.0);
+
+        let status = browser.status_after(61.0, &config);
+        let control_state = ControlState {
+            paused: true,
+            ..Default::default()
+        };
+        assert!(evaluate(&Snapshot::new(&status, &control_state), &config).is_empty());
+    }
+
+    #[test]
+    fn time_limits_wait_for_grace_period_after_resume() {
+        let config = config(&[
+            KillTabStrategy::BackgroundTimeLimit,
+            KillTabStrategy::CpuIdleTimeLimit,
+        ]);
+        let status = FakeBrowser::new()
+            .with_tab(tab(1, "Background"), 100 * MB, 0.0)
+            .status_after(61.0, &config);
+        let mut snapshot = Snapshot::new(&status, &ControlState::default());
+        // Resumed from suspend 10s ago, grace period is 60s
+        snapshot.resume_timestamp = Some(add_secs(snapshot.timestamp, -10.0));
+        assert!(evaluate(&snapshot, &config).is_empty());
+        predict_next_kills(&mut snapshot, &config);
+        assert_eq!(
+            snapshot.tab_infos[0]
+                .next_kill
+                .as_ref()
+                .and_then(|next_kill| next_kill.secs_left),
+            Some(50.0)
+        );
+
+        snapshot.resume_timestamp = Some(add_secs(snapshot.timestamp, -60.0));
+        let decisions = evaluate(&snapshot, &config);
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].reason.strategy(), "background_time_limit");
+    }
+
+    #[test]
+    fn next_kill_is_the_earliest_time_limit() {
+        let mut config = config(&[
+            KillTabStrategy::RssLimit,
+            KillTabStrategy::BackgroundTimeLimit,
+            KillTabStrategy::CpuIdleTimeLimit,
+        ]);
+        config.strategy.cpu_idle_time_limit.max_secs = 55.0;
+        let mut browser = FakeBrowser::new()
+            .with_tab(tab(1, "Idle"), 300 * MB, 0.0)
+            .with_tab(tab(2, "Busy"), 200 * MB, 50.0)
+            .with_tab(tab(3, "New Tab"), 100 * MB, 50.0)
+            .with_tab(
+                TabInfo {
+                    active: true,
+                    ..tab(4, "Active")
+                },
+                100 * MB,
+                0.0,
+            );
+
+        let status = browser.status_after(10.0, &config);
+        let mut snapshot = Snapshot::new(&status, &ControlState::default());
+        predict_next_kills(&mut snapshot, &config);
+        let next_kill = |tab_id| {
+            snapshot
+                .tab_infos
+                .iter()
+                .find(|tab_info| tab_info.id == tab_id)
+                .unwrap()
+                .next_kill
+                .clone()
+                .map(|next_kill| (next_kill.strategy, next_kill.secs_left))
+        };
+        assert_eq!(
+            next_kill(1),
+            Some(("cpu_idle_time_limit".to_string(), Some(45.0)))
+        );
+        // Cpu idle timer of a busy tab starts over on each tick
+        assert_eq!(
+            next_kill(2),
+            Some(("background_time_limit".to_string(), Some(50.0)))
+        );
+        assert_eq!(
+            next_kill(3),
+            Some(("cpu_idle_time_limit".to_string(), Some(55.0)))
+        );
+        assert_eq!(next_kill(4), None);
+    }
+}
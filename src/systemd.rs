@@ -0,0 +1,229 @@
+use std::{
+    env, fs, io,
+    net::TcpListener,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::{config::Config, PROJECT_NAME};
+
+/// The first file descriptor passed by socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Names of the activated sockets, matched with "FileDescriptorName=" or the order of "ListenStream="
+pub const WEBSOCKET_SOCKET_NAME: &str = "websocket";
+pub const HTTP_SOCKET_NAME: &str = "http";
+
+/// Where systemd listens for states, `None` if not started by systemd with `Type=notify`
+pub fn notify_socket() -> Option<String> {
+    env::var_os("NOTIFY_SOCKET").map(|notify_socket| notify_socket.to_string_lossy().into_owned())
+}
+
+/// Send a state (e.g. "READY=1") to the socket of `notify_socket`, do nothing if `None`
+pub fn notify(notify_socket: Option<&str>, state: &str) {
+    let Some(notify_socket) = notify_socket else {
+        return;
+    };
+    let result = (|| -> io::Result<()> {
+        // "@" means an abstract socket
+        let addr = match notify_socket.strip_prefix('@') {
+            Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name)?,
+            None => SocketAddr::from_pathname(notify_socket)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        warn!(target: "systemd", "Failed to notify systemd {:?}: {}", notify_socket, e);
+    }
+}
+
+/// How often systemd expects "WATCHDOG=1", `None` if watchdog is not enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(watchdog_pid) = env::var_os("WATCHDOG_PID") {
+        if watchdog_pid.to_string_lossy() != std::process::id().to_string() {
+            return None;
+        }
+    }
+    let watchdog_usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(watchdog_usec))
+}
+
+/// Listening sockets passed by systemd socket activation with their names,
+/// environment variables are removed so child processes don't take them
+pub fn take_listeners() -> Vec<(String, TcpListener)> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    let listen_fdnames = env::var("LISTEN_FDNAMES").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+
+    if listen_pid != Some(std::process::id().to_string()) {
+        return Vec::new();
+    }
+    let Some(listen_fds) = listen_fds.and_then(|listen_fds| listen_fds.parse::<RawFd>().ok())
+    else {
+        return Vec::new();
+    };
+    let listen_fdnames: Vec<String> = listen_fdnames
+        .map(|listen_fdnames| listen_fdnames.split(':').map(str::to_string).collect())
+        .unwrap_or_default();
+    // Without "FileDescriptorName=", names are the socket unit name, fallback to the order
+    let default_names = [WEBSOCKET_SOCKET_NAME, HTTP_SOCKET_NAME];
+    let named = listen_fdnames
+        .iter()
+        .any(|name| default_names.contains(&name.as_str()));
+
+    (0..listen_fds)
+        .map(|index| {
+            let fd = SD_LISTEN_FDS_START + index;
+            // SAFETY: Only setting a flag on an fd passed by systemd
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            // SAFETY: systemd passes these fds to this process, nothing else owns them
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let name = if named {
+                listen_fdnames
+                    .get(index as usize)
+                    .cloned()
+                    .unwrap_or_default()
+            } else {
+                default_names
+                    .get(index as usize)
+                    .map(|name| name.to_string())
+                    .unwrap_or_default()
+            };
+            (name, TcpListener::from(fd))
+        })
+        .collect()
+}
+
+/// Take the activated socket with `name`, bind `addr` if there isn't
+pub fn listen(
+    listeners: &mut Vec<(String, TcpListener)>,
+    name: &str,
+    addr: &str,
+) -> io::Result<TcpListener> {
+    match listeners
+        .iter()
+        .position(|(listener_name, _)| listener_name == name)
+    {
+        Some(index) => Ok(listeners.remove(index).1),
+        None => TcpListener::bind(addr),
+    }
+}
+
+/// Write the user unit files, return their paths
+pub fn install_systemd_unit(
+    config: &Config,
+    target_dir: Option<PathBuf>,
+    socket_activation: bool,
+    websocket_addr: &str,
+    http_addr: &str,
+) -> io::Result<Vec<PathBuf>> {
+    let target_dir = match target_dir {
+        Some(target_dir) => target_dir,
+        // "~/.config/systemd/user"
+        None => dirs::config_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config dir"))?
+            .join("systemd")
+            .join("user"),
+    };
+    fs::create_dir_all(&target_dir)?;
+
+    // Several missed ticks before systemd restarts the service
+    let watchdog_secs = (config.check_interval_secs * 10.0).ceil().max(10.0);
+    let service = format!(
+        "[Unit]
+Description=Kill browser tabs by memory usage
+Documentation=https://github.com/cpprust/tab-memory-manager
+
+[Service]
+Type=notify
+ExecStart={exe}
+Restart=on-failure
+WatchdogSec={watchdog_secs}
+
+[Install]
+WantedBy=default.target
+",
+        exe = env::current_exe()?.display(),
+    );
+    let service_path = target_dir.join(format!("{PROJECT_NAME}.service"));
+    fs::write(&service_path, service)?;
+    let mut unit_paths = vec![service_path];
+
+    if socket_activation {
+        // The order of sockets is how they are told apart
+        let socket = format!(
+            "[Unit]
+Description=Sockets of {PROJECT_NAME}
+
+[Socket]
+ListenStream={websocket_addr}
+ListenStream={http_addr}
+
+[Install]
+WantedBy=sockets.target
+"
+        );
+        let socket_path = target_dir.join(format!("{PROJECT_NAME}.socket"));
+        fs::write(&socket_path, socket)?;
+        unit_paths.push(socket_path);
+    }
+
+    Ok(unit_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every datagram received until the socket has no more
+    fn receive_all(socket: &UnixDatagram) -> Vec<String> {
+        socket.set_nonblocking(true).unwrap();
+        let mut buf = [0; 256];
+        let mut states = Vec::new();
+        while let Ok(len) = socket.recv(&mut buf) {
+            states.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        states
+    }
+
+    #[test]
+    fn states_are_sent_to_the_notify_socket() {
+        let dir = std::env::temp_dir().join(format!("systemd-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let notify_socket = path.to_str().unwrap();
+
+        notify(Some(notify_socket), "READY=1");
+        notify(Some(notify_socket), "STATUS=Tabs: 1\nWATCHDOG=1");
+        notify(None, "RELOADING=1");
+        notify(Some(notify_socket), "STOPPING=1");
+        assert_eq!(
+            receive_all(&socket),
+            ["READY=1", "STATUS=Tabs: 1\nWATCHDOG=1", "STOPPING=1"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn states_are_sent_to_an_abstract_notify_socket() {
+        let name = format!("tab-memory-manager-test-{}", std::process::id());
+        let socket =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+
+        notify(Some(&format!("@{name}")), "READY=1");
+        assert_eq!(receive_all(&socket), ["READY=1"]);
+    }
+}
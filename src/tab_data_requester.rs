@@ -1,202 +1,434 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{
-        mpsc::{Receiver, SyncSender},
-        Arc, Mutex,
-    },
-    thread::{spawn, JoinHandle},
+    collections::HashMap,
+    env, fs, io,
+    net::{Shutdown as SocketShutdown, TcpListener, TcpStream},
+    os::{fd::AsFd, unix::fs::MetadataExt},
+    sync::{Arc, Mutex},
+    thread::{scope, spawn, JoinHandle},
 };
 
-use debug_print::debug_println;
-use serde::{Deserialize, Serialize};
-use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate};
-use ws::{listen, Message};
-
-use crate::{config::Config, status::Status};
-
-pub type BrowserInnerPid = u64;
-pub type Timestamp = f64;
-
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct TabData {
-    timestamp: Timestamp,
-    tab_infos: Vec<TabInfo>,
-}
+use tracing::{debug, error, info, trace, warn};
+use tungstenite::{
+    accept_hdr,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::{HeaderValue, StatusCode},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct TabInfo {
-    pub active: bool,
-    pub audible: bool,
-    pub auto_discardable: bool,
-    pub discarded: bool,
-    pub fav_icon_url: Option<String>,
-    pub group_id: i32,
-    pub height: u32,
-    pub highlighted: bool,
-    pub id: u64,
-    pub incognito: bool,
-    pub index: u32,
-    pub last_accessed: Timestamp,
-    pub muted_info: MutedInfo,
-    pub pinned: bool,
-    pub selected: bool,
-    pub status: String,
-    pub title: String,
-    pub url: String,
-    pub width: u32,
-    pub window_id: usize,
-    pub browser_inner_pid: BrowserInnerPid,
-}
+use crate::{
+    config::Config,
+    protocol::{parse_extension_message, Command, ConnectionId, DaemonMessage, ExtensionMessage},
+    shutdown::Shutdown,
+    status::Connections,
+    token::token_matches,
+};
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct MutedInfo {
-    pub muted: bool,
-}
+pub const WEBSOCKET_ADDR: &str = "127.0.0.1:60000";
 
 pub fn spawn_tab_data_requester(
-    status: Arc<Mutex<Status>>,
+    connections: Arc<Mutex<Connections>>,
     config: Config,
-    update_req_reciever: Receiver<()>,
-    update_result_sender: SyncSender<Result<(), String>>,
-    browser_name: String,
+    token: String,
+    listener: TcpListener,
+    shutdown: Arc<Shutdown>,
 ) -> JoinHandle<()> {
     spawn(move || {
+        let _shutdown_guard = shutdown.guard();
         request_tab_data_from_browser_and_update_status(
-            status,
+            connections,
             &config,
-            update_req_reciever,
-            update_result_sender,
-            &browser_name,
+            &token,
+            listener,
+            &shutdown,
         )
     })
 }
 
+/// Accept websocket connections until shutdown, each connection is handled in its own thread
 fn request_tab_data_from_browser_and_update_status(
-    status: Arc<Mutex<Status>>,
+    connections: Arc<Mutex<Connections>>,
     config: &Config,
-    update_req_reciever: Receiver<()>,
-    update_result_sender: SyncSender<Result<(), String>>,
-    browser_name: &String,
+    token: &str,
+    listener: TcpListener,
+    shutdown: &Shutdown,
 ) {
-    let update_req_reciever = Arc::new(Mutex::new(update_req_reciever));
-    listen("127.0.0.1:60000", move |ws_msg_sender| {
-        debug_println!("New sender: {:?}", ws_msg_sender);
-        let update_req_reciever = update_req_reciever.clone();
-        spawn(move || {
-            while let Ok(_) = update_req_reciever.lock().unwrap().recv() {
-                debug_println!("Requesting tab data from browser extension");
-                // Send arbitrary data to tell extension to send json back
-                ws_msg_sender.broadcast(Message::binary(b"")).unwrap();
-                debug_println!("Finish requesting tab data from browser extension");
+    if config.websocket.extension_ids.is_empty() {
+        warn!(
+            target: "tab_data_requester",
+            "No websocket.extension_ids in config, connections from extensions are rejected"
+        );
+    }
+    if let Err(e) = listener.set_nonblocking(true) {
+        warn!(target: "tab_data_requester", "Failed to set websocket listener non-blocking: {}", e);
+    }
+
+    // Streams of open connections, shut down to unblock their threads on shutdown
+    let streams = Mutex::new(HashMap::<ConnectionId, TcpStream>::new());
+    let mut next_connection_id: ConnectionId = 1;
+    scope(|scope| {
+        loop {
+            match shutdown.wait_readable(listener.as_fd()) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => {
+                    error!(
+                        target: "tab_data_requester",
+                        "Failed to wait for websocket connection: {}",
+                        e
+                    );
+                    break;
+                }
             }
-        });
-        let status = Arc::clone(&status);
-        let update_result_sender = update_result_sender.clone();
-        move |ws_msg| {
-            debug_println!("Recieved a ws_msg!");
-            if let Message::Text(msg) = ws_msg {
-                match serde_json::from_str::<TabData>(&msg) {
-                    Ok(input_tab_data) => {
-                        let status = &mut status.lock().unwrap();
-                        // status.system.refresh_all();
-                        status.system.refresh_processes_specifics(ProcessesToUpdate::All, true, ProcessRefreshKind::everything());
-                        // status.system.refresh_processes(ProcessesToUpdate::All, true);
-                        status.system.refresh_memory();
-                        status.system.refresh_cpu_all();
-
-                        // If given tab infos inner pid are the same as last time, use the old pid map
-                        let last_loop_browser_inner_pids: HashSet<BrowserInnerPid> = status.tab_infos.values().map(|tab_info| tab_info.browser_inner_pid).collect();
-                        let same_tabs_as_last_update = input_tab_data.tab_infos.iter().all(|recieved_tab_info| last_loop_browser_inner_pids.contains(&recieved_tab_info.browser_inner_pid)) && input_tab_data.tab_infos.len() == last_loop_browser_inner_pids.len();
-                        let browser_inner_pid_to_pid: HashMap<BrowserInnerPid, Pid> = if same_tabs_as_last_update {
-                             status.tab_infos.iter().map(|(pid, tab_info)| (tab_info.browser_inner_pid, *pid)).collect()
-                        } else {
-                            // Get new pid map
-                            let mut browser_inner_pid_to_pid: HashMap<BrowserInnerPid, Pid> =
-                                HashMap::new();
-                            for process in status.system.processes_by_exact_name(browser_name.as_ref()) {
-                                let cmdline = match process.cmd().first() {
-                                    Some(cmdline) => cmdline,
-                                    None => {
-                                        eprintln!("Process {} cmdline is empty!", process.pid());
-                                        continue;
-                                    }
-                                };
-                                let cmdline = match cmdline.to_str() {
-                                    Some(cmdline) => cmdline,
-                                    None => {
-                                        eprintln!(
-                                            "Process {} cmdline have invalid UTF-8 data: {:?}",
-                                            process.pid(),
-                                            cmdline
-                                        );
-                                        continue;
-                                    }
-                                };
-                                let target_arg = cmdline
-                                    .split_whitespace()
-                                    .filter(|arg| arg.starts_with("--renderer-client-id="))
-                                    .next();
-                                let target_arg = match target_arg {
-                                    Some(target_arg) => target_arg,
-                                    None => {
-                                        // No target flag in this cmdline, skipped
-                                        continue;
-                                    }
-                                };
-                                let browser_inner_pid = target_arg
-                                    .split('=')
-                                    .nth(1);
-                                let browser_inner_pid = match browser_inner_pid {
-                                    Some(browser_inner_pid) => {
-                                        browser_inner_pid.parse::<BrowserInnerPid>()
-                                    }
-                                    None => {
-                                        eprintln!("Process {}, no number after arg \"renderer-client-id=\", cmdline: {}", process.pid(), cmdline);
-                                        continue;
-                                    },
-                                };
-                                let browser_inner_pid = match browser_inner_pid {
-                                    Ok(browser_inner_pid) => browser_inner_pid,
-                                    Err(e) => {
-                                        eprintln!("Cannot find pid from cmdline arg: {}", e);
-                                        continue;
-                                    }
-                                };
-                                browser_inner_pid_to_pid.insert(browser_inner_pid, process.pid());
-                            }
-                            browser_inner_pid_to_pid
-                        };
-
-                        // Update tab infos
-                        let mut new_tab_infos = HashMap::<Pid, TabInfo>::new();
-                        for tab_info in input_tab_data.tab_infos {
-                            match browser_inner_pid_to_pid.get(&tab_info.browser_inner_pid) {
-                                Some(&pid) => {
-                                    if status.system.processes().contains_key(&pid) {
-                                        new_tab_infos.insert(pid, tab_info);
-                                    }
-                                },
-                                None => continue,
-                            };
-                        }
-                        status.update(config, new_tab_infos, input_tab_data.timestamp,browser_name);
-                        let _ = update_result_sender.try_send(Ok(()));
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to parse json: {e}\nError data: {msg}");
-                        if let Err(e) = update_result_sender.try_send(Err(format!("Failed to parse json: {e}\nError data: {msg}"))) {
-                            eprintln!("Failed to send status update result: {}", e);
-                        }
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    warn!(
+                        target: "tab_data_requester",
+                        "Failed to accept websocket connection: {}",
+                        e
+                    );
+                    continue;
+                }
+            };
+            let connection_id = next_connection_id;
+            next_connection_id += 1;
+            match stream.try_clone() {
+                Ok(stream) => {
+                    streams.lock().unwrap().insert(connection_id, stream);
+                }
+                Err(e) => {
+                    warn!(target: "tab_data_requester", "Failed to clone websocket stream: {}", e);
+                    continue;
+                }
+            }
+
+            let connections = &connections;
+            let streams = &streams;
+            scope.spawn(move || {
+                handle_connection(stream, connection_id, connections, config, token);
+                streams.lock().unwrap().remove(&connection_id);
+            });
+        }
+
+        for stream in streams.lock().unwrap().values() {
+            let _ = stream.shutdown(SocketShutdown::Both);
+        }
+    });
+}
+
+/// Handle a websocket connection from extension, reject it if not authenticated
+// The handshake callback signature is defined by tungstenite
+#[allow(clippy::result_large_err)]
+fn handle_connection(
+    stream: TcpStream,
+    connection_id: ConnectionId,
+    connections: &Mutex<Connections>,
+    config: &Config,
+    token: &str,
+) {
+    if let Err(e) = stream.set_nonblocking(false) {
+        warn!(target: "tab_data_requester", "Failed to set websocket stream blocking: {}", e);
+        return;
+    }
+    let mut websocket = match accept_hdr(stream, |req: &Request, res: Response| {
+        authenticate(req, res, config, token)
+    }) {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            debug!(target: "tab_data_requester", "Websocket handshake failed: {}", e);
+            return;
+        }
+    };
+    // Tabs are only paired with processes of the browser on the other side, only looked up for
+    // authenticated connections
+    let browser_pid = peer_pid(websocket.get_ref()).map(relayed_browser_pid);
+
+    debug!(
+        target: "tab_data_requester",
+        "New connection: {} from pid {:?}",
+        connection_id,
+        browser_pid
+    );
+    connections
+        .lock()
+        .unwrap()
+        .add_connection(connection_id, browser_pid);
+    loop {
+        let msg = match websocket.read() {
+            Ok(Message::Text(msg)) => msg,
+            Ok(Message::Binary(_)) => {
+                warn!(target: "tab_data_requester", "Error, recieved binary message!");
+                continue;
+            }
+            // Ping, pong and close are answered by tungstenite
+            Ok(_) => continue,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => break,
+            Err(e) => {
+                debug!(target: "tab_data_requester", "Connection {} failed: {}", connection_id, e);
+                break;
+            }
+        };
+        trace!(target: "tab_data_requester", "Recieved a ws_msg!");
+
+        for reply in handle_extension_message(connections, connection_id, msg.as_str(), config) {
+            let result = websocket
+                .send(Message::text(reply.to_json()))
+                .and_then(|_| {
+                    if reply.is_fatal() {
+                        websocket.close(Some(CloseFrame {
+                            code: CloseCode::Protocol,
+                            reason: "Incompatible protocol".into(),
+                        }))
+                    } else {
+                        Ok(())
                     }
+                });
+            if let Err(e) = result {
+                warn!(
+                    target: "tab_data_requester",
+                    "Failed to send message to browser extension: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    debug!(target: "tab_data_requester", "Connection closed: {}", connection_id);
+    connections.lock().unwrap().remove_connection(connection_id);
+}
+
+/// The process on the other side of a localhost connection, found by the inode of its socket in
+/// "/proc/net/tcp", `None` if it's not found, e.g. it's owned by another user
+fn peer_pid(stream: &TcpStream) -> Option<u32> {
+    let (inode, uid) = peer_socket(stream)?;
+    // File descriptors of other users can't be read, don't scan for them
+    let own_uid = fs::metadata("/proc/self").ok()?.uid();
+    if uid != own_uid {
+        debug!(target: "tab_data_requester", "Peer socket is owned by uid {}", uid);
+        return None;
+    }
+    let socket_link = format!("socket:[{inode}]");
+
+    fs::read_dir("/proc")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.metadata().is_ok_and(|metadata| metadata.uid() == uid))
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .find(|pid| {
+            fs::read_dir(format!("/proc/{pid}/fd")).is_ok_and(|fds| {
+                fds.filter_map(Result::ok).any(|fd| {
+                    fs::read_link(fd.path())
+                        .is_ok_and(|link| link.as_os_str() == socket_link.as_str())
+                })
+            })
+        })
+}
+
+/// Inode and owner uid of the socket on the other side of a localhost connection
+fn peer_socket(stream: &TcpStream) -> Option<(String, u32)> {
+    let local_port = stream.local_addr().ok()?.port();
+    let peer_port = stream.peer_addr().ok()?.port();
+    // "sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode"
+    let port = |address: &str| {
+        address
+            .rsplit_once(':')
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok())
+    };
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .into_iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|sockets| {
+            sockets.lines().skip(1).find_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let matched = fields.len() > 9
+                    && port(fields[1]) == Some(peer_port)
+                    && port(fields[2]) == Some(local_port);
+                if !matched {
+                    return None;
                 }
+                Some((fields[9].to_string(), fields[7].parse().ok()?))
+            })
+        })
+}
+
+/// Native messaging hosts relay for the browser which launched them, other peers are the browser
+fn relayed_browser_pid(pid: u32) -> u32 {
+    let is_native_messaging_host = fs::read_link(format!("/proc/{pid}/exe"))
+        .ok()
+        .zip(env::current_exe().ok())
+        .is_some_and(|(exe, current_exe)| exe == current_exe);
+    if !is_native_messaging_host {
+        return pid;
+    }
+    // "pid (comm) state ppid ...", comm may have spaces
+    fs::read_to_string(format!("/proc/{pid}/stat"))
+        .ok()
+        .and_then(|stat| {
+            stat.rsplit_once(')')?
+                .1
+                .split_whitespace()
+                .nth(1)?
+                .parse()
+                .ok()
+        })
+        .unwrap_or(pid)
+}
+
+/// Web pages can connect to localhost too, only extensions with the token are allowed
+#[allow(clippy::result_large_err)]
+fn authenticate(
+    req: &Request,
+    mut res: Response,
+    config: &Config,
+    token: &str,
+) -> Result<Response, ErrorResponse> {
+    let reject = |status: StatusCode, reason: &str| {
+        let mut error_response = ErrorResponse::new(Some(reason.to_string()));
+        *error_response.status_mut() = status;
+        error_response
+    };
+
+    let origin = req
+        .headers()
+        .get("Origin")
+        .and_then(|origin| origin.to_str().ok())
+        .unwrap_or_default();
+    if !is_allowed_origin(origin, &config.websocket.extension_ids) {
+        warn!(
+            target: "tab_data_requester",
+            "Reject websocket connection from origin {:?}, add its id to websocket.extension_ids",
+            origin
+        );
+        return Err(reject(StatusCode::FORBIDDEN, "Origin not allowed"));
+    }
+
+    // Extension send the token as the websocket subprotocol
+    let Some(protocol) = req
+        .headers()
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|protocols| protocols.to_str().ok())
+        .flat_map(|protocols| protocols.split(','))
+        .map(str::trim)
+        .find(|&protocol| token_matches(protocol, token))
+    else {
+        warn!(
+            target: "tab_data_requester",
+            "Reject websocket connection from {:?}, wrong token",
+            origin
+        );
+        return Err(reject(StatusCode::UNAUTHORIZED, "Wrong token"));
+    };
+
+    if let Ok(protocol) = HeaderValue::from_str(protocol) {
+        res.headers_mut().insert("Sec-WebSocket-Protocol", protocol);
+    }
+    Ok(res)
+}
+
+/// Whether the "Origin" header is one of the configured extensions, none if not configured
+fn is_allowed_origin(origin: &str, extension_ids: &[String]) -> bool {
+    ["chrome-extension://", "moz-extension://"]
+        .iter()
+        .find_map(|scheme| origin.strip_prefix(scheme))
+        .map(|extension_id| extension_id.trim_end_matches('/'))
+        .is_some_and(|extension_id| extension_ids.iter().any(|id| id == extension_id))
+}
+
+/// Parse json message sent by extension and apply it on connections, return replies to extension.
+/// The connection should be closed after a fatal error reply
+fn handle_extension_message(
+    connections: &Mutex<Connections>,
+    connection_id: ConnectionId,
+    msg: &str,
+    config: &Config,
+) -> Vec<DaemonMessage> {
+    let extension_message = match parse_extension_message(msg) {
+        Ok(extension_message) => extension_message,
+        Err(error) => {
+            warn!(target: "tab_data_requester", "{:?}\nError data: {msg}", error);
+            return vec![error];
+        }
+    };
+
+    let connections = &mut connections.lock().unwrap();
+    if !matches!(
+        extension_message,
+        ExtensionMessage::Hello(_) | ExtensionMessage::Unknown
+    ) && !connections.has_hello(connection_id)
+    {
+        warn!(target: "tab_data_requester", "Extension sent tab data before hello");
+        return vec![DaemonMessage::Error {
+            message: "Send hello before any tab data".to_string(),
+            fatal: true,
+        }];
+    }
+
+    match extension_message {
+        ExtensionMessage::Hello(hello) => {
+            info!(
+                target: "tab_data_requester",
+                "Extension {} connected, capabilities: {:?}",
+                hello.extension_version, hello.capabilities
+            );
+            connections.set_hello(connection_id, hello);
+            vec![DaemonMessage::ack()]
+        }
+        ExtensionMessage::Snapshot(tab_data) => {
+            connections.apply_snapshot(connection_id, tab_data);
+            Vec::new()
+        }
+        ExtensionMessage::Event(tab_event_data) => {
+            if connections.apply_event(connection_id, tab_event_data, config) {
+                debug!(
+                    target: "tab_data_requester",
+                    "Requesting tab data snapshot from browser extension"
+                );
+                vec![DaemonMessage::Command {
+                    command: Command::Snapshot,
+                }]
             } else {
-                eprintln!("Error, recieved binary message!");
+                Vec::new()
             }
-            Ok(())
         }
-    })
-    .unwrap();
+        ExtensionMessage::Unknown => {
+            debug!(target: "tab_data_requester", "Ignore unknown message type: {msg}");
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_configured_extensions_are_allowed() {
+        let extension_ids = vec!["abc".to_string()];
+        assert!(is_allowed_origin("chrome-extension://abc", &extension_ids));
+        assert!(is_allowed_origin("moz-extension://abc/", &extension_ids));
+        assert!(!is_allowed_origin("chrome-extension://def", &extension_ids));
+        assert!(!is_allowed_origin("https://abc", &extension_ids));
+        assert!(!is_allowed_origin("chrome-extension://abc", &[]));
+    }
+
+    #[test]
+    fn peers_are_found_by_their_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(peer_pid(&stream), Some(std::process::id()));
+    }
+
+    #[test]
+    fn native_messaging_hosts_relay_for_their_parent() {
+        // Tests run in this executable, like hosts do in the daemon's
+        assert_eq!(
+            relayed_browser_pid(std::process::id()),
+            std::os::unix::process::parent_id()
+        );
+        assert_eq!(relayed_browser_pid(1), 1);
+    }
 }
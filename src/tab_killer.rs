@@ -1,66 +1,176 @@
 use std::{
-    collections::BTreeMap,
-    sync::{
-        mpsc::{Receiver, SyncSender},
-        Arc, Mutex,
-    },
-    thread::{sleep, spawn, JoinHandle},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::{spawn, JoinHandle},
     time::{Duration, Instant},
 };
 
-use debug_print::debug_println;
-use sysinfo::Signal;
+use sysinfo::Pid;
 use thousands::Separable;
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
+    cgroup::CgroupManager,
+    clock::{add_secs, Clock},
     config::{Config, KillTabStrategy},
-    Status,
+    control::SharedControl,
+    history::{KilledTab, SharedHistory},
+    notification::Notifier,
+    processes::{ProcessTerminator, SystemProcesses},
+    replay::Recorder,
+    shutdown::Shutdown,
+    snapshot::{SharedSnapshot, Snapshot},
+    status::{Connections, Rss, Status},
+    strategy::{
+        background_tabs_longer_than, evaluate, predict_next_kills, resume_grace_secs_left,
+        KillDecision, KillReason,
+    },
+    systemd, timers,
 };
 
-pub type Rss = u64;
+/// How often timers of tabs are saved, they are also saved on exit
+const SAVE_TIMERS_INTERVAL: Duration = Duration::from_secs(30);
 
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_tab_killer_thread(
-    status: Arc<Mutex<Status>>,
+    connections: Arc<Mutex<Connections>>,
+    snapshot: SharedSnapshot,
+    history: SharedHistory,
+    control: SharedControl,
+    clock: Clock,
+    recorder: Option<Recorder>,
     config: Config,
-    update_req_sender: SyncSender<()>,
-    update_result_reciever: Receiver<Result<(), String>>,
+    shutdown: Arc<Shutdown>,
 ) -> JoinHandle<()> {
     spawn(move || {
+        let _shutdown_guard = shutdown.guard();
+        let mut processes = SystemProcesses::default();
+        let timers_path = timers::timers_path();
+        let mut status = Status {
+            restored_timers: timers_path
+                .as_deref()
+                .map(timers::load_timers)
+                .unwrap_or_default(),
+            ..Default::default()
+        };
+        let mut save_timers_instant = Instant::now();
+        let notify_socket = systemd::notify_socket();
+        let watchdog = systemd::watchdog_interval().is_some();
         // The duration loop sleep for
         let tick = Duration::from_secs_f32(config.check_interval_secs);
-        let update_status_timeout = tick * 4;
-        loop {
+        let mut cgroup_manager = if config.cgroup.enable {
+            match CgroupManager::new(&config) {
+                Ok(cgroup_manager) => Some(cgroup_manager),
+                Err(e) => {
+                    error!(
+                        target: "tab_killer",
+                        "Failed to setup cgroup {:?}: {}",
+                        config.cgroup.root,
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let mut notifier = if config.notification.enable {
+            match Notifier::new() {
+                Ok(notifier) => Some(notifier),
+                Err(e) => {
+                    error!(target: "tab_killer", "Failed to connect to session bus: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        while !shutdown.is_requested() {
             let start_instant = Instant::now();
 
-            debug_println!("Request update status");
-            match update_req_sender.try_send(()) {
-                Ok(_) => (),
-                Err(_) => {
-                    eprintln!("Failed to request update status");
-                }
+            // Tabs are kept up to date by extension events, only processes need refresh
+            trace!(target: "tab_killer", "Refresh status");
+            let (fresh_connections, tabs_changed) = {
+                let connections = &mut connections.lock().unwrap();
+                (
+                    connections.fresh_connections(&config),
+                    connections.take_tabs_changed(),
+                )
+            };
+            status.refresh(
+                &mut processes,
+                &fresh_connections,
+                tabs_changed,
+                &clock,
+                &config,
+            );
+            if let Some(recorder) = &recorder {
+                recorder.record_tick(&status, &clock);
+            }
+            if let Some(cgroup_manager) = &mut cgroup_manager {
+                apply_cgroup_tiers(&mut status, &config, cgroup_manager);
             }
+            trace!(target: "tab_killer", "{:?}", status);
 
-            // Waiting for status update
-            match update_result_reciever.recv_timeout(update_status_timeout) {
-                Ok(update_result) => match update_result {
-                    Ok(_) => {
-                        debug_println!("Status update successed");
-                        kill_tabs_by_strategies(status.clone(), &config);
-                    }
-                    Err(e) => {
-                        eprintln!("Cannot update status, skip this round: {e}");
-                    }
-                },
-                Err(_) => {
-                    eprintln!("Timeout, retry requesting data!");
-                    continue;
+            // Tabs kept by notification are protected like those by control api
+            let kept_tabs = notifier
+                .as_mut()
+                .map(Notifier::take_kept_tabs)
+                .unwrap_or_default();
+            let control_state = {
+                let control = &mut control.lock().unwrap();
+                for pid in kept_tabs {
+                    info!(
+                        target: "tab_killer",
+                        pid,
+                        keep_secs = config.notification.keep_secs,
+                        "Keep tab"
+                    );
+                    control.protections.protect(
+                        pid,
+                        Some(add_secs(status.timestamp, config.notification.keep_secs)),
+                    );
+                }
+                control
+                    .protections
+                    .retain_pids(|pid| status.browser_processes.contains_key(&Pid::from_u32(pid)));
+                control.take_state(status.timestamp)
+            };
+
+            let mut new_snapshot = Snapshot::new(&status, &control_state);
+            let mut killed_tabs =
+                kill_requested_tabs(&new_snapshot, &mut processes, &control_state.kill_requests);
+            if !new_snapshot.paused {
+                if let Some(notifier) = &mut notifier {
+                    warn_before_kill(&new_snapshot, &config, notifier);
                 }
             }
+            killed_tabs.extend(kill_tabs(
+                &new_snapshot,
+                evaluate(&new_snapshot, &config),
+                &mut processes,
+            ));
+            if let Some(notifier) = &mut notifier {
+                notifier.notify_killed_tabs(&killed_tabs);
+            }
+            if save_timers_instant.elapsed() >= SAVE_TIMERS_INTERVAL {
+                save_timers(timers_path.as_deref(), &status, &clock);
+                save_timers_instant = Instant::now();
+            }
+
+            predict_next_kills(&mut new_snapshot, &config);
+            notify_systemd(notify_socket.as_deref(), &new_snapshot, watchdog);
+            history
+                .write()
+                .unwrap()
+                .push(&new_snapshot, &killed_tabs, &clock);
+            snapshot.store(Arc::new(new_snapshot));
 
             let end_instant = Instant::now();
             let consumed_time = end_instant - start_instant;
             if tick < consumed_time {
-                eprintln!(
+                warn!(
+                    target: "tab_killer",
                     "Consumed time {:?} exceed check interval {:?}, delay: {:?}",
                     consumed_time,
                     tick,
@@ -70,206 +180,279 @@ pub fn spawn_tab_killer_thread(
             }
 
             let sleep_duration = tick - consumed_time;
-            println!("Tick consumed: {:?} / {:?}\n", consumed_time, tick);
-            sleep(sleep_duration);
+            debug!(target: "tab_killer", "Tick consumed: {:?} / {:?}", consumed_time, tick);
+            if shutdown.sleep(sleep_duration) {
+                break;
+            }
+        }
+
+        save_timers(timers_path.as_deref(), &status, &clock);
+        if let Some(cgroup_manager) = &mut cgroup_manager {
+            cgroup_manager.release();
         }
     })
 }
 
-fn kill_tabs_by_strategies(status: Arc<Mutex<Status>>, config: &Config) {
-    let status = &mut status.lock().unwrap();
+/// Keep timers of tabs for the next run, so they don't start over after restart
+fn save_timers(timers_path: Option<&Path>, status: &Status, clock: &Clock) {
+    let Some(timers_path) = timers_path else {
+        warn!(target: "tab_killer", "Failed to save timers: No state directory");
+        return;
+    };
+    match timers::save_timers(timers_path, status, clock) {
+        Ok(()) => debug!(target: "tab_killer", "Save timers of {} tabs", status.tab_infos.len()),
+        Err(e) => warn!(target: "tab_killer", "Failed to save timers: {}", e),
+    }
+}
 
-    debug_println!("{:?}", status);
-    println!(
-        "Tabs: {:?}",
-        status
-            .tab_infos
-            .iter()
-            .map(|(pid, tab_info)| (pid, tab_info.title.clone()))
-            .collect::<BTreeMap<_, _>>()
+/// Report progress to systemd, the watchdog restarts the service if the loop gets stuck
+fn notify_systemd(notify_socket: Option<&str>, snapshot: &Snapshot, watchdog: bool) {
+    let total_rss: Rss = snapshot.tab_infos.iter().map(|tab_info| tab_info.rss).sum();
+    let mut state = format!(
+        "STATUS=Tabs: {}, total rss: {}",
+        snapshot.tab_infos.len(),
+        total_rss.separate_with_commas()
     );
-
-    let total_rss: u64 = status
-        .tab_infos
-        .keys()
-        .map(|pid| {
-            if let Some(process) = status.system.processes().get(pid) {
-                process.memory()
-            } else {
-                0
-            }
-        })
-        .sum();
-    println!("Total rss: {}", total_rss.separate_with_commas());
-
-    // Apply kill tab startegies
-    if status.tab_infos.len() > 0 {
-        for kill_tab_strategy in &config.kill_tab_strategies {
-            // Apply strategy
-            match kill_tab_strategy {
-                KillTabStrategy::RssLimit => {
-                    kill_tabs_by_rss_limit(status, &config, total_rss);
-                }
-                KillTabStrategy::BackgroundTimeLimit => {
-                    kill_tabs_by_background_time_limit(status, &config);
-                }
-                KillTabStrategy::CpuIdleTimeLimit => {
-                    kill_tabs_by_cpu_idle_time_limit(status, &config);
-                }
-            }
-        }
+    if watchdog {
+        state.push_str("\nWATCHDOG=1");
     }
+    systemd::notify(notify_socket, &state);
 }
 
-fn kill_tabs_by_rss_limit(status: &Status, config: &Config, total_rss: u64) {
-    if total_rss > config.strategy.rss_limit.max_bytes {
-        println!(
-            "Hit the rss limit({}/{}), apply RssLimit strategy",
-            total_rss.separate_with_commas(),
-            config.strategy.rss_limit.max_bytes.separate_with_commas()
-        );
-
-        let sorted_pid_rss = status.get_sorted_pid_rss();
-        let killable_pid_rss = sorted_pid_rss
+/// Warn about tabs going to be killed by background time limit
+fn warn_before_kill(snapshot: &Snapshot, config: &Config, notifier: &mut Notifier) {
+    let warn_before_secs = config.notification.warn_before_secs;
+    if warn_before_secs <= 0.0
+        || !config
+            .kill_tab_strategies
             .iter()
-            // Don't kill new tab
-            // .filter(|pid| {
-            //     if let Some(tab_info) = status.tab_infos.get(pid) {
-            //         tab_info.title != "New Tab"
-            //     } else {
-            //         false
-            //     }
-            // })
-            // Don't kill audible tab
-            .filter(|(pid, _)| {
-                if config.whitelist_audible_tab {
-                    if let Some(tab_info) = status.tab_infos.get(pid) {
-                        !tab_info.audible
-                    } else {
-                        true
-                    }
-                } else {
-                    true
-                }
-            });
-
-        // Get pids to kill
-        let exceed_rss = total_rss - config.strategy.rss_limit.max_bytes;
-        let mut expected_freed_rss = 0;
-        let mut killing_pids = Vec::new();
-        for &(pid, rss) in killable_pid_rss.rev() {
-            if exceed_rss >= expected_freed_rss {
-                let url = status.tab_infos[&pid].url.clone();
-                let in_whitelist = config.whitelist.iter().any(|regex| regex.is_match(&url));
-                if in_whitelist {
-                    continue;
-                }
-
-                expected_freed_rss += rss;
-                killing_pids.push(pid);
-            } else {
-                break;
-            }
-        }
-
-        // Kill
-        killing_pids.iter().for_each(|pid| {
-            let signal = Signal::Term;
-            if let Some(process) = status.system.processes().get(pid) {
-                match process.kill_with(signal) {
-                    Some(success) => {
-                        if !success {
-                            eprintln!("Failed to send signal {} to {},", signal, pid);
-                        }
-                    }
-                    None => {
-                        eprintln!("The signal {} is not supported on this platform!", signal);
-                    }
-                }
-            }
-        })
+            .any(|strategy| matches!(strategy, KillTabStrategy::BackgroundTimeLimit))
+    {
+        return;
+    }
+    let max_secs = config.strategy.background_time_limit.max_secs;
+    let resume_grace_secs_left = resume_grace_secs_left(snapshot, config);
+    let warning_tabs: Vec<(u32, &str, f64)> =
+        background_tabs_longer_than(snapshot, config, max_secs - warn_before_secs)
+            .into_iter()
+            .map(|tab_info| {
+                (
+                    tab_info.pid,
+                    tab_info.title.as_str(),
+                    (max_secs - tab_info.background_time_secs).max(resume_grace_secs_left),
+                )
+            })
+            // Killed in this tick anyway, e.g. its protection just expired, or not soon after
+            // resumed from suspend
+            .filter(|&(_, _, secs_left)| 0.0 < secs_left && secs_left < warn_before_secs)
+            .collect();
+    notifier.retain_warnings(|pid| warning_tabs.iter().any(|&(tab_pid, _, _)| tab_pid == pid));
+    for (pid, title, secs_left) in warning_tabs {
+        notifier.warn_before_kill(pid, title, secs_left);
     }
 }
 
-/// Will not kill new tab, because the last_access_time is wrong
-fn kill_tabs_by_background_time_limit(status: &mut Status, config: &Config) {
-    let signal = Signal::Term;
-    status
-        .begin_background_timestamps
-        .iter()
-        // Only left those tabs which in background too long
-        .filter(|(_, &begin_background_timestamp)| {
-            Duration::from_secs_f64((status.timestamp - begin_background_timestamp) / 1000.0)
-                > Duration::from_secs_f64(config.strategy.background_time_limit.max_secs)
-        })
-        .map(|(pid, _)| pid)
-        // Don't kill new tab
-        .filter(|pid| {
-            if let Some(tab_info) = status.tab_infos.get(pid) {
-                tab_info.title != "New Tab"
-            } else {
-                false
-            }
-        })
-        // Don't kill audible tab
-        .filter(|pid| {
-            if config.whitelist_audible_tab {
-                if let Some(tab_info) = status.tab_infos.get(pid) {
-                    !tab_info.audible
-                } else {
-                    true
-                }
-            } else {
-                true
-            }
-        })
-        // Kill
-        .for_each(|pid| {
-            if let Some(process) = status.system.processes().get(pid) {
-                process.kill_with(signal);
-            }
-        });
+fn apply_cgroup_tiers(status: &mut Status, config: &Config, cgroup_manager: &mut CgroupManager) {
+    cgroup_manager.assign_tiers(status, config);
+    status.cgroup_tiers = cgroup_manager.read_tier_statuses();
 }
 
-fn kill_tabs_by_cpu_idle_time_limit(status: &mut Status, config: &Config) {
-    let signal = Signal::Term;
-    status
-        .begin_cpu_idle_timestamps
+/// Kill tabs requested by pid through control api, whether strategies are paused or not
+fn kill_requested_tabs(
+    snapshot: &Snapshot,
+    terminator: &mut dyn ProcessTerminator,
+    kill_requests: &[u32],
+) -> Vec<KilledTab> {
+    let decisions = kill_requests
         .iter()
-        // Only left those tabs which cpu idle too long
-        .filter(|(_, &begin_cpu_idle_timestamp)| {
-            Duration::from_secs_f64((status.timestamp - begin_cpu_idle_timestamp) / 1000.0)
-                > Duration::from_secs_f64(config.strategy.cpu_idle_time_limit.max_secs)
+        .filter_map(|&pid| {
+            let Some(tab_info) = snapshot
+                .tab_infos
+                .iter()
+                .find(|tab_info| tab_info.pid == pid)
+            else {
+                warn!(target: "tab_killer", pid, "Tab to kill is not found");
+                return None;
+            };
+            Some(KillDecision {
+                tab_id: tab_info.id,
+                pid,
+                reason: KillReason::Requested,
+            })
         })
-        .map(|(pid, _)| pid)
-        // Don't kill new tab
-        // .filter(|pid| {
-        //     if let Some(tab_info) = status.tab_infos.get(pid) {
-        //         tab_info.title != "New Tab"
-        //     } else {
-        //         false
-        //     }
-        // })
-        // Don't kill audible tab
-        .filter(|pid| {
-            if config.whitelist_audible_tab {
-                if let Some(tab_info) = status.tab_infos.get(pid) {
-                    !tab_info.audible
-                } else {
-                    true
-                }
-            } else {
-                true
+        .collect();
+    kill_tabs(snapshot, decisions, terminator)
+}
+
+/// Terminate tab processes, log each decision with its reason, return tabs that are sent the
+/// kill signal
+fn kill_tabs(
+    snapshot: &Snapshot,
+    decisions: Vec<KillDecision>,
+    terminator: &mut dyn ProcessTerminator,
+) -> Vec<KilledTab> {
+    decisions
+        .into_iter()
+        .filter_map(|decision| {
+            let tab_info = snapshot
+                .tab_infos
+                .iter()
+                .find(|tab_info| tab_info.pid == decision.pid)?;
+            info!(
+                target: "tab_killer",
+                pid = tab_info.pid,
+                title = tab_info.title.as_str(),
+                url = tab_info.url.as_str(),
+                rss = tab_info.rss,
+                strategy = decision.reason.strategy(),
+                reason = %decision.reason,
+                "Kill tab"
+            );
+
+            if let Err(e) = terminator.terminate(Pid::from_u32(tab_info.pid)) {
+                error!(target: "tab_killer", "Failed to terminate {}: {}", tab_info.pid, e);
+                return None;
             }
+            Some(KilledTab {
+                timestamp: snapshot.timestamp,
+                tab_id: tab_info.id,
+                pid: tab_info.pid,
+                title: tab_info.title.clone(),
+                url: tab_info.url.clone(),
+                rss: tab_info.rss,
+                strategy: decision.reason.strategy(),
+                reason: decision.reason.to_string(),
+            })
         })
-        // Kill
-        .for_each(|pid| {
-            if let Some(process) = status.system.processes().get(pid) {
-                if let Some(tab_info) = status.tab_infos.get(pid) {
-                    if !tab_info.active {
-                        process.kill_with(signal);
-                    }
-                }
-            }
-        });
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        browser::ProcessClass,
+        control::ControlState,
+        protection::Protections,
+        protocol::{TabData, TabInfo},
+        testing::{process, FakeBrowser, FakeProcesses, MB, START},
+    };
+
+    fn config() -> Config {
+        toml::from_str(include_str!("config.toml")).unwrap()
+    }
+
+    #[test]
+    fn killed_tabs_are_reported_with_reason() {
+        // Tabs 1 and 2 hosted by pids 101 and 102
+        let mut browser = FakeBrowser::with_tabs(2);
+        let snapshot = browser.snapshot(&config());
+        // The process of tab 2 exited before it's killed
+        browser
+            .processes
+            .browser_processes
+            .remove(&Pid::from_u32(102));
+
+        let decisions = [1, 2]
+            .into_iter()
+            .map(|tab_id| KillDecision {
+                tab_id,
+                pid: 100 + tab_id as u32,
+                reason: KillReason::BackgroundTimeLimit {
+                    background_secs: 61.0,
+                    max_secs: 60.0,
+                },
+            })
+            .collect();
+        let killed_tabs = kill_tabs(&snapshot, decisions, &mut browser.processes);
+        assert_eq!(browser.processes.terminated_pids, [Pid::from_u32(101)]);
+        assert_eq!(killed_tabs.len(), 1);
+        assert_eq!(killed_tabs[0].tab_id, 1);
+        assert_eq!(killed_tabs[0].timestamp, snapshot.timestamp);
+        assert_eq!(killed_tabs[0].rss, 100 * MB);
+        assert_eq!(killed_tabs[0].strategy, "background_time_limit");
+        assert_eq!(
+            killed_tabs[0].reason,
+            "in background for 61.0s, limit 60.0s"
+        );
+    }
+
+    #[test]
+    fn requested_tabs_are_killed_whatever_strategies() {
+        let mut browser = FakeBrowser::with_tabs(2);
+        let mut snapshot = browser.snapshot(&config());
+        snapshot.paused = true;
+        snapshot.tab_infos[0].foreground = true;
+        snapshot.tab_infos[0].protected = true;
+
+        // Tab 3 doesn't exist
+        let killed_tabs = kill_requested_tabs(&snapshot, &mut browser.processes, &[101, 103]);
+        assert_eq!(killed_tabs.len(), 1);
+        assert_eq!(killed_tabs[0].tab_id, 1);
+        assert_eq!(killed_tabs[0].strategy, "requested");
+        assert_eq!(browser.processes.terminated_pids, [Pid::from_u32(101)]);
+    }
+
+    #[test]
+    fn tabs_sharing_an_id_across_browsers_are_protected_and_killed_by_pid() {
+        let config = config();
+        let clock = Clock::simulated(START);
+        // Two browsers, both extensions report tab 1 hosted by browser inner pid 5
+        let mut processes = FakeProcesses::default();
+        let mut connections = Connections::new(None, clock.clone());
+        for browser_pid in [10, 20] {
+            processes.browser_processes.insert(
+                Pid::from_u32(browser_pid),
+                process(None, ProcessClass::Browser, None),
+            );
+            processes.browser_processes.insert(
+                Pid::from_u32(browser_pid + 1),
+                process(Some(browser_pid), ProcessClass::Renderer, Some(5)),
+            );
+            connections.add_connection(browser_pid, Some(browser_pid));
+            connections.apply_snapshot(
+                browser_pid,
+                TabData {
+                    seq: Some(0),
+                    timestamp: clock.now(),
+                    tab_infos: vec![TabInfo {
+                        id: 1,
+                        browser_inner_pid: Some(5),
+                        ..Default::default()
+                    }],
+                },
+            );
+        }
+        let mut status = Status::default();
+        status.refresh(
+            &mut processes,
+            &connections.fresh_connections(&config),
+            true,
+            &clock,
+            &config,
+        );
+
+        let mut protections = Protections::default();
+        protections.protect(11, None);
+        let control_state = ControlState {
+            protections,
+            ..Default::default()
+        };
+        let snapshot = Snapshot::new(&status, &control_state);
+        let protected = |pid| {
+            snapshot
+                .tab_infos
+                .iter()
+                .find(|tab_info| tab_info.pid == pid)
+                .unwrap()
+                .protected
+        };
+        assert!(protected(11));
+        assert!(!protected(21));
+
+        let killed_tabs = kill_requested_tabs(&snapshot, &mut processes, &[21]);
+        assert_eq!(killed_tabs.len(), 1);
+        assert_eq!(killed_tabs[0].tab_id, 1);
+        assert_eq!(processes.terminated_pids, [Pid::from_u32(21)]);
+    }
 }
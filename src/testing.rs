@@ -0,0 +1,200 @@
+//! Fakes and fixtures shared by tests of the library and the daemon, enabled by the `testing`
+//! feature outside of the library's own tests
+
+use std::{collections::HashMap, io};
+
+use sysinfo::Pid;
+
+use crate::{
+    browser::ProcessClass,
+    clock::{add_secs, Clock},
+    config::Config,
+    control::ControlState,
+    processes::{ProcessSource, ProcessTerminator},
+    protection::Protections,
+    protocol::{BrowserInnerPid, TabData, TabId, TabInfo, Timestamp},
+    snapshot::Snapshot,
+    status::{Connections, ProcessInfo, Rss, Status},
+    strategy::evaluate,
+};
+
+// When the extension reported tabs, background tabs went to background then
+pub const START: Timestamp = 1_700_000_000_000.0;
+pub const MB: Rss = 1_000_000;
+
+/// Processes kept in memory, a process is gone once terminated, e.g. for tests
+#[derive(Debug, Default)]
+pub struct FakeProcesses {
+    pub browser_processes: HashMap<Pid, ProcessInfo>,
+    pub available_memory: u64,
+    // In the order they are terminated
+    pub terminated_pids: Vec<Pid>,
+    // Processes of browsers started later, only sampled after browsers are rescanned
+    pub unscanned_processes: HashMap<Pid, ProcessInfo>,
+    pub browser_rescans: usize,
+}
+
+impl ProcessSource for FakeProcesses {
+    fn browser_processes(
+        &mut self,
+        _tabs_changed: bool,
+        _config: &Config,
+    ) -> HashMap<Pid, ProcessInfo> {
+        self.browser_processes.clone()
+    }
+
+    fn available_memory(&self) -> u64 {
+        self.available_memory
+    }
+
+    fn rescan_browsers(&mut self) {
+        self.browser_rescans += 1;
+        self.browser_processes
+            .extend(std::mem::take(&mut self.unscanned_processes));
+    }
+}
+
+impl ProcessTerminator for FakeProcesses {
+    fn terminate(&mut self, pid: Pid) -> io::Result<()> {
+        self.browser_processes
+            .remove(&pid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such process"))?;
+        self.terminated_pids.push(pid);
+        Ok(())
+    }
+}
+
+/// An idle process of a browser started at 0, with 100 MB of rss
+pub fn process(
+    parent: Option<u32>,
+    class: ProcessClass,
+    browser_inner_pid: Option<BrowserInnerPid>,
+) -> ProcessInfo {
+    ProcessInfo {
+        parent,
+        start_time: 0,
+        rss: 100 * MB,
+        cpu_usage: 0.0,
+        class,
+        browser_inner_pid,
+    }
+}
+
+/// A renderer of no known browser main process
+pub fn renderer(browser_inner_pid: BrowserInnerPid) -> ProcessInfo {
+    process(None, ProcessClass::Renderer, Some(browser_inner_pid))
+}
+
+/// A background tab, hosted by the renderer with browser inner pid `id`
+pub fn tab(id: TabId, title: &str) -> TabInfo {
+    TabInfo {
+        id,
+        title: title.to_string(),
+        url: format!("https://example.com/{id}"),
+        last_accessed: START,
+        browser_inner_pid: Some(id),
+        ..Default::default()
+    }
+}
+
+/// Pid of the renderer hosting tab `id` in `FakeBrowser`
+pub fn tab_pid(id: TabId) -> Pid {
+    Pid::from_u32(100 + id as u32)
+}
+
+/// A browser main process of pid 1 with a renderer for each tab
+pub struct FakeBrowser {
+    pub tabs: Vec<TabInfo>,
+    pub processes: FakeProcesses,
+}
+
+impl FakeBrowser {
+    pub fn new() -> Self {
+        let mut processes = FakeProcesses::default();
+        processes.browser_processes.insert(
+            Pid::from_u32(1),
+            ProcessInfo {
+                rss: 50 * MB,
+                cpu_usage: 1.0,
+                ..process(None, ProcessClass::Browser, None)
+            },
+        );
+        FakeBrowser {
+            tabs: Vec::new(),
+            processes,
+        }
+    }
+
+    /// Tabs `1..=count` of 100 MB, all in background
+    pub fn with_tabs(count: TabId) -> Self {
+        (1..=count).fold(FakeBrowser::new(), |browser, id| {
+            browser.with_tab(tab(id, &format!("Tab {id}")), 100 * MB, 0.0)
+        })
+    }
+
+    pub fn with_tab(mut self, tab_info: TabInfo, rss: Rss, cpu_usage: f32) -> Self {
+        self.processes.browser_processes.insert(
+            tab_pid(tab_info.id),
+            ProcessInfo {
+                rss,
+                cpu_usage,
+                ..process(Some(1), ProcessClass::Renderer, tab_info.browser_inner_pid)
+            },
+        );
+        self.tabs.push(tab_info);
+        self
+    }
+
+    /// Status sampled at `START` and again `secs` later
+    pub fn status_after(&mut self, secs: f64, config: &Config) -> Status {
+        let clock = Clock::simulated(START);
+        let mut connections = Connections::new(None, clock.clone());
+        connections.apply_snapshot(
+            0,
+            TabData {
+                seq: Some(0),
+                timestamp: START,
+                tab_infos: self.tabs.clone(),
+            },
+        );
+        let fresh_connections = connections.fresh_connections(config);
+        let mut status = Status::default();
+        for timestamp in [START, add_secs(START, secs)] {
+            clock.set(timestamp, timestamp);
+            status.sample(&mut self.processes, true, config);
+            status.update(&fresh_connections, &clock, config);
+        }
+        status
+    }
+
+    /// Ids of tabs strategies decide to kill `secs` after `START`, sorted
+    pub fn kill_after(
+        &mut self,
+        secs: f64,
+        config: &Config,
+        protections: &Protections,
+    ) -> Vec<TabId> {
+        let status = self.status_after(secs, config);
+        let control_state = ControlState {
+            protections: protections.clone(),
+            ..Default::default()
+        };
+        let mut tab_ids: Vec<TabId> = evaluate(&Snapshot::new(&status, &control_state), config)
+            .iter()
+            .map(|decision| decision.tab_id)
+            .collect();
+        tab_ids.sort_unstable();
+        tab_ids
+    }
+
+    /// Snapshot of the status sampled at `START`, nothing protected
+    pub fn snapshot(&mut self, config: &Config) -> Snapshot {
+        Snapshot::new(&self.status_after(0.0, config), &ControlState::default())
+    }
+}
+
+impl Default for FakeBrowser {
+    fn default() -> Self {
+        FakeBrowser::new()
+    }
+}
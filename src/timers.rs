@@ -0,0 +1,339 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::Pid;
+use tracing::{info, warn};
+
+use crate::{
+    clock::Clock,
+    protocol::{TabId, TabInfo, Timestamp},
+    status::{browser_main_pid, ProcessInfo, Status},
+    PROJECT_NAME,
+};
+
+/// The same tab of the same browser session, tab ids start over when the browser restarts
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct TabKey {
+    pub tab_id: TabId,
+    pub url: String,
+    // Start time of the browser main process, in secs since unix epoch
+    pub browser_start_time: u64,
+}
+
+/// Timers of a tab which are earlier than the daemon start, by the wall clock, `None` once not
+/// applicable
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct TabTimers {
+    pub begin_background_timestamp: Option<Timestamp>,
+    pub begin_cpu_idle_timestamp: Option<Timestamp>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SavedTab {
+    #[serde(flatten)]
+    key: TabKey,
+    #[serde(flatten)]
+    timers: TabTimers,
+}
+
+impl TabKey {
+    pub fn new(tab_info: &TabInfo, browser_start_time: u64) -> Self {
+        TabKey {
+            tab_id: tab_info.id,
+            url: tab_info.url.clone(),
+            browser_start_time,
+        }
+    }
+}
+
+/// "~/.local/state/tab-memory-manager.timers.json" on Linux
+pub fn timers_path() -> Option<PathBuf> {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .map(|dir| dir.join(format!("{PROJECT_NAME}.timers.json")))
+}
+
+/// Timers saved by the last run, empty if there isn't any
+pub fn load_timers(timers_path: &Path) -> HashMap<TabKey, TabTimers> {
+    let saved_tabs: Vec<SavedTab> = match fs::read_to_string(timers_path) {
+        Ok(json) => match serde_json::from_str(&json) {
+            Ok(saved_tabs) => saved_tabs,
+            Err(e) => {
+                warn!(target: "timers", "Ignore invalid timers file {:?}: {}", timers_path, e);
+                return HashMap::new();
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            warn!(target: "timers", "Failed to read timers file {:?}: {}", timers_path, e);
+            return HashMap::new();
+        }
+    };
+    info!(
+        target: "timers",
+        "Load timers of {} tabs from {:?}",
+        saved_tabs.len(),
+        timers_path
+    );
+    saved_tabs
+        .into_iter()
+        .map(|saved_tab| (saved_tab.key, saved_tab.timers))
+        .collect()
+}
+
+/// Save timers of current tabs by the wall clock, and restored timers of tabs not seen yet
+pub fn save_timers(timers_path: &Path, status: &Status, clock: &Clock) -> io::Result<()> {
+    // Restored timers are useless once their browser exits
+    let browser_start_times: HashSet<u64> = status
+        .browser_processes
+        .keys()
+        .filter_map(|&pid| browser_start_time(&status.browser_processes, pid))
+        .collect();
+    let mut saved_tabs: HashMap<TabKey, TabTimers> = status
+        .restored_timers
+        .iter()
+        .filter(|(key, _)| browser_start_times.contains(&key.browser_start_time))
+        .map(|(key, timers)| (key.clone(), *timers))
+        .collect();
+    for (pid, tab_info) in &status.tab_infos {
+        // Urls of private tabs are never written to disk
+        if tab_info.incognito {
+            continue;
+        }
+        let Some(browser_start_time) = browser_start_time(&status.browser_processes, *pid) else {
+            continue;
+        };
+        saved_tabs.insert(
+            TabKey::new(tab_info, browser_start_time),
+            TabTimers {
+                // The active tab goes to background some time after saved
+                begin_background_timestamp: status
+                    .begin_background_timestamps
+                    .get(pid)
+                    .filter(|_| !tab_info.active)
+                    .map(|&timestamp| clock.to_wall(timestamp)),
+                begin_cpu_idle_timestamp: status
+                    .begin_cpu_idle_timestamps
+                    .get(pid)
+                    .map(|&timestamp| clock.to_wall(timestamp)),
+            },
+        );
+    }
+    let saved_tabs: Vec<SavedTab> = saved_tabs
+        .into_iter()
+        .map(|(key, timers)| SavedTab { key, timers })
+        .collect();
+    let json = serde_json::to_string(&saved_tabs)?;
+
+    if let Some(dir) = timers_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Replace at once, the daemon may be killed while writing
+    let temp_path = timers_path.with_extension("json.tmp");
+    let mut temp_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    temp_file.write_all(json.as_bytes())?;
+    fs::rename(&temp_path, timers_path)
+}
+
+/// Start time of the browser main process which the tab process belongs to
+pub fn browser_start_time(browser_processes: &HashMap<Pid, ProcessInfo>, pid: Pid) -> Option<u64> {
+    let browser_main_pid = browser_main_pid(browser_processes, pid)?;
+    Some(browser_processes[&browser_main_pid].start_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        browser::ProcessClass,
+        config::Config,
+        protocol::TabData,
+        status::Connections,
+        testing::{process, FakeProcesses, START},
+    };
+
+    const BROWSER_START_TIME: u64 = 1_699_990_000;
+
+    fn config() -> Config {
+        toml::from_str(include_str!("config.toml")).unwrap()
+    }
+
+    /// A browser started at `BROWSER_START_TIME`, with renderer 11 of inner pid 5
+    fn processes() -> FakeProcesses {
+        let process = |parent, class, browser_inner_pid| ProcessInfo {
+            start_time: BROWSER_START_TIME,
+            ..process(parent, class, browser_inner_pid)
+        };
+        let mut processes = FakeProcesses::default();
+        processes.browser_processes.insert(
+            Pid::from_u32(10),
+            process(None, ProcessClass::Browser, None),
+        );
+        processes.browser_processes.insert(
+            Pid::from_u32(11),
+            process(Some(10), ProcessClass::Renderer, Some(5)),
+        );
+        processes
+    }
+
+    fn tab_info(url: &str, last_accessed: Timestamp) -> TabInfo {
+        TabInfo {
+            id: 1,
+            url: url.to_owned(),
+            last_accessed,
+            browser_inner_pid: Some(5),
+            ..Default::default()
+        }
+    }
+
+    fn updated_status(
+        restored_timers: HashMap<TabKey, TabTimers>,
+        tab_info: TabInfo,
+        clock: &Clock,
+    ) -> Status {
+        let config = config();
+        let mut connections = Connections::new(None, clock.clone());
+        connections.apply_snapshot(
+            0,
+            TabData {
+                seq: Some(0),
+                timestamp: clock.now(),
+                tab_infos: vec![tab_info],
+            },
+        );
+        let mut status = Status {
+            restored_timers,
+            ..Default::default()
+        };
+        status.sample(&mut processes(), true, &config);
+        status.update(&connections.fresh_connections(&config), clock, &config);
+        status
+    }
+
+    fn timers_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn timers_are_saved_and_loaded_back_by_tab_key() {
+        let dir = timers_dir("timers-round-trip");
+        let timers_path = dir.join("timers.json");
+        let clock = Clock::simulated(START);
+        let status = updated_status(
+            HashMap::new(),
+            tab_info("https://example.com/", START - 60_000.0),
+            &clock,
+        );
+        save_timers(&timers_path, &status, &clock).unwrap();
+
+        let restored_timers = load_timers(&timers_path);
+        let key = TabKey {
+            tab_id: 1,
+            url: "https://example.com/".to_owned(),
+            browser_start_time: BROWSER_START_TIME,
+        };
+        assert_eq!(restored_timers.len(), 1);
+        assert_eq!(
+            restored_timers[&key].begin_background_timestamp,
+            Some(START - 60_000.0)
+        );
+        assert_eq!(restored_timers[&key].begin_cpu_idle_timestamp, Some(START));
+
+        // A missing or invalid file is the same as no saved timers
+        assert!(load_timers(&dir.join("missing.json")).is_empty());
+        fs::write(&timers_path, "not json").unwrap();
+        assert!(load_timers(&timers_path).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_timers_apply_only_to_the_same_tab_of_the_same_browser() {
+        let clock = Clock::simulated(START);
+        let restored_timer = |url: &str, browser_start_time| {
+            (
+                TabKey {
+                    tab_id: 1,
+                    url: url.to_owned(),
+                    browser_start_time,
+                },
+                TabTimers {
+                    begin_background_timestamp: Some(START - 3_600_000.0),
+                    begin_cpu_idle_timestamp: Some(START - 3_600_000.0),
+                },
+            )
+        };
+        let tab_info = tab_info("https://example.com/", START - 60_000.0);
+
+        // Tab id, url and browser start time all match
+        let status = updated_status(
+            HashMap::from([restored_timer("https://example.com/", BROWSER_START_TIME)]),
+            tab_info.clone(),
+            &clock,
+        );
+        let pid = Pid::from_u32(11);
+        assert_eq!(
+            status.begin_background_timestamps[&pid],
+            START - 3_600_000.0
+        );
+        assert_eq!(status.begin_cpu_idle_timestamps[&pid], START - 3_600_000.0);
+
+        // The tab id was reused by another page, or by a browser started later
+        for key in [
+            restored_timer("https://example.org/", BROWSER_START_TIME),
+            restored_timer("https://example.com/", BROWSER_START_TIME - 1),
+        ] {
+            let status = updated_status(HashMap::from([key]), tab_info.clone(), &clock);
+            assert_eq!(status.begin_background_timestamps[&pid], START - 60_000.0);
+            assert_eq!(status.begin_cpu_idle_timestamps[&pid], START);
+        }
+    }
+
+    #[test]
+    fn restored_timers_of_exited_browsers_are_not_saved_again() {
+        let dir = timers_dir("timers-exited-browser");
+        let timers_path = dir.join("timers.json");
+        let clock = Clock::simulated(START);
+        let key = |browser_start_time| TabKey {
+            tab_id: 2,
+            url: "https://example.org/".to_owned(),
+            browser_start_time,
+        };
+        // Not applied yet, the tab isn't open again
+        let timers = TabTimers {
+            begin_background_timestamp: Some(START - 3_600_000.0),
+            begin_cpu_idle_timestamp: None,
+        };
+        let status = updated_status(
+            HashMap::from([
+                (key(BROWSER_START_TIME), timers),
+                (key(BROWSER_START_TIME - 1), timers),
+            ]),
+            tab_info("https://example.com/", START - 60_000.0),
+            &clock,
+        );
+
+        clock.advance(Duration::from_secs(1));
+        save_timers(&timers_path, &status, &clock).unwrap();
+        let saved_timers = load_timers(&timers_path);
+        // The open tab and the restored tab of the running browser
+        assert_eq!(saved_timers.len(), 2);
+        assert!(saved_timers.contains_key(&key(BROWSER_START_TIME)));
+        assert!(!saved_timers.contains_key(&key(BROWSER_START_TIME - 1)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::{self, Read, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+use crate::PROJECT_NAME;
+
+/// Random bytes of a generated token, hex encoded in the token file
+const TOKEN_BYTES: usize = 32;
+
+/// "~/.config/tab-memory-manager.token" on Linux, the extension must send it to connect the websocket
+pub fn token_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap()
+        .join(format!("{PROJECT_NAME}.token"))
+}
+
+/// Read the shared secret of websocket from `token_path`, generate one if there isn't
+pub fn read_or_create_token(token_path: &Path) -> io::Result<String> {
+    match fs::read_to_string(token_path) {
+        Ok(token) if !token.trim().is_empty() => {
+            // Nobody else should be able to read the token
+            fs::set_permissions(token_path, Permissions::from_mode(0o600))?;
+            return Ok(token.trim().to_string());
+        }
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+
+    let mut random_bytes = [0u8; TOKEN_BYTES];
+    fs::File::open("/dev/urandom")?.read_exact(&mut random_bytes)?;
+    let token: String = random_bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let mut token_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(token_path)?;
+    // The mode only applies to a new file, an empty one may be readable by others
+    token_file.set_permissions(Permissions::from_mode(0o600))?;
+    token_file.write_all(token.as_bytes())?;
+    info!(target: "token", "Generate websocket token {:?}", token_path);
+
+    Ok(token)
+}
+
+/// Compare in constant time, so the token can't be guessed byte by byte from response time
+pub fn token_matches(token: &str, expected_token: &str) -> bool {
+    token.len() == expected_token.len()
+        && token
+            .bytes()
+            .zip(expected_token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn token_is_kept_and_only_readable_by_owner() {
+        let dir = env::temp_dir().join(format!("token-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let token_path = dir.join(format!("{PROJECT_NAME}.token"));
+        let mode = || fs::metadata(&token_path).unwrap().permissions().mode() & 0o777;
+
+        let token = read_or_create_token(&token_path).unwrap();
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert!(token.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        assert_eq!(mode(), 0o600);
+
+        fs::set_permissions(&token_path, Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(read_or_create_token(&token_path).unwrap(), token);
+        assert_eq!(mode(), 0o600);
+
+        // An empty token file readable by others is written again
+        fs::write(&token_path, "").unwrap();
+        fs::set_permissions(&token_path, Permissions::from_mode(0o644)).unwrap();
+        let new_token = read_or_create_token(&token_path).unwrap();
+        assert_ne!(new_token, token);
+        assert_eq!(fs::read_to_string(&token_path).unwrap(), new_token);
+        assert_eq!(mode(), 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tokens_match_only_exactly() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("ab", "abc"));
+        assert!(!token_matches("", "abc"));
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Cell, Paragraph, Row, Table, TableState},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    format::format_bytes,
+    snapshot::{Snapshot, SnapshotTabInfo},
+    token::token_path,
+};
+
+/// How often the snapshot is fetched from daemon
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Columns of the tab table, in display order
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Title,
+    Domain,
+    Pid,
+    Rss,
+    Pss,
+    Cpu,
+    Background,
+    Idle,
+    Protected,
+    NextKill,
+}
+
+const COLUMNS: [Column; 10] = [
+    Column::Title,
+    Column::Domain,
+    Column::Pid,
+    Column::Rss,
+    Column::Pss,
+    Column::Cpu,
+    Column::Background,
+    Column::Idle,
+    Column::Protected,
+    Column::NextKill,
+];
+
+/// A row of the table, the snapshot tab with its pss read locally
+struct TopTab {
+    tab_info: SnapshotTabInfo,
+    // Proportional set size, shared pages are divided among processes, `None` if not readable
+    pss: Option<u64>,
+}
+
+/// State of `top`, the latest snapshot of daemon and what user selected
+struct Top {
+    // "host:port" of the daemon http server
+    http_addr: String,
+    // Sent with control requests, `None` if the token file can't be read
+    token: Option<String>,
+    snapshot: Option<Snapshot>,
+    tabs: Vec<TopTab>,
+    sort_column: Column,
+    descending: bool,
+    table_state: TableState,
+    // Pid of the selected tab, kept selected when rows are sorted again
+    selected_pid: Option<u32>,
+    // Result of the last action
+    message: String,
+    // Shown instead of the message until a snapshot is fetched again
+    fetch_error: Option<String>,
+}
+
+/// `top`, a live table of tabs with keys to protect, kill and pause through the control api
+pub fn run_top(http_addr: String) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let mut top = Top {
+        http_addr,
+        token: fs::read_to_string(token_path())
+            .ok()
+            .map(|token| token.trim().to_string()),
+        snapshot: None,
+        tabs: Vec::new(),
+        sort_column: Column::Rss,
+        descending: true,
+        table_state: TableState::default(),
+        selected_pid: None,
+        message: String::new(),
+        fetch_error: None,
+    };
+    let result = top.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Top {
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        let mut refresh_instant = Instant::now();
+        self.refresh();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let timeout = REFRESH_INTERVAL.saturating_sub(refresh_instant.elapsed());
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Press {
+                        continue;
+                    }
+                    match key.code {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Up => self.select_offset(-1),
+                        KeyCode::Down => self.select_offset(1),
+                        KeyCode::Left => self.sort_by_offset(-1),
+                        KeyCode::Right => self.sort_by_offset(1),
+                        KeyCode::Char('r') => {
+                            self.descending = !self.descending;
+                            self.sort();
+                        }
+                        KeyCode::Char('p') => self.toggle_protection(),
+                        KeyCode::Char('x') => self.kill_selected(),
+                        KeyCode::Char(' ') => self.toggle_pause(),
+                        _ => (),
+                    }
+                }
+            }
+            if refresh_instant.elapsed() >= REFRESH_INTERVAL {
+                self.refresh();
+                refresh_instant = Instant::now();
+            }
+        }
+    }
+
+    /// Fetch the latest snapshot, keep showing the last one if failed
+    fn refresh(&mut self) {
+        let snapshot = match fetch_snapshot(&self.http_addr) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.fetch_error = Some(e);
+                return;
+            }
+        };
+        self.fetch_error = None;
+        self.tabs = snapshot
+            .tab_infos
+            .iter()
+            .map(|tab_info| TopTab {
+                tab_info: tab_info.clone(),
+                pss: read_pss(tab_info.pid),
+            })
+            .collect();
+        self.snapshot = Some(snapshot);
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        let sort_column = self.sort_column;
+        self.tabs.sort_by(|a, b| compare(a, b, sort_column));
+        if self.descending {
+            self.tabs.reverse();
+        }
+        let selected = self
+            .selected_pid
+            .and_then(|pid| self.tabs.iter().position(|tab| tab.tab_info.pid == pid))
+            .or(if self.tabs.is_empty() { None } else { Some(0) });
+        self.table_state.select(selected);
+        self.selected_pid = selected.map(|index| self.tabs[index].tab_info.pid);
+    }
+
+    fn select_offset(&mut self, offset: isize) {
+        if self.tabs.is_empty() {
+            return;
+        }
+        let index = self.table_state.selected().unwrap_or(0) as isize + offset;
+        let index = index.clamp(0, self.tabs.len() as isize - 1) as usize;
+        self.table_state.select(Some(index));
+        self.selected_pid = Some(self.tabs[index].tab_info.pid);
+    }
+
+    fn sort_by_offset(&mut self, offset: isize) {
+        let index = COLUMNS
+            .iter()
+            .position(|&column| column == self.sort_column)
+            .unwrap_or(0) as isize;
+        let index = (index + offset).rem_euclid(COLUMNS.len() as isize) as usize;
+        self.sort_column = COLUMNS[index];
+        self.sort();
+    }
+
+    fn selected_tab(&self) -> Option<&SnapshotTabInfo> {
+        self.table_state
+            .selected()
+            .and_then(|index| self.tabs.get(index))
+            .map(|tab| &tab.tab_info)
+    }
+
+    fn toggle_protection(&mut self) {
+        let Some(tab_info) = self.selected_tab() else {
+            return;
+        };
+        let action = if tab_info.protected {
+            "unprotect"
+        } else {
+            "protect"
+        };
+        let path = format!("/tabs/{}/{}", tab_info.pid, action);
+        let title = tab_info.title.clone();
+        self.control(&path, &format!("{} {:?}", action, title));
+    }
+
+    fn kill_selected(&mut self) {
+        let Some(tab_info) = self.selected_tab() else {
+            return;
+        };
+        let path = format!("/tabs/{}/kill", tab_info.pid);
+        let title = tab_info.title.clone();
+        self.control(&path, &format!("kill {:?}", title));
+    }
+
+    fn toggle_pause(&mut self) {
+        let paused = self
+            .snapshot
+            .as_ref()
+            .is_some_and(|snapshot| snapshot.paused);
+        if paused {
+            self.control("/resume", "resume");
+        } else {
+            self.control("/pause", "pause");
+        }
+    }
+
+    /// Send a control request, the change shows up in the next snapshot
+    fn control(&mut self, path: &str, action: &str) {
+        self.message = match request(&self.http_addr, "POST", path, self.token.as_deref()) {
+            Ok((200 | 202, _)) => format!("Requested to {}", action),
+            Ok((status_code, body)) => format!("Failed to {}: {} {}", action, status_code, body),
+            Err(e) => format!("Failed to {}: {}", action, e),
+        };
+        self.refresh();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header_area, table_area, help_area, message_area] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let header = match &self.snapshot {
+            Some(snapshot) => format!(
+                "{} tabs, total rss {}, available memory {}, {}",
+                snapshot.tab_infos.len(),
+                format_bytes(snapshot.tab_infos.iter().map(|tab_info| tab_info.rss).sum()),
+                format_bytes(snapshot.available_memory),
+                if snapshot.paused { "PAUSED" } else { "running" }
+            ),
+            None => format!("Connecting to http://{}", self.http_addr),
+        };
+        frame.render_widget(Paragraph::new(header), header_area);
+
+        let header_row = Row::new(COLUMNS.iter().map(|&column| {
+            let mut name = column_name(column).to_string();
+            if column == self.sort_column {
+                name.push(if self.descending { '▼' } else { '▲' });
+            }
+            Cell::from(name)
+        }))
+        .style(Style::new().add_modifier(Modifier::BOLD));
+        let rows = self
+            .tabs
+            .iter()
+            .map(|tab| Row::new(COLUMNS.iter().map(|&column| cell_text(tab, column))));
+        let widths = [
+            Constraint::Fill(3),
+            Constraint::Fill(2),
+            Constraint::Length(8),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(6),
+            Constraint::Length(11),
+            Constraint::Length(9),
+            Constraint::Length(10),
+            Constraint::Length(28),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header_row)
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, table_area, &mut self.table_state);
+
+        frame.render_widget(
+            Paragraph::new(Line::from(
+                "↑↓ select  ←→ sort  r reverse  p protect/unprotect  x kill  space pause/resume  q quit",
+            )),
+            help_area,
+        );
+        let message = self.fetch_error.as_deref().unwrap_or(&self.message);
+        frame.render_widget(Paragraph::new(message), message_area);
+    }
+}
+
+fn column_name(column: Column) -> &'static str {
+    match column {
+        Column::Title => "Title",
+        Column::Domain => "Domain",
+        Column::Pid => "Pid",
+        Column::Rss => "Rss",
+        Column::Pss => "Pss",
+        Column::Cpu => "Cpu%",
+        Column::Background => "Background",
+        Column::Idle => "Idle",
+        Column::Protected => "Protected",
+        Column::NextKill => "Next kill",
+    }
+}
+
+fn cell_text(tab: &TopTab, column: Column) -> String {
+    let tab_info = &tab.tab_info;
+    match column {
+        Column::Title => tab_info.title.clone(),
+        Column::Domain => domain(&tab_info.url).to_string(),
+        Column::Pid => tab_info.pid.to_string(),
+        Column::Rss => format_bytes(tab_info.rss),
+        Column::Pss => tab.pss.map(format_bytes).unwrap_or_else(|| "-".to_string()),
+        Column::Cpu => format!("{:.1}", tab_info.cpu_usage),
+        Column::Background if tab_info.foreground => "-".to_string(),
+        Column::Background => format_secs(tab_info.background_time_secs),
+        Column::Idle => format_secs(tab_info.cpu_idle_time_secs),
+        Column::Protected => if tab_info.protected { "yes" } else { "" }.to_string(),
+        Column::NextKill => match &tab_info.next_kill {
+            Some(next_kill) => match next_kill.secs_left {
+                Some(secs_left) if secs_left > 0.0 => {
+                    format!("{} {}", next_kill.strategy, format_secs(secs_left))
+                }
+                Some(_) => format!("{} now", next_kill.strategy),
+                None => format!("{} first", next_kill.strategy),
+            },
+            None => "-".to_string(),
+        },
+    }
+}
+
+fn compare(a: &TopTab, b: &TopTab, column: Column) -> Ordering {
+    let (a_info, b_info) = (&a.tab_info, &b.tab_info);
+    match column {
+        Column::Title => a_info.title.cmp(&b_info.title),
+        Column::Domain => domain(&a_info.url).cmp(domain(&b_info.url)),
+        Column::Pid => a_info.pid.cmp(&b_info.pid),
+        Column::Rss => a_info.rss.cmp(&b_info.rss),
+        Column::Pss => a.pss.cmp(&b.pss),
+        Column::Cpu => a_info.cpu_usage.total_cmp(&b_info.cpu_usage),
+        Column::Background => a_info
+            .background_time_secs
+            .total_cmp(&b_info.background_time_secs),
+        Column::Idle => a_info
+            .cpu_idle_time_secs
+            .total_cmp(&b_info.cpu_idle_time_secs),
+        Column::Protected => a_info.protected.cmp(&b_info.protected),
+        // Tabs killed sooner are greater, so they come first in descending order
+        Column::NextKill => {
+            let secs_left = |tab_info: &SnapshotTabInfo| {
+                tab_info
+                    .next_kill
+                    .as_ref()
+                    .map(|next_kill| next_kill.secs_left.unwrap_or(f64::INFINITY))
+            };
+            match (secs_left(a_info), secs_left(b_info)) {
+                (Some(a), Some(b)) => b.total_cmp(&a),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            }
+        }
+    }
+}
+
+/// Host of the url, e.g. "docs.rs" of "https://docs.rs/regex"
+fn domain(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    without_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or(without_scheme)
+}
+
+/// e.g. "45s", "12m05s", "3h20m"
+fn format_secs(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Pss of the process from "/proc/<pid>/smaps_rollup" in bytes like rss, the daemon only reports rss
+fn read_pss(pid: u32) -> Option<u64> {
+    let smaps_rollup = fs::read_to_string(format!("/proc/{}/smaps_rollup", pid)).ok()?;
+    // "kB" of the kernel is KiB
+    let kibibytes = smaps_rollup
+        .lines()
+        .find_map(|line| line.strip_prefix("Pss:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kibibytes * 1024)
+}
+
+fn fetch_snapshot(http_addr: &str) -> Result<Snapshot, String> {
+    match request(http_addr, "GET", "/", None) {
+        Ok((200, body)) => {
+            serde_json::from_str(&body).map_err(|e| format!("Invalid snapshot: {}", e))
+        }
+        Ok((status_code, body)) => Err(format!(
+            "Failed to fetch snapshot: {} {}",
+            status_code, body
+        )),
+        Err(e) => Err(format!(
+            "Failed to fetch snapshot from {}: {}",
+            http_addr, e
+        )),
+    }
+}
+
+/// A minimal HTTP/1.0 request to the daemon, with the token if given, return status code and body
+fn request(
+    http_addr: &str,
+    method: &str,
+    path: &str,
+    token: Option<&str>,
+) -> io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(http_addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let authorization = token
+        .map(|token| format!("Authorization: Bearer {token}\r\n"))
+        .unwrap_or_default();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.0\r\nHost: {http_addr}\r\n{authorization}Content-Length: 0\r\n\r\n"
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let invalid_response = || io::Error::new(io::ErrorKind::InvalidData, "Invalid http response");
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(invalid_response)?;
+    let status_code = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status_code| status_code.parse().ok())
+        .ok_or_else(invalid_response)?;
+    Ok((status_code, body.to_string()))
+}
@@ -0,0 +1,20 @@
+/* global ExtensionAPI */
+
+// Firefox has no `processes` API, expose the content process childID of a tab like `chrome.processes.getProcessIdForTab`
+// The same childID is in the content process cmdline "-childID <id>"
+this.processes = class extends ExtensionAPI {
+  getAPI(context) {
+    const { tabManager } = context.extension;
+
+    return {
+      processes: {
+        async getProcessIdForTab(tabId) {
+          let nativeTab = tabManager.get(tabId).nativeTab;
+          let frameLoader = nativeTab.linkedBrowser.frameLoader;
+          // Discarded or lazy tabs have no content process, childID 0 is the parent process
+          return frameLoader ? frameLoader.childID : 0;
+        },
+      },
+    };
+  }
+};
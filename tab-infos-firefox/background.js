@@ -0,0 +1,194 @@
+const serverUrl = 'ws://localhost:60000';
+let ws;
+let reconnectInterval = 100;
+// tab-memory-manager rejects messages of other protocol versions
+const protocolVersion = 1;
+const capabilities = ['events'];
+// Set when tab-memory-manager speaks another protocol version, stop reconnecting
+let incompatible = false;
+// Number of every message sent in this connection, tab-memory-manager requests a snapshot if any is missing
+let seq = 0;
+// Messages are built and sent one by one, so they arrive in the order things happened
+let sendQueue = Promise.resolve();
+// The active tab of each window, to tell which tab went to background
+let activeTabIds = new Map();
+
+async function withBrowserInnerPid(tabInfo) {
+  tabInfo.browserInnerPid = await browser.processes.getProcessIdForTab(tabInfo.id);
+  if (tabInfo.active) {
+    activeTabIds.set(tabInfo.windowId, tabInfo.id);
+  }
+  return tabInfo;
+}
+
+async function getTabData() {
+  let timestamp = Date.now();
+  let tabInfos = await browser.tabs.query({});
+  tabInfos = await Promise.all(tabInfos.map(withBrowserInnerPid));
+
+  return {
+    type: 'snapshot',
+    timestamp,
+    tabInfos,
+  };
+}
+
+function post(message) {
+  message = { v: protocolVersion, ...message };
+  if (ws?.readyState === WebSocket.OPEN) {
+    ws.send(JSON.stringify(message));
+  }
+}
+
+function send(message) {
+  post({ ...message, seq: seq++ });
+}
+
+function enqueue(buildMessage) {
+  if (ws?.readyState !== WebSocket.OPEN) {
+    return;
+  }
+  sendQueue = sendQueue
+    .then(async () => {
+      let message = await buildMessage();
+      if (message) {
+        send(message);
+      }
+    })
+    .catch(e => console.error('Failed to send message:', e));
+}
+
+// Full snapshot of tabs, only on connected or requested by tab-memory-manager
+function sendSnapshot() {
+  enqueue(getTabData);
+}
+
+// The first messages of a connection
+function sendHelloAndSnapshot() {
+  seq = 0;
+  post({
+    type: 'hello',
+    extensionVersion: browser.runtime.getManifest().version,
+    capabilities,
+  });
+  sendSnapshot();
+}
+
+function handleMessage(message) {
+  switch (message.type) {
+    case 'ack':
+      console.log(`Connected to tab-memory-manager ${message.daemonVersion}`);
+      break;
+    case 'command':
+      if (message.command === 'snapshot') {
+        sendSnapshot();
+      }
+      break;
+    case 'error':
+      console.error(`Error from tab-memory-manager: ${message.message}`);
+      if (message.fatal) {
+        incompatible = true;
+      }
+      break;
+    default:
+      console.log(`Ignore unknown message: ${JSON.stringify(message)}`);
+  }
+}
+
+function sendTabUpdated(tabId, timestamp = Date.now()) {
+  enqueue(async () => {
+    let tabInfo;
+    try {
+      tabInfo = await withBrowserInnerPid(await browser.tabs.get(tabId));
+    } catch (_e) {
+      // The tab is already removed
+      return;
+    }
+    return {
+      type: 'event',
+      timestamp,
+      event: { type: 'updated', tabInfo },
+    };
+  });
+}
+
+function sendTabRemoved(tabId, timestamp = Date.now()) {
+  enqueue(() => ({
+    type: 'event',
+    timestamp,
+    event: { type: 'removed', tabId },
+  }));
+}
+
+browser.tabs.onCreated.addListener((tab) => sendTabUpdated(tab.id));
+browser.tabs.onUpdated.addListener((tabId) => sendTabUpdated(tabId));
+browser.tabs.onActivated.addListener(({ tabId, previousTabId, windowId }) => {
+  let timestamp = Date.now();
+  let lastActiveTabId = previousTabId ?? activeTabIds.get(windowId);
+  activeTabIds.set(windowId, tabId);
+  if (lastActiveTabId !== undefined && lastActiveTabId !== tabId) {
+    sendTabUpdated(lastActiveTabId, timestamp);
+  }
+  sendTabUpdated(tabId, timestamp);
+});
+browser.tabs.onRemoved.addListener((tabId) => sendTabRemoved(tabId));
+
+async function getToken() {
+  let { token } = await browser.storage.local.get('token');
+  return token ?? '';
+}
+
+async function initWs() {
+  // The token is the websocket subprotocol, set it in extension options
+  let token = await getToken();
+  if (!token) {
+    console.error('No websocket token, set it in extension options');
+    return;
+  }
+  ws = new WebSocket(serverUrl, [token]);
+
+  ws.addEventListener('open', (_event) => {
+    console.log('Connected to the WebSocket server!');
+
+    sendHelloAndSnapshot();
+  });
+
+  ws.addEventListener('message', (event) => {
+    console.log(`Message from server: ${event.data}`);
+
+    try {
+      handleMessage(JSON.parse(event.data));
+    } catch (e) {
+      console.error('Failed to parse message from server:', e);
+    }
+  });
+
+  ws.addEventListener('error', (event) => {
+    console.error('WebSocket error:', event);
+  });
+
+  ws.addEventListener('close', (_event) => {
+    console.log('Disconnected from the WebSocket server!');
+    if (incompatible) {
+      console.error('Update tab-memory-manager or this extension to the same protocol version');
+      return;
+    }
+
+    setTimeout(() => {
+      initWs();
+    }, reconnectInterval);
+  });
+}
+
+initWs();
+
+// Reconnect with the new token, closed websocket reconnects by itself
+browser.storage.onChanged.addListener((changes) => {
+  if (changes.token) {
+    if (ws && ws.readyState !== WebSocket.CLOSED) {
+      ws.close();
+    } else {
+      initWs();
+    }
+  }
+});
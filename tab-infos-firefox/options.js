@@ -0,0 +1,10 @@
+const tokenInput = document.getElementById('token');
+
+browser.storage.local.get('token').then(({ token }) => {
+  tokenInput.value = token ?? '';
+});
+
+document.getElementById('save').addEventListener('click', async () => {
+  await browser.storage.local.set({ token: tokenInput.value.trim() });
+  document.getElementById('saved').hidden = false;
+});
@@ -1,37 +1,186 @@
 const serverUrl = 'ws://localhost:60000';
+const nativeHostName = 'tab_memory_manager';
 let ws;
+let nativePort;
 let reconnectInterval = 100;
+// tab-memory-manager rejects messages of other protocol versions
+const protocolVersion = 1;
+const capabilities = ['events'];
+// Set when tab-memory-manager speaks another protocol version, stop reconnecting
+let incompatible = false;
+// Number of every message sent in this connection, tab-memory-manager requests a snapshot if any is missing
+let seq = 0;
+// Messages are built and sent one by one, so they arrive in the order things happened
+let sendQueue = Promise.resolve();
+// The active tab of each window, to tell which tab went to background
+let activeTabIds = new Map();
+// The browser inner pid of each tab, to find tabs of exited processes
+let tabBrowserInnerPids = new Map();
+
+async function withBrowserInnerPid(tabInfo) {
+  tabInfo.browserInnerPid = await chrome.processes.getProcessIdForTab(tabInfo.id);
+  tabBrowserInnerPids.set(tabInfo.id, tabInfo.browserInnerPid);
+  if (tabInfo.active) {
+    activeTabIds.set(tabInfo.windowId, tabInfo.id);
+  }
+  return tabInfo;
+}
 
 async function getTabData() {
+  let timestamp = Date.now();
   let tabInfos = await chrome.tabs.query({});
-  await Promise.all(tabInfos.map(async tabInfo => {
-    tabInfo.browserInnerPid = await chrome.processes.getProcessIdForTab(tabInfo.id);
-    return tabInfo;
-  }));
+  await Promise.all(tabInfos.map(withBrowserInnerPid));
 
   return {
-    timestamp: Date.now(),
+    type: 'snapshot',
+    timestamp,
     tabInfos,
   };
 }
 
-function initWs() {
-  ws = new WebSocket(serverUrl);
+function post(message) {
+  message = { v: protocolVersion, ...message };
+  if (nativePort) {
+    nativePort.postMessage(message);
+  } else if (ws?.readyState === WebSocket.OPEN) {
+    ws.send(JSON.stringify(message));
+  }
+}
+
+function send(message) {
+  post({ ...message, seq: seq++ });
+}
+
+function enqueue(buildMessage) {
+  if (!nativePort && ws?.readyState !== WebSocket.OPEN) {
+    return;
+  }
+  sendQueue = sendQueue
+    .then(async () => {
+      let message = await buildMessage();
+      if (message) {
+        send(message);
+      }
+    })
+    .catch(e => console.error('Failed to send message:', e));
+}
+
+// Full snapshot of tabs, only on connected or requested by tab-memory-manager
+function sendSnapshot() {
+  enqueue(getTabData);
+}
+
+// The first messages of a connection
+function sendHelloAndSnapshot() {
+  seq = 0;
+  post({
+    type: 'hello',
+    extensionVersion: chrome.runtime.getManifest().version,
+    capabilities,
+  });
+  sendSnapshot();
+}
+
+function handleMessage(message) {
+  switch (message.type) {
+    case 'ack':
+      console.log(`Connected to tab-memory-manager ${message.daemonVersion}`);
+      break;
+    case 'command':
+      if (message.command === 'snapshot') {
+        sendSnapshot();
+      }
+      break;
+    case 'error':
+      console.error(`Error from tab-memory-manager: ${message.message}`);
+      if (message.fatal) {
+        incompatible = true;
+      }
+      break;
+    default:
+      console.log(`Ignore unknown message: ${JSON.stringify(message)}`);
+  }
+}
+
+function sendTabUpdated(tabId, timestamp = Date.now()) {
+  enqueue(async () => {
+    let tabInfo;
+    try {
+      tabInfo = await withBrowserInnerPid(await chrome.tabs.get(tabId));
+    } catch (_e) {
+      // The tab is already removed
+      return;
+    }
+    return {
+      type: 'event',
+      timestamp,
+      event: { type: 'updated', tabInfo },
+    };
+  });
+}
+
+function sendTabRemoved(tabId, timestamp = Date.now()) {
+  tabBrowserInnerPids.delete(tabId);
+  enqueue(() => ({
+    type: 'event',
+    timestamp,
+    event: { type: 'removed', tabId },
+  }));
+}
 
-  ws.addEventListener('open', async (_event) => {
+chrome.tabs.onCreated.addListener((tab) => sendTabUpdated(tab.id));
+chrome.tabs.onUpdated.addListener((tabId) => sendTabUpdated(tabId));
+chrome.tabs.onActivated.addListener(({ tabId, windowId }) => {
+  let timestamp = Date.now();
+  let lastActiveTabId = activeTabIds.get(windowId);
+  activeTabIds.set(windowId, tabId);
+  if (lastActiveTabId !== undefined && lastActiveTabId !== tabId) {
+    sendTabUpdated(lastActiveTabId, timestamp);
+  }
+  sendTabUpdated(tabId, timestamp);
+});
+chrome.tabs.onRemoved.addListener((tabId) => sendTabRemoved(tabId));
+chrome.tabs.onReplaced.addListener((addedTabId, removedTabId) => {
+  sendTabRemoved(removedTabId);
+  sendTabUpdated(addedTabId);
+});
+// The tab process is gone (e.g. killed by tab-memory-manager), its tabs get new processes later
+chrome.processes.onExited.addListener((processId) => {
+  for (let [tabId, browserInnerPid] of tabBrowserInnerPids) {
+    if (browserInnerPid === processId) {
+      sendTabUpdated(tabId);
+    }
+  }
+});
+
+async function getToken() {
+  let { token } = await chrome.storage.local.get('token');
+  return token ?? '';
+}
+
+async function initWs() {
+  // The token is the websocket subprotocol, set it in extension options
+  let token = await getToken();
+  if (!token) {
+    console.error('No websocket token, set it in extension options');
+    return;
+  }
+  ws = new WebSocket(serverUrl, [token]);
+
+  ws.addEventListener('open', (_event) => {
     console.log('Connected to the WebSocket server!');
 
-    let tabData = await getTabData();
-    let json = JSON.stringify(tabData);
-    ws.send(json);
+    sendHelloAndSnapshot();
   });
 
-  ws.addEventListener('message', async (event) => {
+  ws.addEventListener('message', (event) => {
     console.log(`Message from server: ${event.data}`);
 
-    let tabData = await getTabData();
-    let json = JSON.stringify(tabData);
-    ws.send(json);
+    try {
+      handleMessage(JSON.parse(event.data));
+    } catch (e) {
+      console.error('Failed to parse message from server:', e);
+    }
   });
 
   ws.addEventListener('error', (event) => {
@@ -40,6 +189,10 @@ function initWs() {
 
   ws.addEventListener('close', (_event) => {
     console.log('Disconnected from the WebSocket server!');
+    if (incompatible) {
+      console.error('Update tab-memory-manager or this extension to the same protocol version');
+      return;
+    }
 
     setTimeout(() => {
       // console.error('Try to reconnect to WebSocket server...');
@@ -48,7 +201,49 @@ function initWs() {
   });
 }
 
-initWs();
+// Prefer native messaging, the browser launches tab-memory-manager itself
+// Fallback to websocket if native messaging host is not installed
+function initNativeMessaging() {
+  let connected = false;
+  nativePort = chrome.runtime.connectNative(nativeHostName);
+  sendHelloAndSnapshot();
+
+  nativePort.onMessage.addListener((message) => {
+    // Native host replies hello, so the host is installed
+    connected = true;
+    console.log(`Message from native host: ${JSON.stringify(message)}`);
+
+    handleMessage(message);
+  });
+
+  nativePort.onDisconnect.addListener((_port) => {
+    console.log('Disconnected from native host:', chrome.runtime.lastError?.message);
+    nativePort = undefined;
+
+    if (incompatible) {
+      console.error('Update tab-memory-manager or this extension to the same protocol version');
+    } else if (connected) {
+      setTimeout(() => {
+        initNativeMessaging();
+      }, reconnectInterval);
+    } else {
+      initWs();
+    }
+  });
+}
+
+initNativeMessaging();
+
+// Reconnect with the new token, closed websocket reconnects by itself
+chrome.storage.onChanged.addListener((changes) => {
+  if (changes.token && !nativePort) {
+    if (ws && ws.readyState !== WebSocket.CLOSED) {
+      ws.close();
+    } else {
+      initWs();
+    }
+  }
+});
 
 // Function to keep the service worker alive
 // https://stackoverflow.com/a/66618269
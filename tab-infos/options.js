@@ -0,0 +1,10 @@
+const tokenInput = document.getElementById('token');
+
+chrome.storage.local.get('token').then(({ token }) => {
+  tokenInput.value = token ?? '';
+});
+
+document.getElementById('save').addEventListener('click', async () => {
+  await chrome.storage.local.set({ token: tokenInput.value.trim() });
+  document.getElementById('saved').hidden = false;
+});